@@ -1,116 +1,513 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, Write, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::SystemTime;
 use std::collections::HashMap;
 
 use std::sync::mpsc;
 
 use memmap2::Mmap;
 use zstd::Encoder;
-use bincode::{
-    config::{standard, Configuration},
-    encode_to_vec,
-    Encode,
-};
-use xorf::{BinaryFuse8, Filter};
-use rand::Rng;
-
-use num_cpus;
+use xxhash_rust::xxh3::Xxh3;
 
+use crate::checkpoint::{BuildInputs, Checkpoint};
 use crate::fastu64set::FastSet;
+use crate::index::{
+    split_header, FilterSpec, FrameFilter, FrameInfo, IndexKind, IndexReader, IndexWriter,
+};
+use crate::input::{decompressing_reader, Compression, MAGIC_LEN};
+use crate::timestamp::{TimeDetector, TimeRange};
 
 use crate::utils::{default_output_names_if_omitted, CHUNK_SIZE, HASH_CAPACITY};
 
-#[derive(Debug, Encode)]
-struct FrameInfo {
-    frame_offset: u64,
-    frame_size: u64,
+/// How much more to read while looking for the newline that ends a streamed chunk
+const STREAM_READ_SIZE: usize = 64 * 1024;
+
+/// Rough peak memory of one filter worker: its hash set, the extracted keys
+/// and the fuse filter's construction arrays
+const WORKER_MEMORY: u64 = 4 * 8 * HASH_CAPACITY as u64;
+
+/// What `run_build` does with an existing archive at the output paths
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BuildMode {
+    /// Replace it
+    #[default]
+    Create,
+    /// Add new frames after its last frame, leaving existing frames untouched.
+    /// A missing archive is created.
+    Append,
+    /// Continue an interrupted `Create` from its checkpoint
+    Resume,
+}
+
+/// Settings of a build beyond its input and output paths
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub mode: BuildMode,
+    /// Approximate cap on the memory used by the chunk pipeline, in bytes.
+    /// Limits both the number of filter workers and the chunks in flight.
+    pub max_memory: Option<u64>,
+    /// Finds line timestamps for the time range of each frame
+    pub time_detector: TimeDetector,
+    /// How the filters of a new index are built; appends and resumed builds
+    /// keep the settings of the index they continue
+    pub filter: FilterSpec,
+}
+
+/// One chunk of input on its way through the pipeline: a range of the
+/// memory-mapped input, shared without copying, or an owned buffer for
+/// streamed input
+pub enum Chunk {
+    Mapped(Arc<Mmap>, Range<usize>),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Chunk::Mapped(mmap, range) => &mmap[range.clone()],
+            Chunk::Owned(data) => data,
+        }
+    }
+}
+
+/// A chunk together with where in the build's inputs it was cut from
+pub struct InputChunk {
+    pub data: Chunk,
+    /// Position of the input in the list given to the build
+    pub input_index: usize,
+    /// Name recorded as the source of the chunk's frame
+    pub source: Arc<str>,
+    /// Offset of the chunk within its input, after decompression
+    pub source_offset: u64,
+    /// Lines of the input before the chunk
+    pub source_line: u64,
+    /// Lines in the chunk (see `count_lines`)
+    pub line_count: u64,
+    /// When the input was last written, which dates syslog timestamps
+    pub last_write: Option<SystemTime>,
 }
 
 /// A small struct carrying all data needed by the writer to finalize output.
 struct ChunkResult {
     chunk_index: usize,
-    /// The uncompressed chunk is optional if you want to do compression in the Writer thread
-    /// or do it in the Worker thread. Shown here for illustration; you can store
-    /// compressed bytes if you prefer compressing inside the worker.
-    chunk_data: Vec<u8>,  
-    filter: BinaryFuse8,
+    /// The chunk as the worker got it, still uncompressed: a `Chunk::Mapped`
+    /// range of the input's mapping or a `Chunk::Owned` buffer read from a
+    /// stream. The writer compresses it, so a mapped chunk is never copied.
+    chunk: InputChunk,
+    filter: FrameFilter,
+    time_range: Option<TimeRange>,
 }
 
-/// The main entry point for the "build" subcommand
+/// The main entry point for the "build" and "append" subcommands.
+/// The inputs are packed into one archive in order, each cut into its own
+/// chunks. An input may be `-` for stdin; pipes and other non-regular files
+/// are streamed.
 pub fn run_build(
-    input_path: &str,
+    inputs: &[String],
     maybe_zst_path: Option<&str>,
     maybe_idx_path: Option<&str>,
+    options: &BuildOptions,
 ) -> io::Result<()> {
+    let mode = options.mode;
     // 1) Figure out output paths
-    let (zst_path, idx_path) = default_output_names_if_omitted(input_path, maybe_zst_path, maybe_idx_path);
+    let (zst_path, idx_path) = output_paths(inputs, maybe_zst_path, maybe_idx_path, mode)?;
+    let stamps = BuildInputs::stamp(inputs)?;
+
+    // A resumed build continues right after the last chunk it wrote; chunks
+    // are cut relative to the start of their input, so the rest comes out the same
+    let (first_input, input_offset, input_line) = match mode {
+        BuildMode::Resume if inputs.iter().any(|input| input == "-") => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a build from stdin can't be resumed",
+            ));
+        }
+        BuildMode::Resume => {
+            let checkpoint = Checkpoint::load(&Checkpoint::path(&zst_path))?;
+            stamps.check(&checkpoint)?;
+            (checkpoint.input_index as usize, checkpoint.input_offset, checkpoint.input_line)
+        }
+        _ => (0, 0, 0),
+    };
+
+    // 2) Open each input in turn when its chunks are needed
+    let chunks = InputChunks::new(inputs, first_input, input_offset, input_line);
+    write_archive(chunks, &stamps, &zst_path, &idx_path, options)?;
+
+    let verb = match mode {
+        BuildMode::Create | BuildMode::Resume => "Build",
+        BuildMode::Append => "Append",
+    };
+    println!("{} complete. ZST: {:?} | MG: {:?}", verb, zst_path, idx_path);
+    Ok(())
+}
+
+/// Read `--input-list`: one input path per line, blank lines ignored
+pub fn read_input_list(list_path: &Path) -> io::Result<Vec<String>> {
+    let list = fs::read_to_string(list_path)?;
+    Ok(list
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Name recorded as the source of frames cut from `input_path`
+fn source_name(input_path: &str) -> &str {
+    if input_path == "-" { "(standard input)" } else { input_path }
+}
+
+/// Owning view of a shared mapping, so a reader over it isn't tied to a borrow
+struct SharedMmap(Arc<Mmap>);
+
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
 
-    // 2) Open the input and memory-map
+/// Open one input to be chunked from (decompressed) offset `offset` on:
+/// memory-map plain regular files, stream everything else, decompressing
+/// gzip/bzip2/xz/zstd input on the fly
+fn open_chunks(input_path: &str, offset: u64) -> io::Result<Box<dyn Iterator<Item = io::Result<Chunk>>>> {
+    if input_path == "-" {
+        let reader = skip_input(decompressing_reader(io::stdin().lock())?, offset, input_path)?;
+        return Ok(Box::new(StreamChunker::new(reader)));
+    }
     let input_file = File::open(input_path)?;
-    let metadata = input_file.metadata()?;
-    let file_len = metadata.len() as usize;
-    let mmap = unsafe { Mmap::map(&input_file)? };
-
-    // 3) Set up output files (opened here but only written in the writer thread)
-    let output_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&zst_path)?;
-
-    let index_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&idx_path)?;
-
-    // We'll share these file handles with the writer thread via Arc<Mutex<...>>
+    if !input_file.metadata()?.is_file() {
+        let reader = skip_input(decompressing_reader(input_file)?, offset, input_path)?;
+        return Ok(Box::new(StreamChunker::new(reader)));
+    }
+    let mmap = Arc::new(unsafe { Mmap::map(&input_file)? });
+    if Compression::detect(&mmap[..MAGIC_LEN.min(mmap.len())]) == Compression::None {
+        if offset > mmap.len() as u64 {
+            return Err(input_too_short(input_path));
+        }
+        Ok(Box::new(MmapChunker::new(mmap, offset as usize)))
+    } else {
+        let reader = decompressing_reader(io::Cursor::new(SharedMmap(mmap)))?;
+        Ok(Box::new(StreamChunker::new(skip_input(reader, offset, input_path)?)))
+    }
+}
+
+/// Chunks of every input in turn. An input is only opened once the one
+/// before it is used up, and no chunk spans two inputs.
+struct InputChunks<'a> {
+    inputs: &'a [String],
+    /// Index of the input being chunked
+    input_index: usize,
+    source: Arc<str>,
+    last_write: Option<SystemTime>,
+    /// Offset of the next chunk within the current input
+    offset: u64,
+    /// Lines of the current input before the next chunk
+    line: u64,
+    /// Chunker of the current input, once opened
+    chunks: Option<Box<dyn Iterator<Item = io::Result<Chunk>>>>,
+}
+
+impl<'a> InputChunks<'a> {
+    /// Start at input `first_input`, from `offset` (line `line`) within it
+    fn new(inputs: &'a [String], first_input: usize, offset: u64, line: u64) -> Self {
+        Self {
+            inputs,
+            input_index: first_input,
+            source: Arc::from(""),
+            last_write: None,
+            offset,
+            line,
+            chunks: None,
+        }
+    }
+}
+
+impl Iterator for InputChunks<'_> {
+    type Item = io::Result<InputChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let chunks = match &mut self.chunks {
+                Some(chunks) => chunks,
+                None => {
+                    let input_path = self.inputs.get(self.input_index)?;
+                    let chunks = match open_chunks(input_path, self.offset) {
+                        Ok(chunks) => chunks,
+                        Err(e) => {
+                            // Nothing after a failed input is read
+                            self.input_index = self.inputs.len();
+                            return Some(Err(e));
+                        }
+                    };
+                    self.source = Arc::from(source_name(input_path));
+                    self.last_write = match input_path.as_str() {
+                        "-" => None,
+                        path => fs::metadata(path).and_then(|meta| meta.modified()).ok(),
+                    };
+                    self.chunks.insert(chunks)
+                }
+            };
+            match chunks.next() {
+                Some(Ok(data)) => {
+                    let chunk = InputChunk {
+                        input_index: self.input_index,
+                        source: Arc::clone(&self.source),
+                        source_offset: self.offset,
+                        source_line: self.line,
+                        line_count: count_lines(&data),
+                        last_write: self.last_write,
+                        data,
+                    };
+                    self.offset += chunk.data.len() as u64;
+                    self.line += chunk.line_count;
+                    return Some(Ok(chunk));
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.chunks = None;
+                    self.input_index += 1;
+                    self.offset = 0;
+                    self.line = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Discard the first `offset` bytes of `reader`
+fn skip_input<R: Read>(mut reader: R, offset: u64, input_path: &str) -> io::Result<R> {
+    if io::copy(&mut (&mut reader).take(offset), &mut io::sink())? < offset {
+        return Err(input_too_short(input_path));
+    }
+    Ok(reader)
+}
+
+fn input_too_short(input_path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is shorter than the checkpoint of the build being resumed", input_path),
+    )
+}
+
+/// Resolve the `.zst` and `.mg` paths for building from `inputs`
+pub fn output_paths(
+    inputs: &[String],
+    maybe_zst_path: Option<&str>,
+    maybe_idx_path: Option<&str>,
+    mode: BuildMode,
+) -> io::Result<(PathBuf, PathBuf)> {
+    // Only a single named input has a name to derive outputs from. An append
+    // targets an existing archive, whose index sits next to it.
+    let (zst_path, idx_path) = match inputs {
+        [input_path] if input_path != "-" && !(mode == BuildMode::Append && maybe_zst_path.is_some()) => {
+            default_output_names_if_omitted(input_path, maybe_zst_path, maybe_idx_path)
+        }
+        _ => output_names_from_zst(maybe_zst_path, maybe_idx_path)?,
+    };
+
+    // Deriving names from e.g. `app.zst` would otherwise clobber the input
+    for input_path in inputs.iter().filter(|input| *input != "-") {
+        if [&zst_path, &idx_path].iter().any(|out| same_file(out, Path::new(input_path))) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("output would overwrite input {}; pass --zst/--idx", input_path),
+            ));
+        }
+    }
+    Ok((zst_path, idx_path))
+}
+
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Streams and input lists have no single name to derive outputs from, and
+/// appends go to a given archive: `--zst` is required and the index defaults
+/// to the same path with `.mg`, as `mg search` expects
+fn output_names_from_zst(
+    maybe_zst_path: Option<&str>,
+    maybe_idx_path: Option<&str>,
+) -> io::Result<(PathBuf, PathBuf)> {
+    let zst_path = maybe_zst_path.map(PathBuf::from).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "building from stdin or from several inputs requires --zst")
+    })?;
+    let idx_path = maybe_idx_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| zst_path.with_extension("mg"));
+    Ok((zst_path, idx_path))
+}
+
+/// Compress `chunks` into frames at `zst_path` and write their filters to `idx_path`.
+///
+/// A new archive is written to partial files next to the outputs, synced,
+/// and renamed into place data first, index last, so a failed or interrupted
+/// build leaves any existing archive alone. After every chunk a checkpoint
+/// records how far the synced partial files get, for `BuildMode::Resume`.
+/// An append writes its frames after the existing ones but stages the index
+/// records in a side file until the frames are on disk.
+pub fn write_archive(
+    chunks: impl Iterator<Item = io::Result<InputChunk>>,
+    inputs: &BuildInputs,
+    zst_path: &Path,
+    idx_path: &Path,
+    options: &BuildOptions,
+) -> io::Result<()> {
+    let mode = match options.mode {
+        BuildMode::Append if !zst_path.exists() && !idx_path.exists() => BuildMode::Create,
+        mode => mode,
+    };
+
+    match mode {
+        BuildMode::Create | BuildMode::Resume => {
+            let partial_zst_path = partial_path(zst_path);
+            let partial_idx_path = partial_path(idx_path);
+            let checkpoint_path = Checkpoint::path(zst_path);
+            let result = (|| {
+                let mut options = options.clone();
+                let (output_file, index_file, header_kind) = if mode == BuildMode::Resume {
+                    let checkpoint = Checkpoint::load(&checkpoint_path)?;
+                    options.filter = split_header(&fs::read(&partial_idx_path)?)?.0.filter;
+                    let output_file = open_partial(&partial_zst_path, checkpoint.zst_len)?;
+                    let index_file = open_partial(&partial_idx_path, checkpoint.idx_len)?;
+                    (output_file, index_file, None)
+                } else {
+                    // A stale checkpoint must not describe the new partial files
+                    remove_if_exists(&checkpoint_path)?;
+                    let output_file = File::create(&partial_zst_path)?;
+                    let index_file = File::create(&partial_idx_path)?;
+                    (output_file, index_file, Some(IndexKind::ZstdFrames))
+                };
+                let (output_file, index_file) = write_frames(
+                    chunks,
+                    output_file,
+                    index_file,
+                    header_kind,
+                    Some((&checkpoint_path, inputs)),
+                    &options,
+                )?;
+                output_file.sync_all()?;
+                index_file.sync_all()?;
+                fs::rename(&partial_zst_path, zst_path)?;
+                fs::rename(&partial_idx_path, idx_path)?;
+                sync_parent_dir(idx_path)?;
+                remove_if_exists(&checkpoint_path)
+            })();
+            if result.is_err() {
+                if checkpoint_path.exists() {
+                    eprintln!("Partial build kept; rerun with --resume to continue it");
+                } else {
+                    let _ = fs::remove_file(&partial_zst_path);
+                    let _ = fs::remove_file(&partial_idx_path);
+                }
+            }
+            result
+        }
+        BuildMode::Append => {
+            let (data_end, index_len, filter) = existing_archive_end(idx_path)?;
+            let options = &BuildOptions { filter, ..options.clone() };
+            let mut output_file = OpenOptions::new().write(true).open(zst_path)?;
+            if output_file.metadata()?.len() < data_end {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is shorter than its index {:?} says", zst_path, idx_path),
+                ));
+            }
+            // Frames past the last indexed one were left by an interrupted append
+            output_file.set_len(data_end)?;
+            output_file.seek(SeekFrom::Start(data_end))?;
+
+            let staging = staging_path(idx_path);
+            let result = File::create(&staging)
+                .and_then(|staged_index| write_frames(chunks, output_file, staged_index, None, None, options))
+                .and_then(|(output_file, _)| output_file.sync_all())
+                .and_then(|_| commit_staged_records(idx_path, index_len));
+            if result.is_err() {
+                let _ = fs::remove_file(&staging);
+            }
+            result
+        }
+    }
+}
+
+/// Run the chunk -> filter -> frame pipeline, writing frames to `output_file`
+/// and records to `index_file` (after a header for `header_kind`, if given).
+/// With a checkpoint path, both files are synced and a checkpoint of the
+/// given inputs stored there after each chunk. Returns both files once every
+/// chunk is written.
+fn write_frames(
+    chunks: impl Iterator<Item = io::Result<InputChunk>>,
+    output_file: File,
+    index_file: File,
+    header_kind: Option<IndexKind>,
+    checkpoint: Option<(&Path, &BuildInputs)>,
+    options: &BuildOptions,
+) -> io::Result<(File, File)> {
+    let checkpoint = checkpoint.map(|(path, inputs)| (path.to_path_buf(), inputs.clone()));
+    // We'll share the data file handle with the writer thread via Arc<Mutex<...>>;
+    // the index is only ever touched by the writer, so it moves there
     let output_file = Arc::new(Mutex::new(output_file));
-    let index_file = Arc::new(Mutex::new(index_file));
 
     // 4) Channels for pipeline
     //    - `chunk_sender`: main thread -> worker threads
     //    - `chunk_receiver`: worker threads -> writer thread
     const CHANNEL_CAPACITY: usize = 10;
-    let (chunk_sender, worker_rx) = mpsc::sync_channel::<(usize, Vec<u8>)>(CHANNEL_CAPACITY);
+    let (chunk_sender, worker_rx) = mpsc::sync_channel::<(usize, InputChunk)>(CHANNEL_CAPACITY);
     let (result_sender, chunk_receiver) = mpsc::sync_channel::<ChunkResult>(CHANNEL_CAPACITY);
 
     // 5) Spawn some worker threads that build filters (the expensive part).
-    //    You can tweak the number of threads as needed.
-    let num_workers = num_cpus::get(); // or set to some fixed number
+    //    Chunks count against the budget from being read until they are written.
+    let (num_workers, chunk_budget) = plan_memory(options.max_memory);
+    let budget = Arc::new(MemoryBudget::new(chunk_budget));
 
     let arc_rx = Arc::new(Mutex::new(worker_rx));
-    println!("num_workers: {}", num_workers);
     let mut handles: Vec<_> = vec![];
 
     for _ in 0..num_workers {
         let result_sender = result_sender.clone();
         let arc_rx = Arc::clone(&arc_rx);
+        let budget_guard = CloseOnDrop(Arc::clone(&budget));
+        let mut time_detector = options.time_detector.clone();
+        let filter_spec = options.filter;
 
         let handle = thread::spawn(move || {
-            let rx = arc_rx.lock().unwrap();
+            let _budget_guard = budget_guard;
             let mut set = FastSet::new(HASH_CAPACITY);
 
-            while let Ok((chunk_index, chunk_data)) = rx.recv() {
+            // Hold the receiver lock only while taking a chunk, so workers run in parallel
+            loop {
+                let received = arc_rx.lock().unwrap().recv();
+                let Ok((chunk_index, chunk)) = received else {
+                    break;
+                };
                 set.clear();
                 // Build the filter (expensive)
-                let filter = build_binaryfuse_filter(&chunk_data, &mut set);
-                println!("collision_count: {}", set.collision_count());
+                let filter = filter_spec.build_filter(&chunk.data, &mut set);
+                time_detector.set_last_write(chunk.last_write);
+                let time_range = time_detector.range(&chunk.data);
 
                 // Possibly compress here, or return the raw chunk_data and let
                 // the writer do the compression. In this example, we do NOT compress
                 // in the worker. We just build the filter.
 
-                // Send result to writer
+                // Send result to writer; it only goes away after an error, which it reports
                 let res = ChunkResult {
                     chunk_index,
-                    chunk_data,
+                    chunk,
                     filter,
+                    time_range,
                 };
-                if let Err(e) = result_sender.send(res) {
-                    eprintln!("Worker->Writer channel send error: {:?}", e);
+                if result_sender.send(res).is_err() {
                     break;
                 }
             }
@@ -122,14 +519,21 @@ pub fn run_build(
     // 6) Spawn the single writer thread
     let writer_handle = {
         let output_file = Arc::clone(&output_file);
-        let index_file = Arc::clone(&index_file);
-        thread::spawn(move || {
+        let budget = Arc::clone(&budget);
+        let budget_guard = CloseOnDrop(Arc::clone(&budget));
+        let filter_spec = options.filter;
+        thread::spawn(move || -> io::Result<File> {
+            // Once the writer is gone nothing is released any more
+            let _budget_guard = budget_guard;
             // We must preserve chunk order. We'll store results by chunk_index
             // and write them in ascending order.
             let mut next_to_write = 0usize;
             let mut pending: HashMap<usize, ChunkResult> = HashMap::new();
 
-            let bin_cfg: Configuration = standard();
+            let mut index_writer = match header_kind {
+                Some(kind) => IndexWriter::create(index_file, kind, filter_spec)?,
+                None => IndexWriter::append(index_file),
+            };
 
             while let Ok(chunk_res) = chunk_receiver.recv() {
                 // Insert into a pending map
@@ -140,17 +544,11 @@ pub fn run_build(
                 while let Some(res) = pending.remove(&next_to_write) {
                     // Actually write to .zst
                     let mut of = output_file.lock().unwrap();
-                    let frame_offset = of.seek(SeekFrom::Current(0))
-                        .expect("Failed to get zst offset");
+                    let frame_offset = of.stream_position()?;
 
                     // If you do the compression here:
-                    let frame_size = match compress_and_write_chunk(&res.chunk_data, &mut *of) {
-                        Ok(sz) => sz,
-                        Err(e) => {
-                            eprintln!("Writer error while compressing chunk: {:?}", e);
-                            return;
-                        }
-                    };
+                    let chunk = &res.chunk;
+                    let (frame_size, checksum) = compress_and_write_chunk(&chunk.data, &mut of)?;
 
                     drop(of); // release the lock
 
@@ -158,112 +556,699 @@ pub fn run_build(
                     let frame_info = FrameInfo {
                         frame_offset,
                         frame_size,
+                        checksum: Some(checksum),
+                        source: chunk.source.to_string(),
+                        source_offset: chunk.source_offset,
+                        source_line: chunk.source_line,
+                        uncompressed_size: chunk.data.len() as u64,
+                        line_count: chunk.line_count,
+                        time_range: res.time_range,
                     };
+                    index_writer.write_record(&frame_info, &res.filter)?;
+                    budget.release(chunk.data.len() as u64);
 
-                    let frame_info_bytes =
-                        encode_to_vec(&frame_info, bin_cfg)
-                            .expect("Failed to encode FrameInfo");
-                    let filter_bytes =
-                        encode_to_vec(&res.filter, bin_cfg)
-                            .expect("Failed to encode BinaryFuse8");
-
-                    let mut idxf = index_file.lock().unwrap();
-                    if let Err(e) = idxf.write_all(&frame_info_bytes) {
-                        eprintln!("Writer error writing FrameInfo: {:?}", e);
-                        return;
+                    if let Some((checkpoint_path, inputs)) = &checkpoint {
+                        output_file.lock().unwrap().sync_data()?;
+                        let index_file = index_writer.get_ref();
+                        index_file.sync_data()?;
+                        let checkpoint = inputs.checkpoint(
+                            chunk.input_index,
+                            chunk.source_offset + chunk.data.len() as u64,
+                            chunk.source_line + chunk.line_count,
+                            frame_offset + frame_size,
+                            index_file.metadata()?.len(),
+                        );
+                        checkpoint.store(checkpoint_path)?;
                     }
-                    if let Err(e) = idxf.write_all(&filter_bytes) {
-                        eprintln!("Writer error writing filter: {:?}", e);
-                        return;
-                    }
-                    drop(idxf);
 
                     // Move on to the next chunk
                     next_to_write += 1;
                 }
             }
+            Ok(index_writer.into_inner())
         })
     };
 
-    // 7) Chunk the file in the main thread and send to worker threads.
-    let mut start = 0usize;
-    let mut chunk_index = 0usize;
-    while start < file_len {
+    // 7) Pull chunks from the source in the main thread and send to worker threads.
+    let mut read_result = Ok(());
+    for (chunk_index, chunk) in chunks.enumerate() {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                read_result = Err(e);
+                break;
+            }
+        };
+
+        // Send it off; this only fails once the workers are gone, after the
+        // writer failed or a worker panicked, both reported below
+        budget.acquire(chunk.data.len() as u64);
+        if chunk_sender.send((chunk_index, chunk)).is_err() {
+            break;
+        }
+    }
+    drop(chunk_sender); // no more chunks will be produced
+
+    // 8) Wait for writer thread to finish
+    let write_result = writer_handle
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("writer thread panicked")));
+    // Wait for all worker threads to finish
+    let mut worker_result = Ok(());
+    for handle in handles {
+        if handle.join().is_err() {
+            worker_result = Err(io::Error::other("filter worker panicked"));
+        }
+    }
+    let index_file = write_result?;
+    worker_result?;
+    read_result?;
+
+    let output_file = Arc::try_unwrap(output_file)
+        .expect("writer thread still holds the data file")
+        .into_inner()
+        .unwrap();
+    Ok((output_file, index_file))
+}
+
+/// Split `max_memory` between filter workers and chunks in flight. Each
+/// worker gets room for one chunk besides its own working memory; without a
+/// budget there is a worker per core and no limit.
+fn plan_memory(max_memory: Option<u64>) -> (usize, u64) {
+    let cpus = num_cpus::get();
+    let Some(max_memory) = max_memory else {
+        return (cpus, u64::MAX);
+    };
+    let per_worker = WORKER_MEMORY + CHUNK_SIZE as u64;
+    let num_workers = ((max_memory / per_worker) as usize).clamp(1, cpus);
+    let chunk_budget = max_memory
+        .saturating_sub(num_workers as u64 * WORKER_MEMORY)
+        .max(CHUNK_SIZE as u64);
+    (num_workers, chunk_budget)
+}
+
+/// Caps the bytes of chunks between being read and being written. A single
+/// chunk is always let through, however large, so the build can progress.
+struct MemoryBudget {
+    limit: u64,
+    /// Bytes in use, or `None` once closed
+    in_use: Mutex<Option<u64>>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            in_use: Mutex::new(Some(0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until `size` more bytes fit
+    fn acquire(&self, size: u64) {
+        let mut in_use = self.in_use.lock().unwrap();
+        while let Some(used) = *in_use {
+            if used == 0 || used.saturating_add(size) <= self.limit {
+                *in_use = Some(used + size);
+                break;
+            }
+            in_use = self.released.wait(in_use).unwrap();
+        }
+    }
+
+    fn release(&self, size: u64) {
+        if let Some(used) = self.in_use.lock().unwrap().as_mut() {
+            *used -= size;
+        }
+        self.released.notify_all();
+    }
+
+    /// Stop limiting; called when a pipeline thread exits, so the reader
+    /// never waits for memory that will not be released any more
+    fn close(&self) {
+        *self.in_use.lock().unwrap() = None;
+        self.released.notify_all();
+    }
+}
+
+/// Closes the budget when the owning thread exits, normally or by panic
+struct CloseOnDrop(Arc<MemoryBudget>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Name of an output while it is being written, in the same directory so the
+/// final rename stays on one file system. It is fixed so a resumed build
+/// finds the files of the interrupted one.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// Open a partial output to continue writing after its first `len` bytes,
+/// dropping anything written after the checkpoint
+fn open_partial(path: &Path, len: u64) -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    if file.metadata()?.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is shorter than its checkpoint says", path),
+        ));
+    }
+    file.set_len(len)?;
+    file.seek(SeekFrom::Start(len))?;
+    Ok(file)
+}
+
+pub fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Make renames in the directory of `path` durable
+#[cfg(unix)]
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// End of the last frame an existing `.mg` points at, the length of the
+/// part of the index that decodes cleanly, and how its filters are built
+pub fn existing_archive_end(idx_path: &Path) -> io::Result<(u64, u64, FilterSpec)> {
+    let index_data = fs::read(idx_path)?;
+    let (header, records) = split_header(&index_data)?;
+    if header.kind != IndexKind::ZstdFrames {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} does not index a zstd archive; only those can be appended to", idx_path),
+        ));
+    }
+    // New records would not match the layout of the existing ones
+    if header.is_legacy_frames() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} has index format version {}; rewrite it with `mg reindex` before appending",
+                idx_path, header.version
+            ),
+        ));
+    }
+    let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+    let data_end = reader
+        .by_ref()
+        .last()
+        .map_or(0, |(frame_info, _)| frame_info.frame_offset + frame_info.frame_size);
+    // A record cut short by an interrupted append doesn't decode and is dropped
+    let index_len = (index_data.len() - reader.remaining().len()) as u64;
+    Ok((data_end, index_len, header.filter))
+}
+
+/// Side file holding the index records of an append in progress
+fn staging_path(idx_path: &Path) -> PathBuf {
+    let mut name = OsString::from(idx_path.as_os_str());
+    name.push(".append");
+    PathBuf::from(name)
+}
+
+/// Add the staged records after the first `index_len` bytes of the index
+fn commit_staged_records(idx_path: &Path, index_len: u64) -> io::Result<()> {
+    let staging = staging_path(idx_path);
+    let mut index_file = OpenOptions::new().write(true).open(idx_path)?;
+    index_file.set_len(index_len)?;
+    index_file.seek(SeekFrom::End(0))?;
+    io::copy(&mut File::open(&staging)?, &mut index_file)?;
+    index_file.sync_all()?;
+    fs::remove_file(staging)
+}
+
+/// Cuts a memory-mapped file into chunks of about `CHUNK_SIZE`, ending each
+/// at the first newline at or after the size limit. Chunks are ranges of the
+/// shared mapping, so the file is never copied.
+struct MmapChunker {
+    data: Arc<Mmap>,
+    start: usize,
+}
+
+impl MmapChunker {
+    /// Chunk `data` from offset `start` on
+    fn new(data: Arc<Mmap>, start: usize) -> Self {
+        Self { data, start }
+    }
+}
+
+impl Iterator for MmapChunker {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let file_len = self.data.len();
+        let start = self.start;
+        if start >= file_len {
+            return None;
+        }
+
         // Same logic as before to find chunk boundary
         let end_candidate = (start + CHUNK_SIZE).min(file_len);
         let actual_end = if end_candidate < file_len {
-            match find_line_boundary(&mmap[end_candidate..]) {
+            match find_line_boundary(&self.data[end_candidate..]) {
                 Some(rel_pos) => end_candidate + rel_pos + 1,
                 None => file_len,
             }
         } else {
             file_len
         };
+        self.start = actual_end;
 
-        // Copy the chunk into a Vec<u8> so we can send it to a worker thread
-        let chunk = mmap[start..actual_end].to_vec();
+        Some(Ok(Chunk::Mapped(Arc::clone(&self.data), start..actual_end)))
+    }
+}
 
-        // Send it off
-        if let Err(e) = chunk_sender.send((chunk_index, chunk)) {
-            eprintln!("Error sending chunk to worker: {:?}", e);
-            break;
-        }
+/// Cuts any `Read` source into the same chunks `MmapChunker` would produce
+/// for the same bytes, buffering only the current chunk
+struct StreamChunker<R> {
+    reader: R,
+    /// Bytes read past the end of the previous chunk
+    carry: Vec<u8>,
+    eof: bool,
+}
 
-        chunk_index += 1;
-        start = actual_end;
+impl<R: Read> StreamChunker<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, carry: Vec::new(), eof: false }
     }
-    drop(chunk_sender); // no more chunks will be produced
 
-    // 8) Wait for writer thread to finish
-    writer_handle.join().expect("Writer thread panicked");
-    // Wait for all worker threads to finish
-    for handle in handles {
-        handle.join().expect("Thread failed");
+    /// Append up to `want` more bytes to `buf`; returns how many were read
+    fn fill(&mut self, buf: &mut Vec<u8>, want: usize) -> io::Result<usize> {
+        let n = (&mut self.reader).take(want as u64).read_to_end(buf)?;
+        if n < want {
+            self.eof = true;
+        }
+        Ok(n)
     }
+}
 
-    println!("Build complete. ZST: {:?} | MG: {:?}", zst_path, idx_path);
-    Ok(())
+impl<R: Read> Iterator for StreamChunker<R> {
+    type Item = io::Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = std::mem::take(&mut self.carry);
+        if !self.eof && buf.len() < CHUNK_SIZE {
+            let want = CHUNK_SIZE - buf.len();
+            if let Err(e) = self.fill(&mut buf, want) {
+                return Some(Err(e));
+            }
+        }
+
+        // Extend past the size limit up to the next newline
+        let mut scanned = CHUNK_SIZE.min(buf.len());
+        loop {
+            if let Some(rel_pos) = find_line_boundary(&buf[scanned..]) {
+                self.carry = buf.split_off(scanned + rel_pos + 1);
+                break;
+            }
+            if self.eof {
+                break;
+            }
+            scanned = buf.len();
+            if let Err(e) = self.fill(&mut buf, STREAM_READ_SIZE) {
+                return Some(Err(e));
+            }
+        }
+
+        if buf.is_empty() { None } else { Some(Ok(Chunk::Owned(buf))) }
+    }
+}
+
+/// Number of lines in `data`, counting an unterminated last line
+pub fn count_lines(data: &[u8]) -> u64 {
+    let newlines = data.iter().filter(|&&c| c == b'\n').count() as u64;
+    newlines + u64::from(!data.is_empty() && !data.ends_with(b"\n"))
 }
 
 /// Just like original
-fn find_line_boundary(data: &[u8]) -> Option<usize> {
+pub fn find_line_boundary(data: &[u8]) -> Option<usize> {
     data.iter().position(|&c| c == b'\n')
 }
 
-/// Just like original
-fn compress_and_write_chunk(chunk: &[u8], output: &mut File) -> io::Result<u64> {
-    let start_pos = output.seek(SeekFrom::Current(0))?;
-    let mut encoder = Encoder::new(output, 0)?; // Level=0 for speed
+/// Compress `chunk` into one zstd frame with a content checksum, appending it
+/// to `output`. Returns the frame's size and the xxh3 hash of its bytes.
+pub fn compress_and_write_chunk(chunk: &[u8], output: &mut File) -> io::Result<(u64, u64)> {
+    let mut hashing = HashingWriter {
+        inner: output,
+        hasher: Xxh3::new(),
+        len: 0,
+    };
+    let mut encoder = Encoder::new(&mut hashing, 0)?; // Level=0 for speed
+    encoder.include_checksum(true)?;
     encoder.write_all(chunk)?;
-    let output_file = encoder.finish()?;
-    let end_pos = output_file.seek(SeekFrom::Current(0))?;
-    Ok(end_pos - start_pos)
+    encoder.finish()?;
+    Ok((hashing.len, hashing.hasher.digest()))
 }
 
-/// Build a BinaryFuse8 filter for 8-byte windows in the chunk
-fn build_binaryfuse_filter(chunk: &[u8],set: &mut FastSet) -> BinaryFuse8 {
-    for window in chunk.windows(8) {
-        let key = u64::from_le_bytes(window.try_into().unwrap());
-        set.insert(key);
+/// Passes writes through, hashing and counting the bytes that go by
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Xxh3,
+    len: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::frame_checksum;
+    use crate::testutil::{log_lines, temp_dir};
+
+    /// Build `inputs` into `zst_path` and the `.mg` next to it
+    fn build(inputs: &[&Path], zst_path: &Path, mode: BuildMode) -> io::Result<()> {
+        let inputs: Vec<String> = inputs.iter().map(|path| path.display().to_string()).collect();
+        let idx_path = zst_path.with_extension("mg");
+        let options = BuildOptions { mode, ..Default::default() };
+        run_build(&inputs, zst_path.to_str(), idx_path.to_str(), &options)
+    }
+
+    fn records(idx_path: &Path) -> Vec<FrameInfo> {
+        let index_data = fs::read(idx_path).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+        let frames = reader.by_ref().map(|(frame_info, _)| frame_info).collect();
+        assert!(reader.remaining().is_empty());
+        frames
+    }
+
+    /// Append lines of `line_len` bytes, newline included, until `data` is at least `len` long
+    fn fill_lines(data: &mut Vec<u8>, line_len: usize, len: usize) {
+        while data.len() < len {
+            data.extend(std::iter::repeat_n(b'x', line_len - 1));
+            data.push(b'\n');
+        }
+    }
+
+    #[test]
+    fn stream_chunker_cuts_like_mmap_chunker() {
+        // Over two chunks, with a line longer than a stream read across the
+        // first cut and no newline at the very end
+        let mut data = Vec::new();
+        fill_lines(&mut data, 100, CHUNK_SIZE - 1000);
+        let first_cut = data.len() + 4 * STREAM_READ_SIZE + 1;
+        data.extend(std::iter::repeat_n(b'y', 4 * STREAM_READ_SIZE));
+        data.push(b'\n');
+        fill_lines(&mut data, 100, 2 * CHUNK_SIZE + 1_000_000);
+        data.extend_from_slice(b"unterminated");
+        let dir = temp_dir();
+        let path = dir.path().join("input.log");
+        fs::write(&path, &data).unwrap();
+
+        let mmap = Arc::new(unsafe { Mmap::map(&File::open(&path).unwrap()).unwrap() });
+        let mapped: Vec<Chunk> = MmapChunker::new(mmap, 0).collect::<io::Result<_>>().unwrap();
+        let streamed: Vec<Chunk> = StreamChunker::new(File::open(&path).unwrap())
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        let lens = |chunks: &[Chunk]| chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>();
+        assert_eq!(mapped.len(), 3);
+        assert_eq!(mapped[0].len(), first_cut);
+        assert_eq!(lens(&streamed), lens(&mapped));
+        assert!(streamed.iter().zip(&mapped).all(|(s, m)| s[..] == m[..]));
+        assert_eq!(lens(&mapped).iter().sum::<usize>(), data.len());
+    }
+
+    #[test]
+    fn stream_chunker_handles_short_input() {
+        let chunks: Vec<Chunk> = StreamChunker::new(&b"one\ntwo"[..]).collect::<io::Result<_>>().unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(&chunks[0][..], b"one\ntwo");
+        assert!(StreamChunker::new(&b""[..]).next().is_none());
+    }
+
+    #[test]
+    fn failed_build_leaves_existing_archive_alone() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("app.log");
+        fs::write(&log_path, log_lines(0, 40_000)).unwrap();
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = zst_path.with_extension("mg");
+        build(&[&log_path], &zst_path, BuildMode::Create).unwrap();
+        let (data, index) = (fs::read(&zst_path).unwrap(), fs::read(&idx_path).unwrap());
+        let names = vec![log_path.display().to_string()];
+        let stamps = BuildInputs::stamp(&names).unwrap();
+        let options = BuildOptions::default();
+
+        // Failing before the first chunk is written leaves nothing behind
+        let chunks = std::iter::once(Err(io::Error::other("read failed")));
+        assert!(write_archive(chunks, &stamps, &zst_path, &idx_path, &options).is_err());
+        assert!(fs::read(&zst_path).unwrap() == data && fs::read(&idx_path).unwrap() == index);
+        assert!(!partial_path(&zst_path).exists() && !partial_path(&idx_path).exists());
+        assert!(!Checkpoint::path(&zst_path).exists());
+
+        // Failing later keeps the partial files for --resume, still apart from the archive
+        let chunks = InputChunks::new(&names, 0, 0, 0)
+            .take(1)
+            .chain(std::iter::once(Err(io::Error::other("read failed"))));
+        assert!(write_archive(chunks, &stamps, &zst_path, &idx_path, &options).is_err());
+        assert!(fs::read(&zst_path).unwrap() == data && fs::read(&idx_path).unwrap() == index);
+        assert!(partial_path(&zst_path).exists() && Checkpoint::path(&zst_path).exists());
+
+        // A missing input fails the build instead of reporting it complete
+        let missing = dir.path().join("missing.log");
+        assert!(build(&[&log_path, &missing], &zst_path, BuildMode::Create).is_err());
+        assert!(fs::read(&zst_path).unwrap() == data && fs::read(&idx_path).unwrap() == index);
+    }
+
+    #[test]
+    fn inputs_keep_their_own_frames_and_provenance() {
+        let dir = temp_dir();
+        let plain = dir.path().join("a.log");
+        let short = dir.path().join("b.log");
+        let gzipped = dir.path().join("c.log.gz");
+        fs::write(&plain, log_lines(0, 40_000)).unwrap();
+        fs::write(&short, log_lines(40_000, 10)).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&log_lines(40_010, 20_000)).unwrap();
+        fs::write(&gzipped, encoder.finish().unwrap()).unwrap();
+        let list = dir.path().join("inputs.txt");
+        fs::write(&list, format!("{}\r\n\n{}\n{}\n", plain.display(), short.display(), gzipped.display())).unwrap();
+        let inputs = read_input_list(&list).unwrap();
+        assert_eq!(inputs.len(), 3);
+        let zst_path = dir.path().join("day.zst");
+        let options = BuildOptions::default();
+        run_build(&inputs, zst_path.to_str(), None, &options).unwrap();
+
+        // Each input's frames follow each other from its start, and no frame spans two inputs
+        let frames = records(&zst_path.with_extension("mg"));
+        let mut frames = frames.iter().peekable();
+        for (input, lines) in inputs.iter().zip([40_000, 10, 20_000]) {
+            let mut end = (0, 0);
+            while let Some(frame) = frames.next_if(|frame| &frame.source == input) {
+                assert_eq!((frame.source_offset, frame.source_line), end);
+                end = (frame.source_offset + frame.uncompressed_size, frame.source_line + frame.line_count);
+            }
+            assert_eq!(end.1, lines);
+        }
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn frames_are_checksummed_in_data_and_index() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("app.log");
+        let zst_path = dir.path().join("app.zst");
+        fs::write(&log_path, log_lines(0, 40_000)).unwrap();
+        build(&[&log_path], &zst_path, BuildMode::Create).unwrap();
+
+        let data = fs::read(&zst_path).unwrap();
+        let frames = records(&zst_path.with_extension("mg"));
+        assert!(frames.len() >= 3);
+        for frame in &frames {
+            let bytes = &data[frame.frame_offset as usize..(frame.frame_offset + frame.frame_size) as usize];
+            assert_eq!(frame.checksum, Some(frame_checksum(bytes)));
+            // Frame header descriptor: the content checksum flag
+            assert_ne!(bytes[4] & 0x04, 0);
+        }
+
+        // zstd itself notices a flipped bit in the last block
+        let last = frames.last().unwrap();
+        let mut bytes = data[last.frame_offset as usize..].to_vec();
+        let end = bytes.len() - 8;
+        bytes[end] ^= 0x01;
+        assert!(zstd::decode_all(&bytes[..]).is_err());
     }
 
-    let numkeys = set.len();
-    let keys = set.extract();
+    #[test]
+    fn append_adds_frames_after_existing_ones() {
+        let dir = temp_dir();
+        let (first, second) = (dir.path().join("first.log"), dir.path().join("second.log"));
+        let zst_path = dir.path().join("day.zst");
+        let idx_path = dir.path().join("day.mg");
+        fs::write(&first, log_lines(0, 30_000)).unwrap();
+        fs::write(&second, log_lines(30_000, 20_000)).unwrap();
+
+        build(&[&first], &zst_path, BuildMode::Create).unwrap();
+        let before = fs::read(&zst_path).unwrap();
+        let frames_before = records(&idx_path);
+        assert!(frames_before.len() >= 2);
+        build(&[&second], &zst_path, BuildMode::Append).unwrap();
 
-    let filter = BinaryFuse8::try_from(&keys[..]).expect("Failed to build BinaryFuse8 filter");
+        // Existing frames and records are left as they were
+        let data = fs::read(&zst_path).unwrap();
+        assert!(data.starts_with(&before));
+        let frames = records(&idx_path);
+        assert!(frames.len() > frames_before.len());
+        for (frame, old) in frames.iter().zip(&frames_before) {
+            assert_eq!((frame.frame_offset, frame.checksum), (old.frame_offset, old.checksum));
+        }
 
-    // Some debug stats
-    /*
-    let bpe = (filter.len() as f64) * 16.0 / (numkeys as f64);
-    let mut rng = rand::thread_rng();
-    let false_positives: usize = (0..numkeys)
-        .map(|_| rng.gen::<u64>())
-        .filter(|n| filter.contains(n))
-        .count();
-    let fp_rate: f64 = (false_positives * 100) as f64 / numkeys as f64;
-    println!("set size: {}, bpe: {}, fp_rate: {}%", numkeys, bpe, fp_rate);
-    */
-    println!("set size: {}", numkeys);
+        // New ones follow them, positioned within their own input
+        let mut end = 0;
+        for frame in &frames {
+            assert_eq!(frame.frame_offset, end);
+            end += frame.frame_size;
+            assert_eq!(frame.checksum, Some(frame_checksum(&data[frame.frame_offset as usize..end as usize])));
+        }
+        assert_eq!(end, data.len() as u64);
+        let appended = &frames[frames_before.len()..];
+        let mut source_end = (0, 0);
+        for frame in appended {
+            assert_eq!(frame.source, second.display().to_string());
+            assert_eq!((frame.source_offset, frame.source_line), source_end);
+            source_end = (frame.source_offset + frame.uncompressed_size, frame.source_line + frame.line_count);
+        }
+        assert_eq!(source_end, (fs::metadata(&second).unwrap().len(), 20_000));
 
-    filter
+        let mut expected = log_lines(0, 30_000);
+        expected.extend(log_lines(30_000, 20_000));
+        assert!(zstd::decode_all(&data[..]).unwrap() == expected);
+    }
+
+    #[test]
+    fn resumed_build_matches_uninterrupted_one() {
+        let dir = temp_dir();
+        let paths: Vec<PathBuf> = [(0, 40_000), (40_000, 500), (40_500, 20_000)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(first, count))| {
+                let path = dir.path().join(format!("{}.log", i));
+                fs::write(&path, log_lines(first, count)).unwrap();
+                path
+            })
+            .collect();
+        let inputs: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let whole_zst = dir.path().join("whole.zst");
+        build(&inputs, &whole_zst, BuildMode::Create).unwrap();
+
+        // Fails partway through the first input, after two chunks
+        let resumed_zst = dir.path().join("resumed.zst");
+        let resumed_idx = resumed_zst.with_extension("mg");
+        let names: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+        let chunks = InputChunks::new(&names, 0, 0, 0)
+            .take(2)
+            .chain(std::iter::once(Err(io::Error::other("interrupted"))));
+        let options = BuildOptions::default();
+        let stamps = BuildInputs::stamp(&names).unwrap();
+        assert!(write_archive(chunks, &stamps, &resumed_zst, &resumed_idx, &options).is_err());
+        assert!(!resumed_zst.exists());
+        let checkpoint = Checkpoint::load(&Checkpoint::path(&resumed_zst)).unwrap();
+        assert_eq!(checkpoint.input_index, 0);
+        assert!(checkpoint.input_offset > 0);
+
+        build(&inputs, &resumed_zst, BuildMode::Resume).unwrap();
+        assert!(!Checkpoint::path(&resumed_zst).exists());
+        assert!(fs::read(&resumed_zst).unwrap() == fs::read(&whole_zst).unwrap());
+
+        // Filters leave their unused slots random, so they are compared by
+        // what they hold rather than bit for bit
+        let data = fs::read(&resumed_zst).unwrap();
+        let (resumed, whole) = (fs::read(&resumed_idx).unwrap(), fs::read(whole_zst.with_extension("mg")).unwrap());
+        let (header, resumed_records) = split_header(&resumed).unwrap();
+        let (_, whole_records) = split_header(&whole).unwrap();
+        assert_eq!(resumed.len(), whole.len());
+        assert_eq!(resumed[..resumed.len() - resumed_records.len()], whole[..whole.len() - whole_records.len()]);
+        let resumed_entries: Vec<_> = IndexReader::<FrameInfo>::new(&header, resumed_records).collect();
+        let whole_entries: Vec<_> = IndexReader::<FrameInfo>::new(&header, whole_records).collect();
+        assert_eq!(resumed_entries.len(), whole_entries.len());
+        for ((frame, filter), (whole_frame, whole_filter)) in resumed_entries.iter().zip(&whole_entries) {
+            assert_eq!(format!("{:?}", frame), format!("{:?}", whole_frame));
+            assert_eq!(filter.len(), whole_filter.len());
+            let end = (frame.frame_offset + frame.frame_size) as usize;
+            let chunk = zstd::decode_all(&data[frame.frame_offset as usize..end]).unwrap();
+            assert!(header.filter.keys(&chunk).all(|key| filter.contains(&key)));
+        }
+    }
+
+    #[test]
+    fn resume_refuses_changed_inputs() {
+        let dir = temp_dir();
+        let (first, second) = (dir.path().join("0.log"), dir.path().join("1.log"));
+        fs::write(&first, log_lines(0, 40_000)).unwrap();
+        fs::write(&second, log_lines(40_000, 500)).unwrap();
+        let zst_path = dir.path().join("out.zst");
+        let names = vec![first.display().to_string(), second.display().to_string()];
+        let interrupt = || {
+            let chunks = InputChunks::new(&names, 0, 0, 0)
+                .take(1)
+                .chain(std::iter::once(Err(io::Error::other("interrupted"))));
+            let stamps = BuildInputs::stamp(&names).unwrap();
+            let options = BuildOptions::default();
+            assert!(write_archive(chunks, &stamps, &zst_path, &zst_path.with_extension("mg"), &options).is_err());
+        };
+
+        // A different input list
+        interrupt();
+        let err = build(&[&second, &first], &zst_path, BuildMode::Resume).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // The input being read was rewritten
+        fs::write(&first, log_lines(1, 40_000)).unwrap();
+        let err = build(&[&first, &second], &zst_path, BuildMode::Resume).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(Checkpoint::path(&zst_path).exists());
+
+        // Inputs past the checkpoint may change
+        interrupt();
+        fs::write(&second, log_lines(40_000, 600)).unwrap();
+        build(&[&first, &second], &zst_path, BuildMode::Resume).unwrap();
+        let mut expected = log_lines(1, 40_000);
+        expected.extend(log_lines(40_000, 600));
+        assert!(zstd::decode_all(&fs::read(&zst_path).unwrap()[..]).unwrap() == expected);
+    }
+
+    #[test]
+    fn syslog_lines_are_dated_by_input_last_write() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("syslog.1");
+        let zst_path = dir.path().join("syslog.zst");
+        fs::write(&log_path, b"Dec 30 23:59:00 host cron[1]: job\nJan  2 00:00:05 host cron[1]: job\n").unwrap();
+        let written = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_546_646_400); // 2019-01-05
+        File::options().write(true).open(&log_path).unwrap().set_modified(written).unwrap();
+
+        build(&[&log_path], &zst_path, BuildMode::Create).unwrap();
+        let range = records(&zst_path.with_extension("mg"))[0].time_range.unwrap();
+        assert_eq!((range.min, range.max), (1_546_214_340_000, 1_546_387_205_000));
+    }
 }
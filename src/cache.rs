@@ -0,0 +1,291 @@
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::storage::{LogStorage, StorageError};
+
+/// On-disk LRU cache of fetched data blocks, wrapped around any `LogStorage`.
+///
+/// Entries are keyed by the archive identity plus `(offset, size)`. The
+/// identity is a hash of the storage location and the index contents, so a
+/// rebuilt or re-uploaded archive never hits blocks cached for the old one.
+/// Recency is tracked through file mtimes, so the cache survives restarts and
+/// can be shared by concurrent searches.
+pub struct CachedStorage {
+    inner: Box<dyn LogStorage>,
+    dir: PathBuf,
+    budget: u64,
+    location: String,
+    identity: Cell<Option<u64>>,
+    /// Total size of cached blocks as of the last directory walk plus what
+    /// this process inserted since; blocks other processes add are only
+    /// counted on the next walk
+    total: Cell<u64>,
+}
+
+impl CachedStorage {
+    /// `location` names the archive (URL or path); `budget` is the maximum
+    /// total size of cached blocks in bytes.
+    pub fn new(
+        inner: Box<dyn LogStorage>,
+        location: &str,
+        dir: &Path,
+        budget: u64,
+    ) -> Result<Self, StorageError> {
+        fs::create_dir_all(dir)?;
+        let cache = Self {
+            inner,
+            dir: dir.to_path_buf(),
+            budget,
+            location: location.to_string(),
+            identity: Cell::new(None),
+            total: Cell::new(0),
+        };
+        // The budget may have shrunk since the last run
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    fn entry_path(&self, offset: u64, size: u64) -> Option<PathBuf> {
+        // Without the index we can't tell archive versions apart, so don't cache
+        let identity = self.identity.get()?;
+        Some(self.dir.join(format!("{:016x}-{}-{}.blk", identity, offset, size)))
+    }
+
+    fn lookup(&self, path: &Path, size: u64, buf: &mut Vec<u8>) -> bool {
+        let Ok(data) = fs::read(path) else {
+            return false;
+        };
+        if data.len() as u64 != size {
+            return false;
+        }
+        // Mark as recently used; failing to do so only skews eviction
+        if let Ok(file) = File::options().write(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        *buf = data;
+        true
+    }
+
+    fn insert(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if data.len() as u64 > self.budget {
+            return Ok(());
+        }
+        // Write under a temporary name so concurrent readers never see a partial block
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        drop(file);
+        // Another search may have cached the same block meanwhile
+        let replaced = fs::metadata(path).map_or(0, |meta| meta.len());
+        fs::rename(&tmp, path)?;
+        self.total.set(self.total.get().saturating_sub(replaced) + data.len() as u64);
+        if self.total.get() > self.budget {
+            self.evict()?;
+        }
+        Ok(())
+    }
+
+    /// Walk the cache directory for its total size, removing least recently
+    /// used blocks until it fits the budget.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let mut total = 0u64;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "blk") {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            total += meta.len();
+            entries.push((meta.modified()?, meta.len(), path));
+        }
+        if total <= self.budget {
+            self.total.set(total);
+            return Ok(());
+        }
+
+        entries.sort_by_key(|(mtime, _, _)| *mtime);
+        for (_, len, path) in entries {
+            if total <= self.budget {
+                break;
+            }
+            // Another process may have evicted it already
+            if fs::remove_file(&path).is_ok() {
+                total -= len;
+            }
+        }
+        self.total.set(total);
+        Ok(())
+    }
+}
+
+impl LogStorage for CachedStorage {
+    fn fetch_index(&self) -> Result<Vec<u8>, StorageError> {
+        let data = self.inner.fetch_index()?;
+        let mut keyed = Vec::with_capacity(self.location.len() + data.len());
+        keyed.extend_from_slice(self.location.as_bytes());
+        keyed.extend_from_slice(&data);
+        self.identity.set(Some(gxhash::gxhash64(&keyed, 0)));
+        Ok(data)
+    }
+
+    fn read_block(&self, offset: u64, size: u64) -> Result<Vec<u8>, StorageError> {
+        let mut buffer = Vec::new();
+        self.read_block_into(offset, size, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_block_into(&self, offset: u64, size: u64, buf: &mut Vec<u8>) -> Result<(), StorageError> {
+        let Some(path) = self.entry_path(offset, size) else {
+            return self.inner.read_block_into(offset, size, buf);
+        };
+        if self.lookup(&path, size, buf) {
+            return Ok(());
+        }
+
+        self.inner.read_block_into(offset, size, buf)?;
+        // The cache is an optimisation; a full disk must not fail the search
+        if let Err(e) = self.insert(&path, buf) {
+            eprintln!("Block cache write failed: {}", e);
+        }
+        Ok(())
+    }
+
+    fn discard_block(&self, offset: u64, size: u64) {
+        if let Some(path) = self.entry_path(offset, size)
+            && fs::remove_file(&path).is_ok()
+        {
+            self.total.set(self.total.get().saturating_sub(size));
+        }
+    }
+
+    fn prefetch(&self, blocks: &[(u64, u64)]) {
+        let misses: Vec<(u64, u64)> = blocks
+            .iter()
+            .copied()
+            .filter(|&(offset, size)| {
+                self.entry_path(offset, size).is_none_or(|path| !path.exists())
+            })
+            .collect();
+        self.inner.prefetch(&misses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    /// In-memory archive counting the blocks read from it
+    struct Counting {
+        index: Vec<u8>,
+        data: Vec<u8>,
+        reads: Rc<Cell<usize>>,
+    }
+
+    impl LogStorage for Counting {
+        fn fetch_index(&self) -> Result<Vec<u8>, StorageError> {
+            Ok(self.index.clone())
+        }
+
+        fn read_block(&self, offset: u64, size: u64) -> Result<Vec<u8>, StorageError> {
+            self.reads.set(self.reads.get() + 1);
+            Ok(self.data[offset as usize..(offset + size) as usize].to_vec())
+        }
+    }
+
+    fn cached(index: &[u8], dir: &Path, budget: u64) -> (CachedStorage, Rc<Cell<usize>>) {
+        let reads = Rc::new(Cell::new(0));
+        let data = (0..=255u8).cycle().take(1000).collect();
+        let inner = Counting { index: index.to_vec(), data, reads: Rc::clone(&reads) };
+        let cache = CachedStorage::new(Box::new(inner), "gs://bucket/app.zst", dir, budget).unwrap();
+        cache.fetch_index().unwrap();
+        (cache, reads)
+    }
+
+    fn cached_blocks(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".blk"))
+            .map(|name| name.rsplit_once('-').unwrap().0.split_once('-').unwrap().1.to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn repeated_reads_hit_the_cache() {
+        let dir = temp_dir();
+        let (cache, reads) = cached(b"index", dir.path(), 1 << 20);
+        let first = cache.read_block(100, 50).unwrap();
+        assert_eq!(cache.read_block(100, 50).unwrap(), first);
+        assert_eq!(reads.get(), 1);
+
+        // A later run over the same archive finds the block too
+        let (cache, reads) = cached(b"index", dir.path(), 1 << 20);
+        assert_eq!(cache.read_block(100, 50).unwrap(), first);
+        assert_eq!(reads.get(), 0);
+
+        // A rebuilt archive doesn't
+        let (cache, reads) = cached(b"other index", dir.path(), 1 << 20);
+        cache.read_block(100, 50).unwrap();
+        assert_eq!(reads.get(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let dir = temp_dir();
+        let (cache, reads) = cached(b"index", dir.path(), 250);
+        for offset in [0, 100, 0, 200] {
+            cache.read_block(offset, 100).unwrap();
+            // Recency is kept in mtimes, which need to tell the reads apart
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reads.get(), 3);
+        assert_eq!(cached_blocks(dir.path()), ["0", "200"]);
+
+        cache.read_block(0, 100).unwrap();
+        cache.read_block(100, 100).unwrap();
+        assert_eq!(reads.get(), 4);
+    }
+
+    #[test]
+    fn cache_stays_within_its_budget() {
+        let dir = temp_dir();
+        let (cache, _) = cached(b"index", dir.path(), 300);
+        for offset in (0..900).step_by(100) {
+            cache.read_block(offset, 100).unwrap();
+            assert!(cached_blocks(dir.path()).len() <= 3);
+        }
+        // A block larger than the whole budget is never stored
+        cache.read_block(0, 400).unwrap();
+        assert_eq!(cached_blocks(dir.path()).len(), 3);
+
+        // Reopening with a smaller budget trims the cache straight away
+        cached(b"index", dir.path(), 100);
+        assert_eq!(cached_blocks(dir.path()).len(), 1);
+    }
+
+    #[test]
+    fn discarded_blocks_are_fetched_again() {
+        let dir = temp_dir();
+        let (cache, reads) = cached(b"index", dir.path(), 1 << 20);
+        cache.read_block(0, 100).unwrap();
+        cache.read_block(100, 100).unwrap();
+        cache.discard_block(0, 100);
+        assert_eq!(cached_blocks(dir.path()), ["100"]);
+
+        cache.read_block(0, 100).unwrap();
+        cache.read_block(100, 100).unwrap();
+        assert_eq!(reads.get(), 3);
+    }
+}
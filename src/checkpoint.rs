@@ -0,0 +1,155 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bincode::{config::standard, decode_from_slice, encode_to_vec, Decode, Encode};
+use xxhash_rust::xxh3::Xxh3;
+
+/// Progress of a build, saved after every written chunk so that
+/// `mg build --resume` can continue an interrupted build
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Checkpoint {
+    /// Input the last written frame was cut from, by position in the input list
+    pub input_index: u64,
+    /// Bytes of that input (after decompression) covered by the frames written so far
+    pub input_offset: u64,
+    /// Lines of that input covered by the frames written so far
+    pub input_line: u64,
+    /// Length of the partial `.zst` up to the end of the last complete frame
+    pub zst_len: u64,
+    /// Length of the partial `.mg` up to the end of the last complete record
+    pub idx_len: u64,
+    /// That input as it was when the build started
+    pub input: InputStamp,
+    /// Hash of the stamps of the inputs before it, in order
+    pub earlier_inputs: u64,
+}
+
+/// What identifies an input between a build and its resumption
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct InputStamp {
+    pub path: String,
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch
+    pub modified: u64,
+}
+
+impl InputStamp {
+    /// Stamp `path` with its current size and modification time; stdin has neither
+    pub fn of(path: &str) -> io::Result<Self> {
+        let (size, modified) = if path == "-" {
+            (0, 0)
+        } else {
+            let metadata = fs::metadata(path)?;
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+            (metadata.len(), modified.as_nanos() as u64)
+        };
+        Ok(Self { path: path.to_string(), size, modified })
+    }
+}
+
+/// The inputs of a build, stamped when it starts. Checkpoints carry the
+/// stamp of the current input and a hash of the ones before it, so storing
+/// one stays cheap however long the input list is.
+#[derive(Debug, Clone)]
+pub struct BuildInputs {
+    stamps: Vec<InputStamp>,
+    /// `earlier[i]` hashes the stamps of the inputs before input `i`
+    earlier: Vec<u64>,
+}
+
+impl BuildInputs {
+    pub fn stamp(inputs: &[String]) -> io::Result<Self> {
+        let stamps = inputs.iter().map(|input| InputStamp::of(input)).collect::<io::Result<Vec<_>>>()?;
+        let mut hasher = Xxh3::new();
+        let mut earlier = Vec::with_capacity(stamps.len());
+        for stamp in &stamps {
+            earlier.push(hasher.digest());
+            hasher.update(&encode_to_vec(stamp, standard()).map_err(io::Error::other)?);
+        }
+        Ok(Self { stamps, earlier })
+    }
+
+    /// The checkpoint of a build that got `input_offset` bytes and `input_line`
+    /// lines into input `input_index`
+    pub fn checkpoint(&self, input_index: usize, input_offset: u64, input_line: u64, zst_len: u64, idx_len: u64)
+        -> Checkpoint {
+        Checkpoint {
+            input_index: input_index as u64,
+            input_offset,
+            input_line,
+            zst_len,
+            idx_len,
+            input: self.stamps[input_index].clone(),
+            earlier_inputs: self.earlier[input_index],
+        }
+    }
+
+    /// Refuse to resume from `checkpoint` when an input it already covers
+    /// has changed, or the input list before it differs
+    pub fn check(&self, checkpoint: &Checkpoint) -> io::Result<()> {
+        let index = checkpoint.input_index as usize;
+        let changed = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        let Some(stamp) = self.stamps.get(index) else {
+            return changed("the build being resumed had more inputs than were given".to_string());
+        };
+        if stamp.path != checkpoint.input.path {
+            return changed(format!(
+                "the build being resumed was reading {:?} where {:?} is given; pass the same inputs",
+                checkpoint.input.path, stamp.path,
+            ));
+        }
+        if *stamp != checkpoint.input {
+            return changed(format!(
+                "{:?} changed since the build was interrupted; start it over without --resume",
+                stamp.path,
+            ));
+        }
+        if self.earlier[index] != checkpoint.earlier_inputs {
+            return changed(format!(
+                "the inputs before {:?} changed since the build was interrupted; start it over without --resume",
+                stamp.path,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Checkpoint {
+    /// Where the checkpoint of a build of `zst_path` lives
+    pub fn path(zst_path: &Path) -> PathBuf {
+        let mut name = OsString::from(zst_path.as_os_str());
+        name.push(".ckpt");
+        PathBuf::from(name)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no checkpoint at {:?}; there is no interrupted build to resume", path),
+            ),
+            _ => e,
+        })?;
+        let (checkpoint, _): (Self, usize) = decode_from_slice(&data, standard()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("bad checkpoint {:?}: {}", path, e))
+        })?;
+        Ok(checkpoint)
+    }
+
+    /// Replace the checkpoint at `path` atomically. The outputs it describes
+    /// must already be synced.
+    pub fn store(&self, path: &Path) -> io::Result<()> {
+        let data = encode_to_vec(self, standard()).map_err(io::Error::other)?;
+        let mut tmp_name = OsString::from(path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+}
@@ -1,4 +1,17 @@
-use clap::{Parser, Subcommand};
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+#[cfg(feature = "gcs")]
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+
+#[cfg(feature = "gcs")]
+use crate::retry::RetryPolicy;
+use crate::storage::StorageOptions;
+use crate::index::{FilterSpec, FilterType};
+use crate::timestamp::{parse_time_bound, TimeDetector, TimeFormat};
+use crate::utils::{parse_frame_range, parse_percent, parse_size};
 
 /// Command-line interface definition
 #[derive(Parser)]
@@ -16,16 +29,54 @@ pub struct Cli {
 pub enum Commands {
     /// Build an index from a large log
     Build {
-        /// Path to input log file
-        input: String,
+        /// Paths to input log files, or `-` to read stdin. Several inputs
+        /// (or stdin) require --zst and are packed into one archive in order.
+        /// gzip, bzip2, xz and zstd input is decompressed on the fly
+        #[arg(required_unless_present = "input_list")]
+        inputs: Vec<String>,
 
-        /// Optional output .zst file
+        /// Read further input paths from this file, one per line
+        #[arg(long = "input-list", value_name = "FILE")]
+        input_list: Option<PathBuf>,
+
+        /// Optional output .zst file; a gs:// URL builds locally, then uploads
         #[arg(long = "zst", short = 'z')]
         zst: Option<String>,
 
-        /// Optional output .idx file
+        /// Optional output .idx file (or gs:// URL when uploading)
         #[arg(long = "idx", short = 'i')]
         idx: Option<String>,
+
+        /// Add frames to the end of an existing local archive instead of replacing it
+        #[arg(long = "append")]
+        append: bool,
+
+        /// Keep indexing the input as it grows, like `tail -F`, appending a
+        /// frame per full chunk or after --idle-timeout without new data
+        #[arg(long = "follow", conflicts_with = "append")]
+        follow: bool,
+
+        /// Continue an interrupted build of the same input from its checkpoint
+        #[arg(long = "resume", conflicts_with_all = ["append", "follow"])]
+        resume: bool,
+
+        /// Approximate memory cap for the build pipeline, e.g. 1G; fewer
+        /// filter workers and chunks in flight are used to stay within it
+        #[arg(long = "max-memory", value_name = "SIZE", value_parser = parse_size)]
+        max_memory: Option<u64>,
+
+        /// With --follow, seal pending complete lines after this many idle seconds
+        #[arg(long = "idle-timeout", value_name = "SECS", default_value_t = 30, requires = "follow")]
+        idle_timeout: u64,
+
+        #[command(flatten)]
+        time: TimeArgs,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[command(flatten)]
+        storage: StorageArgs,
     },
     /// Search within existing .zst + .idx files
     Search {
@@ -38,5 +89,400 @@ pub enum Commands {
 
         /// The search pattern (string or bytes)
         pattern: String,
+
+        /// Prefix each output line with the input file it was built from
+        #[arg(long = "with-filename", short = 'H')]
+        with_filename: bool,
+
+        /// Prefix each output line with its line number in that file
+        #[arg(long = "line-number", short = 'n')]
+        line_number: bool,
+
+        /// Prefix each output line with the byte offset of its start in that file
+        #[arg(long = "byte-offset", short = 'b')]
+        byte_offset: bool,
+
+        /// Only output lines timestamped at or after this time, e.g.
+        /// 2026-10-01T00:00 (UTC unless a zone is given); frames ending
+        /// earlier are skipped without reading them
+        #[arg(long = "since", value_name = "TIME", value_parser = parse_time_bound)]
+        since: Option<i64>,
+
+        /// Only output lines timestamped before this time
+        #[arg(long = "until", value_name = "TIME", value_parser = parse_time_bound)]
+        until: Option<i64>,
+
+        #[command(flatten)]
+        time: TimeArgs,
+
+        /// Cache fetched blocks in this directory (useful for remote archives)
+        #[arg(long = "block-cache", value_name = "DIR")]
+        block_cache: Option<PathBuf>,
+
+        /// Size budget of the block cache, e.g. 512M or 10G
+        #[arg(long = "block-cache-size", value_name = "SIZE", default_value = "1G", value_parser = parse_size)]
+        block_cache_size: u64,
+
+        #[command(flatten)]
+        storage: StorageArgs,
+    },
+    /// Write a .mg for an existing multi-frame .zst or a plain .gz without
+    /// touching the data. Each .zst frame is indexed on its own, so lines split
+    /// across frames by the producing tool can't be matched as a whole. A .gz
+    /// gets access points roughly every 64MB so search can inflate only the
+    /// candidate chunks.
+    Index {
+        /// Path to existing .zst or .gz file
+        zst: String,
+
+        /// Optional output .mg file (defaults to the data path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        #[command(flatten)]
+        time: TimeArgs,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Write a fresh .mg for an archive from its .zst alone, for instance with
+    /// other filter settings or after the .mg was lost. Frames recorded in the
+    /// existing .mg keep their sources; without a readable one the data is
+    /// scanned for frames. Frames are indexed in parallel and the data is
+    /// never modified.
+    Reindex {
+        /// Path to the .zst (or indexed .gz) file
+        zst: String,
+
+        /// Optional .mg file to read and replace (defaults to the data path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        #[command(flatten)]
+        time: TimeArgs,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Append a log to an existing archive: new frames go after the last frame
+    /// of the .zst, then their records are added to the .mg. Existing frames
+    /// are never rewritten; a missing archive is created.
+    Append {
+        /// Path to the .zst to extend
+        zst: String,
+
+        /// Path to the log to add, or `-` to read stdin.
+        /// gzip, bzip2, xz and zstd input is decompressed on the fly
+        input: String,
+
+        /// Optional .mg file (defaults to the .zst path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        /// Approximate memory cap for the build pipeline, e.g. 1G
+        #[arg(long = "max-memory", value_name = "SIZE", value_parser = parse_size)]
+        max_memory: Option<u64>,
+
+        #[command(flatten)]
+        time: TimeArgs,
+    },
+    /// Join local archives into one, in order, without recompressing: frames
+    /// are copied as they are and their records moved into one .mg, keeping
+    /// sources, time ranges and filters. Each input's .mg sits next to it.
+    Concat {
+        /// Paths to the .zst files to join
+        #[arg(required_unless_present = "input_list")]
+        inputs: Vec<String>,
+
+        /// Read further input paths from this file, one per line
+        #[arg(long = "input-list", value_name = "FILE")]
+        input_list: Option<PathBuf>,
+
+        /// Output .zst file
+        #[arg(long = "zst", short = 'z')]
+        zst: String,
+
+        /// Optional output .mg file (defaults to the .zst path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+    },
+    /// Copy whole frames of a local archive into a new .zst + .mg pair without
+    /// recompressing, chosen by frame number and recorded time range. Frames
+    /// are copied whole, so lines just outside a time window come along.
+    Slice {
+        /// Path to local .zst file; its .mg sits next to it
+        zst: String,
+
+        /// Output .zst file
+        #[arg(long = "zst", short = 'z')]
+        out: String,
+
+        /// Optional output .mg file (defaults to the output path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        /// Frames to copy, counting from 0: 100..200 (up to 199), 100.. or ..200
+        #[arg(long = "frames", value_name = "RANGE", value_parser = parse_frame_range)]
+        frames: Option<Range<u64>>,
+
+        /// Only copy frames with lines timestamped at or after this time;
+        /// frames without recorded times are copied
+        #[arg(long = "since", value_name = "TIME", value_parser = parse_time_bound)]
+        since: Option<i64>,
+
+        /// Only copy frames with lines timestamped before this time
+        #[arg(long = "until", value_name = "TIME", value_parser = parse_time_bound)]
+        until: Option<i64>,
+    },
+    /// Copy the frames of a local archive, in order, into numbered .zst + .mg
+    /// pairs (day.0000.zst, day.0001.zst, ...) of at most --max-size of data
+    /// each, without recompressing
+    Split {
+        /// Path to local .zst file; its .mg sits next to it
+        zst: String,
+
+        /// Name the parts after this path instead of the archive
+        #[arg(long = "zst", short = 'z')]
+        out: Option<String>,
+
+        /// Largest .zst of a part, e.g. 4G; a larger frame gets a part of its own
+        #[arg(long = "max-size", value_name = "SIZE", value_parser = parse_size)]
+        max_size: u64,
+    },
+    /// Report the format, sizes, filter statistics and time range of an
+    /// archive. Only the .mg is read, so remote archives cost one fetch.
+    Info {
+        /// Path to .zst or indexed .gz file (local path or gs://bucket/path)
+        zst: String,
+
+        /// Optional path to .mg file (defaults to the data path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        /// Print a JSON object instead of text
+        #[arg(long = "json")]
+        json: bool,
+
+        #[command(flatten)]
+        storage: StorageArgs,
+    },
+    /// Check an archive for damage: the .mg must point at whole, contiguous
+    /// frames of the data, and every frame must match its checksums and
+    /// decompress to the recorded size. Damaged frames are listed by number
+    /// and the command fails.
+    Verify {
+        /// Path to local .zst or indexed .gz file
+        zst: String,
+
+        /// Optional path to .mg file (defaults to the data path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        /// Also rebuild each frame's filter and compare it with the stored one
+        #[arg(long = "deep")]
+        deep: bool,
+    },
+    /// Write a .mgpar holding Reed-Solomon parity over groups of the archive's
+    /// frames, so `mg repair` can rebuild frames that go bad. Rerun it after
+    /// appending; new frames are not covered until then.
+    Parity {
+        /// Path to .zst file (local path or gs://bucket/path)
+        zst: String,
+
+        /// Optional path to .mg file (defaults to the .zst path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        /// Path of the local .mgpar to write (defaults to the .zst path with .mgpar)
+        #[arg(long = "mgpar", value_name = "PATH")]
+        mgpar: Option<String>,
+
+        /// Parity size relative to the data, rounded up to whole parity frames
+        /// per group; a group survives as many damaged frames as it has parity frames
+        #[arg(long = "overhead", value_name = "PERCENT", default_value = "5", value_parser = parse_percent)]
+        overhead: f64,
+
+        /// Frames per parity group (at most 255)
+        #[arg(long = "group", value_name = "FRAMES", default_value_t = 20)]
+        group: usize,
+
+        #[command(flatten)]
+        storage: StorageArgs,
+    },
+    /// Rebuild damaged frames of a local archive from its .mgpar and write
+    /// them back in place. Fails if a group has more damaged frames than
+    /// intact parity frames.
+    Repair {
+        /// Path to local .zst file
+        zst: String,
+
+        /// Path of the .mgpar (defaults to the .zst path with .mgpar)
+        #[arg(long = "mgpar", value_name = "PATH")]
+        mgpar: Option<String>,
+    },
+    /// Recover a local archive left behind by a crashed build or append: cut
+    /// the .zst back to its last complete frame, keep the .mg records that
+    /// still match the data and index the complete frames past them. Without
+    /// the .zst, the partial files of an interrupted build are salvaged.
+    Salvage {
+        /// Path to local .zst file
+        zst: String,
+
+        /// Optional path to .mg file (defaults to the .zst path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        #[command(flatten)]
+        time: TimeArgs,
     },
+    /// Upload a built .zst + .mg pair to object storage (data first, index last)
+    Push {
+        /// Path to local .zst file
+        zst: String,
+
+        /// Destination of the .zst (gs://bucket/path)
+        dest: String,
+
+        /// Optional path to local .mg file (defaults to the .zst path with .mg)
+        #[arg(long = "idx", short = 'i')]
+        idx: Option<String>,
+
+        /// Optional destination of the .mg (defaults to the destination with .mg)
+        #[arg(long = "dest-idx")]
+        dest_idx: Option<String>,
+
+        /// Replace existing objects instead of failing
+        #[arg(long = "force")]
+        force: bool,
+
+        #[command(flatten)]
+        storage: StorageArgs,
+    },
+}
+
+/// Options shared by every subcommand that talks to remote storage. Builds
+/// without the `gcs` feature have no remote storage and none of these flags.
+#[derive(Args)]
+pub struct StorageArgs {
+    /// Attempts per remote request before giving up on transient errors
+    #[cfg(feature = "gcs")]
+    #[arg(long = "retries", value_name = "N", default_value_t = 5)]
+    pub retries: u32,
+
+    /// Timeout of a single remote request, in seconds
+    #[cfg(feature = "gcs")]
+    #[arg(long = "timeout", value_name = "SECS", default_value_t = 60)]
+    pub timeout: u64,
+
+    /// Overall time budget of a remote request including retries, in seconds
+    #[cfg(feature = "gcs")]
+    #[arg(long = "deadline", value_name = "SECS", default_value_t = 300)]
+    pub deadline: u64,
+
+    /// GCS endpoint URL, e.g. http://localhost:4443 for fake-gcs-server
+    /// (defaults to $STORAGE_EMULATOR_HOST when set)
+    #[cfg(feature = "gcs")]
+    #[arg(long = "gcs-endpoint", value_name = "URL")]
+    pub gcs_endpoint: Option<String>,
+
+    /// Access GCS without credentials (public buckets, emulators)
+    #[cfg(feature = "gcs")]
+    #[arg(long = "gcs-anonymous")]
+    pub gcs_anonymous: bool,
+}
+
+impl StorageArgs {
+    pub fn to_options(&self) -> StorageOptions {
+        StorageOptions {
+            block_cache: None,
+            #[cfg(feature = "gcs")]
+            retry: RetryPolicy {
+                max_attempts: self.retries.max(1),
+                request_timeout: Duration::from_secs(self.timeout),
+                deadline: Duration::from_secs(self.deadline),
+                ..Default::default()
+            },
+            #[cfg(feature = "gcs")]
+            gcs_endpoint: self.gcs_endpoint.clone(),
+            #[cfg(feature = "gcs")]
+            gcs_anonymous: self.gcs_anonymous,
+        }
+    }
+}
+
+/// How line timestamps are found, for the time range of each frame and for
+/// trimming `mg search --since/--until` output. A search of an archive built
+/// with custom settings needs the same ones to trim lines.
+#[derive(Args)]
+pub struct TimeArgs {
+    /// Timestamp format: auto, iso8601, rfc3339, clf, syslog, epoch-ms, or a
+    /// strftime format such as "%d.%m.%Y %H:%M:%S"
+    #[arg(long = "time-format", value_name = "FORMAT", default_value = "auto")]
+    pub time_format: TimeFormat,
+
+    /// Regex locating the timestamp in a line; its first capture group (or
+    /// the whole match) is parsed with --time-format
+    #[arg(long = "time-regex", value_name = "REGEX")]
+    pub time_regex: Option<String>,
+
+    /// Year of syslog timestamps, which have none. Without it, a build dates
+    /// them by when each input was last written, and they are otherwise
+    /// ignored rather than guessed.
+    #[arg(long = "year", value_name = "YEAR")]
+    pub year: Option<i32>,
+}
+
+impl TimeArgs {
+    pub fn to_detector(&self) -> io::Result<TimeDetector> {
+        TimeDetector::new(self.time_format.clone(), self.time_regex.as_deref(), self.year)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// How the filters of a new index are built. Searches read the settings from
+/// the index; appends keep the settings of the index they extend.
+#[derive(Args)]
+pub struct FilterArgs {
+    /// Filter type: fuse8 (about 0.4% false positives per n-gram) or fuse16
+    /// (about 0.0015%, twice the index size)
+    #[arg(long = "filter", value_name = "TYPE", default_value = "fuse8")]
+    pub filter: FilterType,
+
+    /// Bytes per n-gram key; shorter n-grams let shorter patterns use the
+    /// index but match more frames
+    #[arg(long = "ngram", value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u8).range(3..=8))]
+    pub ngram: u8,
+
+    /// Fold ASCII letters to lower case before indexing, so searches find
+    /// their pattern in any case
+    #[arg(long = "casefold")]
+    pub casefold: bool,
+}
+
+impl FilterArgs {
+    pub fn to_spec(&self) -> FilterSpec {
+        FilterSpec {
+            filter_type: self.filter,
+            ngram: self.ngram,
+            casefold: self.casefold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parses(args: &[&str]) -> bool {
+        Cli::try_parse_from(std::iter::once("mg").chain(args.iter().copied())).is_ok()
+    }
+
+    #[test]
+    fn storage_flags_need_remote_storage() {
+        assert!(parses(&["info", "app.zst"]));
+        assert_eq!(parses(&["info", "app.zst", "--retries", "3"]), cfg!(feature = "gcs"));
+        assert_eq!(parses(&["search", "app.zst", "error", "--gcs-anonymous"]), cfg!(feature = "gcs"));
+        // Repair only ever works on local files
+        assert!(!parses(&["repair", "app.zst", "--retries", "3"]));
+    }
 }
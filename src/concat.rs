@@ -0,0 +1,308 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::build::{partial_path, same_file, sync_parent_dir};
+use crate::index::{split_header, FilterSpec, FrameInfo, IndexKind, IndexReader, IndexWriter};
+
+/// Each input's frames start on a multiple of this, so copies can share
+/// extents with the input on file systems that support it
+const ALIGNMENT: u64 = 4096;
+
+/// First magic number of a zstd skippable frame, which decoders pass over
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+/// Magic number and length field of a skippable frame
+const SKIPPABLE_HEADER_LEN: u64 = 8;
+
+/// One input archive whose index has been checked
+struct ConcatInput {
+    zst_path: PathBuf,
+    idx_path: PathBuf,
+    /// End of the last indexed frame; the data is copied up to here
+    data_end: u64,
+}
+
+/// Main entry point for the "concat" subcommand: join local archives into
+/// one, in order, without recompressing. The data of every input is copied
+/// up to its last indexed frame (with `copy_file_range` on Linux, which can
+/// share extents instead of copying) and its records are added to the new
+/// `.mg` with their offsets moved. Sources, line positions, time ranges,
+/// checksums and filters are kept as they are, so all inputs must use the
+/// same filter settings. Frames with no recorded source get their input's
+/// path, their line positions being within its decompressed data.
+pub fn run_concat(inputs: &[String], zst_path: &str, maybe_idx_path: Option<&str>) -> io::Result<()> {
+    let zst_path = Path::new(zst_path);
+    let idx_path = match maybe_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => zst_path.with_extension("mg"),
+    };
+
+    let mut filter_spec: Option<(FilterSpec, &str)> = None;
+    let mut archives = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let input_zst = Path::new(input);
+        let input_idx = input_zst.with_extension("mg");
+        if [zst_path, idx_path.as_path()]
+            .iter()
+            .any(|out| same_file(out, input_zst) || same_file(out, &input_idx))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("output would overwrite input {}; pass another --zst/--idx", input),
+            ));
+        }
+        let (spec, archive) = read_input(input_zst, &input_idx)?;
+        match filter_spec {
+            None => filter_spec = Some((spec, input)),
+            Some((first_spec, first_input)) if first_spec != spec => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} ({}) and {} ({}) build their filters differently; \
+                         rewrite one with `mg reindex` to match the other",
+                        first_input, first_spec, input, spec
+                    ),
+                ));
+            }
+            Some(_) => {}
+        }
+        archives.push(archive);
+    }
+    let Some((filter_spec, _)) = filter_spec else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no archives to concatenate"));
+    };
+
+    let partial_zst_path = partial_path(zst_path);
+    let partial_idx_path = partial_path(&idx_path);
+    let result = (|| {
+        let mut output_file = File::create(&partial_zst_path)?;
+        let mut index_writer = IndexWriter::create(
+            BufWriter::new(File::create(&partial_idx_path)?),
+            IndexKind::ZstdFrames,
+            filter_spec,
+        )?;
+        let mut frames = 0;
+        for archive in &archives {
+            let base = pad_to_alignment(&mut output_file)?;
+            // io::copy between files uses copy_file_range where it can
+            let copied = io::copy(&mut File::open(&archive.zst_path)?.take(archive.data_end), &mut output_file)?;
+            if copied < archive.data_end {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{:?} got shorter while it was copied", archive.zst_path),
+                ));
+            }
+            // Indexes are read again one at a time rather than all kept in memory
+            let index_data = fs::read(&archive.idx_path)?;
+            let (header, records) = split_header(&index_data)?;
+            let mut archive_frames = 0;
+            for (frame_info, filter) in IndexReader::<FrameInfo>::new(&header, records) {
+                let source = if frame_info.source.is_empty() {
+                    archive.zst_path.display().to_string()
+                } else {
+                    frame_info.source.clone()
+                };
+                let frame_info = FrameInfo {
+                    frame_offset: base + frame_info.frame_offset,
+                    source,
+                    ..frame_info
+                };
+                index_writer.write_record(&frame_info, &filter)?;
+                archive_frames += 1;
+            }
+            frames += archive_frames;
+            println!("{:?}: {} frames at offset {}", archive.zst_path, archive_frames, base);
+        }
+        output_file.sync_all()?;
+        index_writer
+            .into_inner()
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&partial_zst_path, zst_path)?;
+        fs::rename(&partial_idx_path, &idx_path)?;
+        sync_parent_dir(&idx_path)?;
+        Ok::<_, io::Error>(frames)
+    })();
+    let frames = match result {
+        Ok(frames) => frames,
+        Err(e) => {
+            let _ = fs::remove_file(&partial_zst_path);
+            let _ = fs::remove_file(&partial_idx_path);
+            return Err(e);
+        }
+    };
+
+    println!("inputs: {}, frames: {}", archives.len(), frames);
+    println!("Concat complete. ZST: {:?} | MG: {:?}", zst_path, idx_path);
+    Ok(())
+}
+
+/// Read the index of one input and check it describes the data
+fn read_input(zst_path: &Path, idx_path: &Path) -> io::Result<(FilterSpec, ConcatInput)> {
+    let index_data = fs::read(idx_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't read the index {:?}: {}", idx_path, e)))?;
+    let (header, records) = split_header(&index_data)?;
+    if header.kind != IndexKind::ZstdFrames {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} does not index a zstd archive; only those can be concatenated", idx_path),
+        ));
+    }
+    if header.is_legacy_frames() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} has index format version {}; rewrite it with `mg reindex` first",
+                idx_path, header.version
+            ),
+        ));
+    }
+    let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+    let mut frames = 0;
+    let mut data_end = 0;
+    for (frame_info, _) in reader.by_ref() {
+        frames += 1;
+        data_end = data_end.max(frame_info.frame_offset + frame_info.frame_size);
+    }
+    if !reader.remaining().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is damaged after frame record {}; fix it with `mg salvage` or `mg reindex`",
+                idx_path, frames
+            ),
+        ));
+    }
+    if fs::metadata(zst_path)?.len() < data_end {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is shorter than its index {:?} says", zst_path, idx_path),
+        ));
+    }
+    Ok((
+        header.filter,
+        ConcatInput {
+            zst_path: zst_path.to_path_buf(),
+            idx_path: idx_path.to_path_buf(),
+            data_end,
+        },
+    ))
+}
+
+/// Move the end of `output_file` to the next multiple of `ALIGNMENT` with a
+/// skippable frame, returning the new end
+fn pad_to_alignment(output_file: &mut File) -> io::Result<u64> {
+    let end = output_file.stream_position()?;
+    let mut pad = (ALIGNMENT - end % ALIGNMENT) % ALIGNMENT;
+    if pad == 0 {
+        return Ok(end);
+    }
+    // Too short for a frame header; pad to the boundary after instead
+    if pad < SKIPPABLE_HEADER_LEN {
+        pad += ALIGNMENT;
+    }
+    let mut frame = Vec::with_capacity(pad as usize);
+    frame.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    frame.extend_from_slice(&((pad - SKIPPABLE_HEADER_LEN) as u32).to_le_bytes());
+    frame.resize(pad as usize, 0);
+    output_file.write_all(&frame)?;
+    Ok(end + pad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::testutil::{log_lines, temp_dir};
+
+    fn build(log_path: &Path, log: &[u8], options: &BuildOptions) -> String {
+        fs::write(log_path, log).unwrap();
+        let zst_path = log_path.with_extension("zst");
+        run_build(&[log_path.display().to_string()], zst_path.to_str(), None, options).unwrap();
+        zst_path.display().to_string()
+    }
+
+    fn records(idx_path: &Path) -> Vec<FrameInfo> {
+        let index_data = fs::read(idx_path).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        IndexReader::<FrameInfo>::new(&header, records).map(|(frame_info, _)| frame_info).collect()
+    }
+
+    /// Whether `data` is exactly one skippable frame
+    fn is_skippable_frame(data: &[u8]) -> bool {
+        data.len() >= SKIPPABLE_HEADER_LEN as usize
+            && u32::from_le_bytes(data[..4].try_into().unwrap()) == SKIPPABLE_MAGIC
+            && u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize == data.len() - 8
+    }
+
+    #[test]
+    fn frames_are_moved_and_keep_their_metadata() {
+        let dir = temp_dir();
+        let logs = [log_lines(0, 40_000), log_lines(40_000, 100), log_lines(40_100, 20_000)];
+        let inputs: Vec<String> = logs
+            .iter()
+            .enumerate()
+            .map(|(i, log)| build(&dir.path().join(format!("day{}.log", i)), log, &BuildOptions::default()))
+            .collect();
+        let month = dir.path().join("month.zst");
+        run_concat(&inputs, month.to_str().unwrap(), None).unwrap();
+
+        let data = fs::read(&month).unwrap();
+        let frames = records(&month.with_extension("mg"));
+        let mut frames_iter = frames.iter();
+        let mut end = 0;
+        for input in &inputs {
+            let input_data = fs::read(input).unwrap();
+            let input_frames = records(&Path::new(input).with_extension("mg"));
+            let base = frames_iter.as_slice()[0].frame_offset;
+            assert_eq!(base % ALIGNMENT, 0);
+            assert!(end == base || is_skippable_frame(&data[end as usize..base as usize]));
+            assert!(data[base as usize..base as usize + input_data.len()] == input_data[..]);
+            for input_frame in &input_frames {
+                let frame = frames_iter.next().unwrap();
+                let moved = FrameInfo { frame_offset: base + input_frame.frame_offset, ..input_frame.clone() };
+                assert_eq!(format!("{:?}", frame), format!("{:?}", moved));
+                assert!(frame.time_range.is_some() && !frame.source.is_empty());
+            }
+            end = base + input_data.len() as u64;
+        }
+        assert!(frames_iter.next().is_none());
+        assert_eq!(end, data.len() as u64);
+
+        // Decoders pass over the padding
+        assert!(zstd::decode_all(&data[..]).unwrap() == logs.concat());
+    }
+
+    #[test]
+    fn short_gaps_are_padded_to_the_next_boundary() {
+        let dir = temp_dir();
+        let path = dir.path().join("out.zst");
+        for len in [0, 1, ALIGNMENT - 4, ALIGNMENT - 8, ALIGNMENT + 100] {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&vec![0; len as usize]).unwrap();
+            let end = pad_to_alignment(&mut file).unwrap();
+            drop(file);
+            let data = fs::read(&path).unwrap();
+            assert_eq!(end, data.len() as u64);
+            assert_eq!(end % ALIGNMENT, 0);
+            assert!(end == len || is_skippable_frame(&data[len as usize..]));
+        }
+    }
+
+    #[test]
+    fn different_filter_settings_are_refused() {
+        let dir = temp_dir();
+        let first = build(&dir.path().join("a.log"), &log_lines(0, 100), &BuildOptions::default());
+        let filter = FilterSpec { casefold: true, ..FilterSpec::default() };
+        let casefolded = BuildOptions { filter, ..Default::default() };
+        let second = build(&dir.path().join("b.log"), &log_lines(100, 100), &casefolded);
+        let month = dir.path().join("month.zst");
+
+        let err = run_concat(&[first, second], month.to_str().unwrap(), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("mg reindex"), "{}", err);
+        assert!(!month.exists() && !month.with_extension("mg").exists());
+    }
+}
@@ -3,7 +3,6 @@
 pub struct FastSet {
     table: Vec<u64>,
     size: usize,
-    collision_count: usize,
 }
 
 impl FastSet {
@@ -17,32 +16,12 @@ impl FastSet {
         z ^ (z >> 31)
     }
 
-    #[inline(always)]
-    fn xxhash64(input: u64) -> u64 {
-        let mut hash = 0x9747b28c; // Prime number used as the seed.
-        let mut temp = input;
-    
-        // Mix the bits of the input using a series of bitwise operations.
-        temp ^= temp >> 31;
-        temp = temp.wrapping_mul(0xbea225f9eb34556d);
-        temp ^= temp >> 27;
-        temp = temp.wrapping_mul(0x94d049bb133111eb);
-        temp ^= temp >> 31;
-    
-        // Mix the bits of the hash and the input.
-        hash ^= temp;
-        hash = hash.rotate_left(27);
-        hash = hash.wrapping_mul(0x9cb4bc65158fe28f);
-
-        return hash;
-    }
     // normal new
     pub fn new(capacity: usize) -> Self {
         let table = vec![0_u64; capacity];
         Self {
             table,
             size: 0,
-            collision_count: 0,
         }
     }
     
@@ -71,8 +50,6 @@ impl FastSet {
                 return true;
             } else if slot == value {
                 return false;
-            } else {
-                self.collision_count += 1;
             }
             
             idx = (idx + 1) & mask;
@@ -89,21 +66,12 @@ impl FastSet {
 
     
 
-    pub fn len(&self) -> usize {
-        self.size
-    }
-
-    pub fn collision_count(&self) -> usize {
-        self.collision_count
-    }
-
     #[inline]
     pub fn clear(&mut self) {
         // Fill table with 0
         // .fill() is usually efficient, but you can also use write_bytes if you prefer
         self.table.fill(0);
         self.size = 0;
-        self.collision_count = 0;
     }
 
     pub fn extract(&self) -> Vec<u64> {
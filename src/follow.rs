@@ -0,0 +1,482 @@
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::build::{
+    compress_and_write_chunk, count_lines, existing_archive_end, find_line_boundary, output_paths, partial_path,
+    sync_parent_dir, BuildMode, BuildOptions,
+};
+use crate::fastu64set::FastSet;
+use crate::index::{split_header, FilterSpec, FrameInfo, IndexKind, IndexReader, IndexWriter};
+use crate::timestamp::TimeDetector;
+use crate::utils::{CHUNK_SIZE, HASH_CAPACITY};
+
+/// How often to look for new data once the file has been read to its end
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many leading bytes of a followed file tell it apart from another
+/// file that took its place, such as the next one after a rotation
+const HEAD_LEN: u64 = 4096;
+
+/// Main entry point for `mg build --follow`: index `input_path` like
+/// `tail -F` follows it, appending a frame to the archive whenever a chunk
+/// fills up or no new data arrived for `idle_timeout`.
+///
+/// The archive (created if missing) is appended to, so every sealed frame is
+/// searchable right away. Frames only ever end at a newline; a partial last
+/// line waits for the rest of it, unless the file is rotated first, as no
+/// frame spans two files.
+/// Runs until interrupted. The next run over the same file picks up where
+/// the last frame the archive has of it ends, so lines not sealed yet are
+/// read again and nothing is archived twice. A file shorter than that, or
+/// starting differently from the data archived, was rotated, truncated or
+/// replaced meanwhile and is read from its start.
+pub fn run_follow(
+    input_path: &str,
+    maybe_zst_path: Option<&str>,
+    maybe_idx_path: Option<&str>,
+    idle_timeout: Duration,
+    options: &BuildOptions,
+) -> io::Result<()> {
+    if input_path == "-" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--follow needs a file path; use `mg build -` to index a stream",
+        ));
+    }
+    let inputs = [input_path.to_string()];
+    let (zst_path, idx_path) = output_paths(&inputs, maybe_zst_path, maybe_idx_path, BuildMode::Append)?;
+    println!("Following {} into ZST: {:?} | MG: {:?}", input_path, zst_path, idx_path);
+    let mut archive = FollowArchive::open(input_path, &zst_path, &idx_path, options)?;
+
+    let mut followed = FollowedFile::open(Path::new(input_path))?;
+    let mut pending = Pending::default();
+    if let Some(resume) = resume_point(&zst_path, &idx_path, input_path)? {
+        if followed.skip_to(&resume)? {
+            println!("Resuming {} at byte {} (line {})", input_path, resume.offset, resume.line);
+            pending.offset = resume.offset;
+            pending.line = resume.line;
+        } else {
+            eprintln!(
+                "{}: not the file the {} bytes already archived came from; rotated, truncated or replaced, \
+                 reading from the start",
+                input_path, resume.offset
+            );
+        }
+    }
+    let mut last_data = Instant::now();
+    loop {
+        let read = followed.read_available(&mut pending.data)?;
+        if read > 0 {
+            last_data = Instant::now();
+        }
+        archive.seal_full_chunks(&mut pending)?;
+
+        // Seal whatever complete lines sat idle long enough
+        if last_data.elapsed() >= idle_timeout
+            && let Some(last_newline) = pending.data.iter().rposition(|&c| c == b'\n')
+        {
+            archive.seal(&mut pending, last_newline + 1)?;
+        }
+
+        if read == 0 {
+            if followed.check_rotation(&mut pending.data)? {
+                // The old file is done: seal all of it, a partial last line included
+                archive.seal_full_chunks(&mut pending)?;
+                let rest = pending.data.len();
+                if rest > 0 {
+                    archive.seal(&mut pending, rest)?;
+                }
+                pending.offset = 0;
+                pending.line = 0;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Where a restarted run picks up a file it followed before
+struct ResumePoint {
+    /// Offset and line where the archived data of the file ends
+    offset: u64,
+    line: u64,
+    /// The file's first bytes as archived, up to `HEAD_LEN`; empty if the
+    /// frame holding them is gone
+    head: Vec<u8>,
+}
+
+/// Where the data of `source` in the archive ends, from the last frame
+/// recorded for it, and how the file that data came from starts, from the
+/// last frame recorded at its start
+fn resume_point(zst_path: &Path, idx_path: &Path, source: &str) -> io::Result<Option<ResumePoint>> {
+    let index_data = fs::read(idx_path)?;
+    let (header, records) = split_header(&index_data)?;
+    let mut last = None;
+    let mut first = None;
+    for (frame_info, _) in IndexReader::<FrameInfo>::new(&header, records) {
+        if frame_info.source != source {
+            continue;
+        }
+        if frame_info.source_offset == 0 {
+            first = Some((frame_info.frame_offset, frame_info.frame_size));
+        }
+        last = Some(frame_info);
+    }
+    let Some(last) = last else {
+        return Ok(None);
+    };
+    let mut head = Vec::new();
+    if let Some((frame_offset, frame_size)) = first {
+        let mut frame = vec![0; frame_size as usize];
+        let mut zst_file = File::open(zst_path)?;
+        zst_file.seek(SeekFrom::Start(frame_offset))?;
+        zst_file.read_exact(&mut frame)?;
+        zstd::Decoder::with_buffer(&frame[..])?.take(HEAD_LEN).read_to_end(&mut head)?;
+    }
+    Ok(Some(ResumePoint {
+        offset: last.source_offset + last.uncompressed_size,
+        line: last.source_line + last.line_count,
+        head,
+    }))
+}
+
+/// Data read from the followed file but not sealed yet
+#[derive(Default)]
+struct Pending {
+    data: Vec<u8>,
+    /// Offset of `data` within the file it was read from
+    offset: u64,
+    /// Lines of that file before `data`
+    line: u64,
+}
+
+/// The archive sealed frames are appended to, kept open for the whole run.
+/// Each frame is synced before its record is written, so the index never
+/// points past the data.
+struct FollowArchive {
+    source: String,
+    output_file: File,
+    index_writer: IndexWriter<File>,
+    filter: FilterSpec,
+    time_detector: TimeDetector,
+    set: FastSet,
+}
+
+impl FollowArchive {
+    /// Open the archive for appending, creating it if neither file exists.
+    /// Data past the last indexed frame and a record cut short, left by an
+    /// interrupted run, are dropped.
+    fn open(source: &str, zst_path: &Path, idx_path: &Path, options: &BuildOptions) -> io::Result<Self> {
+        if !zst_path.exists() && !idx_path.exists() {
+            create_empty_archive(zst_path, idx_path, options.filter)?;
+        }
+        let (data_end, index_len, filter) = existing_archive_end(idx_path)?;
+        let mut output_file = OpenOptions::new().write(true).open(zst_path)?;
+        if output_file.metadata()?.len() < data_end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is shorter than its index {:?} says", zst_path, idx_path),
+            ));
+        }
+        output_file.set_len(data_end)?;
+        output_file.seek(SeekFrom::Start(data_end))?;
+        let mut index_file = OpenOptions::new().write(true).open(idx_path)?;
+        index_file.set_len(index_len)?;
+        index_file.seek(SeekFrom::Start(index_len))?;
+        Ok(Self {
+            source: source.to_string(),
+            output_file,
+            index_writer: IndexWriter::append(index_file),
+            filter,
+            time_detector: options.time_detector.clone(),
+            set: FastSet::new(HASH_CAPACITY),
+        })
+    }
+
+    /// Seal full chunks, cut the same way `mg build` cuts a file
+    fn seal_full_chunks(&mut self, pending: &mut Pending) -> io::Result<()> {
+        while pending.data.len() > CHUNK_SIZE {
+            let Some(rel_pos) = find_line_boundary(&pending.data[CHUNK_SIZE..]) else {
+                break;
+            };
+            self.seal(pending, CHUNK_SIZE + rel_pos + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Write the first `len` pending bytes as a new frame at the end of the archive
+    fn seal(&mut self, pending: &mut Pending, len: usize) -> io::Result<()> {
+        let rest = pending.data.split_off(len);
+        let data = std::mem::replace(&mut pending.data, rest);
+        let line_count = count_lines(&data);
+        self.set.clear();
+        let filter = self.filter.build_filter(&data, &mut self.set);
+
+        // The file is being written as it is read
+        self.time_detector.set_last_write(Some(SystemTime::now()));
+        let time_range = self.time_detector.range(&data);
+
+        let frame_offset = self.output_file.stream_position()?;
+        let (frame_size, checksum) = compress_and_write_chunk(&data, &mut self.output_file)?;
+        self.output_file.sync_data()?;
+        let frame_info = FrameInfo {
+            frame_offset,
+            frame_size,
+            checksum: Some(checksum),
+            source: self.source.clone(),
+            source_offset: pending.offset,
+            source_line: pending.line,
+            uncompressed_size: len as u64,
+            line_count,
+            time_range,
+        };
+        self.index_writer.write_record(&frame_info, &filter)?;
+        self.index_writer.get_ref().sync_data()?;
+
+        pending.offset += len as u64;
+        pending.line += line_count;
+        println!("Sealed frame: {} bytes", len);
+        Ok(())
+    }
+}
+
+/// Write an archive with no frames yet, data first, index last
+fn create_empty_archive(zst_path: &Path, idx_path: &Path, filter: FilterSpec) -> io::Result<()> {
+    File::create(partial_path(zst_path))?.sync_all()?;
+    let index_file = File::create(partial_path(idx_path))?;
+    IndexWriter::create(index_file, IndexKind::ZstdFrames, filter)?
+        .into_inner()
+        .sync_all()?;
+    fs::rename(partial_path(zst_path), zst_path)?;
+    fs::rename(partial_path(idx_path), idx_path)?;
+    sync_parent_dir(idx_path)
+}
+
+/// The file currently open under the followed path
+struct FollowedFile {
+    path: PathBuf,
+    file: File,
+    id: Option<FileId>,
+    pos: u64,
+}
+
+impl FollowedFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let id = file_id(&file.metadata()?);
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            id,
+            pos: 0,
+        })
+    }
+
+    /// Continue reading where `resume` says, if this is the file it was
+    /// archived from: at least that long and starting with its head
+    fn skip_to(&mut self, resume: &ResumePoint) -> io::Result<bool> {
+        if self.file.metadata()?.len() < resume.offset {
+            return Ok(false);
+        }
+        let mut head = vec![0; resume.head.len()];
+        self.file.read_exact(&mut head)?;
+        if head != resume.head {
+            self.file.rewind()?;
+            return Ok(false);
+        }
+        self.file.seek(SeekFrom::Start(resume.offset))?;
+        self.pos = resume.offset;
+        Ok(true)
+    }
+
+    /// Append up to a chunk of new data to `buf`
+    fn read_available(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let n = (&mut self.file).take(CHUNK_SIZE as u64).read_to_end(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Called once the open file is read to its end. Handles logrotate's
+    /// two styles: rename (a new file appears under the path) and
+    /// copytruncate (the file shrinks below what was already read).
+    /// Returns whether reading starts over, after the rest of the old file
+    /// was added to `buf`.
+    fn check_rotation(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let meta = match fs::metadata(&self.path) {
+            Ok(meta) => meta,
+            // Renamed away and not recreated yet; the writer may still be
+            // using the old file, so keep reading that
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        if file_id(&meta) != self.id {
+            // Pick up the old file's last lines before letting go of it
+            while self.read_available(buf)? > 0 {}
+            eprintln!("{}: file replaced, following the new file", self.path.display());
+            self.file = File::open(&self.path)?;
+            self.id = file_id(&self.file.metadata()?);
+            self.pos = 0;
+        } else if meta.len() < self.pos {
+            eprintln!("{}: file truncated, reading from the start", self.path.display());
+            self.file.seek(SeekFrom::Start(0))?;
+            self.pos = 0;
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Identity of a file independent of its name
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+/// Without inode numbers only copytruncate-style rotation is detected
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata) -> Option<FileId> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::testutil::{log_lines, temp_dir};
+
+    /// Start following `log_path` as `run_follow` does, resuming where the
+    /// archive leaves off
+    fn start(log_path: &Path, zst_path: &Path, idx_path: &Path) -> (FollowArchive, FollowedFile, Pending) {
+        let source = log_path.display().to_string();
+        let archive = FollowArchive::open(&source, zst_path, idx_path, &BuildOptions::default()).unwrap();
+        let mut followed = FollowedFile::open(log_path).unwrap();
+        let mut pending = Pending::default();
+        if let Some(resume) = resume_point(zst_path, idx_path, &source).unwrap()
+            && followed.skip_to(&resume).unwrap()
+        {
+            pending.offset = resume.offset;
+            pending.line = resume.line;
+        }
+        (archive, followed, pending)
+    }
+
+    /// Read the file to its end and seal everything up to its last newline,
+    /// as an idle timeout would
+    fn catch_up(archive: &mut FollowArchive, followed: &mut FollowedFile, pending: &mut Pending) {
+        while followed.read_available(&mut pending.data).unwrap() > 0 {
+            archive.seal_full_chunks(pending).unwrap();
+        }
+        if let Some(last_newline) = pending.data.iter().rposition(|&c| c == b'\n') {
+            archive.seal(pending, last_newline + 1).unwrap();
+        }
+    }
+
+    /// Offset and line a restart over `log_path` would resume at
+    fn resumed_at(log_path: &Path, zst_path: &Path, idx_path: &Path) -> Option<(u64, u64)> {
+        resume_point(zst_path, idx_path, &log_path.display().to_string())
+            .unwrap()
+            .map(|resume| (resume.offset, resume.line))
+    }
+
+    fn append_to(path: &Path, data: &[u8]) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn restart_resumes_without_duplicates() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("app.log");
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = dir.path().join("app.mg");
+
+        // More than a chunk, ending in half a line
+        let mut expected = log_lines(0, 30_000);
+        expected.extend_from_slice(b"2026-10-01T09:00:00Z host0 half a");
+        fs::write(&log_path, &expected).unwrap();
+        let (mut archive, mut followed, mut pending) = start(&log_path, &zst_path, &idx_path);
+        catch_up(&mut archive, &mut followed, &mut pending);
+        assert_eq!(resumed_at(&log_path, &zst_path, &idx_path), Some((pending.offset, 30_000)));
+
+        // Lines read but not sealed when the run stops are read again
+        let more = log_lines(30_000, 100);
+        append_to(&log_path, b" line\n");
+        append_to(&log_path, &more);
+        expected.extend_from_slice(b" line\n");
+        expected.extend_from_slice(&more);
+        followed.read_available(&mut pending.data).unwrap();
+        drop(archive);
+
+        let rest = log_lines(30_100, 5_000);
+        append_to(&log_path, &rest);
+        expected.extend_from_slice(&rest);
+        let (mut archive, mut followed, mut pending) = start(&log_path, &zst_path, &idx_path);
+        catch_up(&mut archive, &mut followed, &mut pending);
+        drop(archive);
+
+        assert!(zstd::decode_all(File::open(&zst_path).unwrap()).unwrap() == expected);
+        assert_eq!(resumed_at(&log_path, &zst_path, &idx_path), Some((expected.len() as u64, 35_101)));
+    }
+
+    #[test]
+    fn restart_on_shorter_file_reads_from_start() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("app.log");
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = dir.path().join("app.mg");
+
+        let old = log_lines(0, 2_000);
+        fs::write(&log_path, &old).unwrap();
+        let (mut archive, mut followed, mut pending) = start(&log_path, &zst_path, &idx_path);
+        catch_up(&mut archive, &mut followed, &mut pending);
+        drop(archive);
+
+        // Truncated while not followed
+        let new = log_lines(2_000, 100);
+        fs::write(&log_path, &new).unwrap();
+        let (mut archive, mut followed, mut pending) = start(&log_path, &zst_path, &idx_path);
+        assert_eq!(pending.offset, 0);
+        catch_up(&mut archive, &mut followed, &mut pending);
+        drop(archive);
+
+        let mut expected = old;
+        expected.extend_from_slice(&new);
+        assert!(zstd::decode_all(File::open(&zst_path).unwrap()).unwrap() == expected);
+        assert_eq!(resumed_at(&log_path, &zst_path, &idx_path), Some((new.len() as u64, 100)));
+    }
+
+    #[test]
+    fn restart_on_replaced_file_reads_from_start() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("app.log");
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = dir.path().join("app.mg");
+
+        let old = log_lines(0, 2_000);
+        fs::write(&log_path, &old).unwrap();
+        let (mut archive, mut followed, mut pending) = start(&log_path, &zst_path, &idx_path);
+        catch_up(&mut archive, &mut followed, &mut pending);
+        drop(archive);
+
+        // Rotated while not followed, the new file already longer than the old
+        let new = log_lines(2_000, 3_000);
+        fs::write(dir.path().join("app.log.new"), &new).unwrap();
+        fs::rename(dir.path().join("app.log.new"), &log_path).unwrap();
+        let (mut archive, mut followed, mut pending) = start(&log_path, &zst_path, &idx_path);
+        assert_eq!(pending.offset, 0);
+        catch_up(&mut archive, &mut followed, &mut pending);
+        drop(archive);
+
+        let mut expected = old;
+        expected.extend_from_slice(&new);
+        assert!(zstd::decode_all(File::open(&zst_path).unwrap()).unwrap() == expected);
+        assert_eq!(resumed_at(&log_path, &zst_path, &idx_path), Some((new.len() as u64, 3_000)));
+    }
+}
@@ -0,0 +1,519 @@
+use std::ffi::c_int;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+
+use bincode::{config::standard, decode_from_slice, Decode, Encode};
+use libz_sys as zlib;
+
+use crate::fastu64set::FastSet;
+use crate::index::{FilterSpec, IndexKind, IndexRecord, IndexWriter};
+use crate::utils::{CHUNK_SIZE, HASH_CAPACITY};
+
+/// History a deflate stream may refer back to, and so the dictionary an
+/// access point has to carry
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// windowBits for a gzip (or zlib) stream with automatic header detection
+const GZIP_WINDOW_BITS: c_int = 15 + 32;
+
+/// windowBits for raw deflate data, as found when resuming at an access point
+const RAW_WINDOW_BITS: c_int = -15;
+
+/// CRC32 + ISIZE at the end of every gzip member
+const GZIP_TRAILER_LEN: usize = 8;
+
+const IO_BUFFER_SIZE: usize = 256 * 1024;
+
+/// A place in a `.gz` where inflation can start without decoding anything
+/// before it (zran style), together with the line-aligned chunk of output
+/// searched from it. In the `.mg` each AccessPoint is followed by the
+/// filter of the chunk's n-grams.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct AccessPoint {
+    /// First whole compressed byte after the point
+    pub comp_offset: u64,
+    /// Number of bits of the byte before `comp_offset` that belong to the
+    /// deflate block starting at the point (0 if the point is byte aligned)
+    pub bits: u8,
+    /// Compressed offset up to which input is needed to inflate the chunk
+    pub comp_end: u64,
+    /// Uncompressed offset of the point
+    pub point_offset: u64,
+    /// Uncompressed range [start, end) of the chunk. `start` is the first line
+    /// start at or after the point, `end` the start of the next chunk.
+    pub start: u64,
+    pub end: u64,
+    /// Output preceding the point, the inflate dictionary (at most 32KB)
+    pub window: Vec<u8>,
+}
+
+impl AccessPoint {
+    /// (offset, size) of the compressed bytes to fetch for this chunk
+    pub fn compressed_range(&self) -> (u64, u64) {
+        let offset = self.comp_offset - u64::from(self.bits > 0);
+        (offset, self.comp_end - offset)
+    }
+}
+
+/// AccessPoint has kept its layout since it was introduced
+impl IndexRecord for AccessPoint {
+    fn decode(data: &[u8], _version: u32) -> Option<(Self, usize)> {
+        decode_from_slice(data, standard()).ok()
+    }
+}
+
+/// Index a `.gz` read from `input`, writing the `.mg` to `out`.
+/// Returns `out` and the number of access points written.
+///
+/// The whole file is inflated once. At the first deflate block boundary
+/// after every CHUNK_SIZE bytes of output an access point is recorded; its
+/// chunk starts at the next line so no line is split between chunks.
+/// Filters are built as `filter_spec` says on a second thread while
+/// inflating continues.
+pub fn index_gzip<R: Read, W: Write + Send>(input: R, out: W, filter_spec: FilterSpec) -> io::Result<(W, usize)> {
+    let (chunk_sender, chunk_receiver) = mpsc::sync_channel::<(AccessPoint, Vec<u8>)>(1);
+
+    thread::scope(|scope| {
+        let writer_handle = scope.spawn(move || -> io::Result<(W, usize)> {
+            let mut index_writer = IndexWriter::create(out, IndexKind::GzipAccessPoints, filter_spec)?;
+            let mut set_capacity = HASH_CAPACITY;
+            let mut set = FastSet::new(set_capacity);
+            let mut count = 0;
+            for (point, chunk) in chunk_receiver {
+                // Lines can run far past CHUNK_SIZE; keep the set at most half full
+                if chunk.len() * 2 > set_capacity {
+                    set_capacity = (chunk.len() * 2).next_power_of_two();
+                    set = FastSet::new(set_capacity);
+                }
+                set.clear();
+                let filter = filter_spec.build_filter(&chunk, &mut set);
+                index_writer.write_record(&point, &filter)?;
+                count += 1;
+            }
+            Ok((index_writer.into_inner(), count))
+        });
+
+        let mut emit = |point: AccessPoint, chunk: Vec<u8>| {
+            chunk_sender
+                .send((point, chunk))
+                .map_err(|_| io::Error::other("index writer stopped"))
+        };
+        let inflated = inflate_chunks(input, &mut emit);
+        drop(chunk_sender);
+
+        // A writer failure also makes the inflate side fail; report the cause
+        let written = writer_handle.join().expect("index writer panicked");
+        inflated?;
+        written
+    })
+}
+
+/// Inflate all members of the gzip `input`, handing each finished chunk to `emit`
+fn inflate_chunks<R: Read>(
+    input: R,
+    emit: &mut impl FnMut(AccessPoint, Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut input = BufReader::with_capacity(IO_BUFFER_SIZE, input);
+    let mut inflater = Inflater::new(GZIP_WINDOW_BITS)?;
+    let mut out_buf = vec![0u8; IO_BUFFER_SIZE];
+    let mut chunker = Chunker::default();
+    let mut comp_pos = 0u64;
+
+    loop {
+        let in_buf = input.fill_buf()?;
+        let at_eof = in_buf.is_empty();
+        let (consumed, produced, ret) = inflater.inflate(in_buf, &mut out_buf, zlib::Z_BLOCK)?;
+        input.consume(consumed);
+        comp_pos += consumed as u64;
+        chunker.push(&out_buf[..produced], comp_pos, emit)?;
+
+        if ret == zlib::Z_STREAM_END {
+            // Another member may follow (`cat a.gz b.gz`); anything else,
+            // such as zero padding, ends the data
+            match input.fill_buf()?.first() {
+                Some(0x1f) => inflater.reset(GZIP_WINDOW_BITS)?,
+                _ => break,
+            }
+            continue;
+        }
+        if at_eof && consumed == 0 && produced == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "gzip data ends in the middle of a member",
+            ));
+        }
+
+        // Bit 128: stopped at a block boundary; bit 64: in the last block of
+        // the member, where the next thing is the trailer, not a block
+        let data_type = inflater.data_type();
+        if data_type & 128 != 0 && data_type & 64 == 0 && chunker.point_due() {
+            chunker.record_point(comp_pos, (data_type & 7) as u8, emit)?;
+        }
+    }
+
+    chunker.finish(comp_pos, emit)
+}
+
+/// Splits the inflated output into chunks that start at an access point and
+/// end where the next chunk starts
+#[derive(Default)]
+struct Chunker {
+    /// The chunk being collected and its output so far, starting at `start`
+    current: Option<(AccessPoint, Vec<u8>)>,
+    /// A point recorded in the middle of a line; its chunk starts after the next newline
+    pending: Option<AccessPoint>,
+    /// Uncompressed bytes seen so far
+    total_out: u64,
+    /// Whether the last byte seen was a newline
+    after_newline: bool,
+    /// The last WINDOW_SIZE bytes of output
+    window: Vec<u8>,
+}
+
+impl Chunker {
+    fn point_due(&self) -> bool {
+        if self.pending.is_some() {
+            return false;
+        }
+        match &self.current {
+            Some((point, _)) => self.total_out - point.start >= CHUNK_SIZE as u64,
+            None => true,
+        }
+    }
+
+    fn push(
+        &mut self,
+        mut data: &[u8],
+        comp_pos: u64,
+        emit: &mut impl FnMut(AccessPoint, Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if data.len() >= WINDOW_SIZE {
+            self.window.clear();
+            self.window.extend_from_slice(&data[data.len() - WINDOW_SIZE..]);
+        } else {
+            self.window.extend_from_slice(data);
+            let excess = self.window.len().saturating_sub(WINDOW_SIZE);
+            self.window.drain(..excess);
+        }
+
+        if self.pending.is_some() {
+            let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+                self.append(data);
+                return Ok(());
+            };
+            self.append(&data[..=newline]);
+            self.cut(comp_pos, emit)?;
+            data = &data[newline + 1..];
+        }
+        self.append(data);
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        if let Some((_, chunk)) = &mut self.current {
+            chunk.extend_from_slice(data);
+        }
+        self.total_out += data.len() as u64;
+        if let Some(&last) = data.last() {
+            self.after_newline = last == b'\n';
+        }
+    }
+
+    /// Record an access point at the current output position. `comp_offset`
+    /// is the compressed position the inflater stopped at.
+    fn record_point(
+        &mut self,
+        comp_offset: u64,
+        bits: u8,
+        emit: &mut impl FnMut(AccessPoint, Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.pending = Some(AccessPoint {
+            comp_offset,
+            bits,
+            comp_end: 0,
+            point_offset: self.total_out,
+            start: 0,
+            end: 0,
+            window: self.window.clone(),
+        });
+        if self.total_out == 0 || self.after_newline {
+            self.cut(comp_offset, emit)?;
+        }
+        Ok(())
+    }
+
+    /// End the current chunk here and start the pending point's chunk
+    fn cut(
+        &mut self,
+        comp_pos: u64,
+        emit: &mut impl FnMut(AccessPoint, Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut point = self.pending.take().expect("cut without a pending access point");
+        if let Some((mut prev, chunk)) = self.current.take() {
+            prev.end = self.total_out;
+            prev.comp_end = comp_pos;
+            emit(prev, chunk)?;
+        }
+        point.start = self.total_out;
+        self.current = Some((point, Vec::new()));
+        Ok(())
+    }
+
+    /// Emit the last chunk. A point still pending never saw a line start and
+    /// has no chunk of its own; its bytes already went to the current chunk.
+    fn finish(
+        mut self,
+        comp_pos: u64,
+        emit: &mut impl FnMut(AccessPoint, Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if let Some((mut point, chunk)) = self.current.take() {
+            point.end = self.total_out;
+            point.comp_end = comp_pos;
+            emit(point, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Inflate the chunk of `point` from `compressed`, the bytes at
+/// `point.compressed_range()`, appending its lines to `out`
+pub fn extract_chunk(point: &AccessPoint, compressed: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let truncated = || {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("compressed data ends before the chunk at offset {} does", point.start),
+        )
+    };
+
+    let mut inflater = Inflater::new(RAW_WINDOW_BITS)?;
+    let mut input = compressed;
+    if point.bits > 0 {
+        let (&byte, rest) = input.split_first().ok_or_else(truncated)?;
+        inflater.prime(point.bits, byte >> (8 - point.bits))?;
+        input = rest;
+    }
+    if !point.window.is_empty() {
+        inflater.set_dictionary(&point.window)?;
+    }
+
+    let mut skip = (point.start - point.point_offset) as usize;
+    let mut remaining = (point.end - point.start) as usize;
+    let mut raw = true;
+    let mut buf = vec![0u8; IO_BUFFER_SIZE];
+    out.reserve(remaining);
+    while remaining > 0 {
+        let (consumed, produced, ret) = inflater.inflate(input, &mut buf, zlib::Z_NO_FLUSH)?;
+        input = &input[consumed..];
+
+        let skipped = skip.min(produced);
+        skip -= skipped;
+        let taken = remaining.min(produced - skipped);
+        out.extend_from_slice(&buf[skipped..skipped + taken]);
+        remaining -= taken;
+
+        if ret == zlib::Z_STREAM_END {
+            // The chunk continues in the next gzip member. Raw inflate leaves
+            // the trailer of the member it started in unread.
+            if raw {
+                input = input.get(GZIP_TRAILER_LEN..).ok_or_else(truncated)?;
+                raw = false;
+            }
+            inflater.reset(GZIP_WINDOW_BITS)?;
+        } else if consumed == 0 && produced == 0 {
+            return Err(truncated());
+        }
+    }
+    Ok(())
+}
+
+/// An inflate stream of the system zlib. Boxed since zlib keeps a pointer
+/// back to the `z_stream`, which must therefore never move.
+struct Inflater {
+    strm: Box<zlib::z_stream>,
+}
+
+unsafe extern "C" fn zalloc(_opaque: zlib::voidpf, items: zlib::uInt, size: zlib::uInt) -> zlib::voidpf {
+    unsafe { libc::calloc(items as libc::size_t, size as libc::size_t) }
+}
+
+unsafe extern "C" fn zfree(_opaque: zlib::voidpf, address: zlib::voidpf) {
+    unsafe { libc::free(address) }
+}
+
+impl Inflater {
+    fn new(window_bits: c_int) -> io::Result<Self> {
+        let mut strm = Box::new(zlib::z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null_mut(),
+            state: ptr::null_mut(),
+            zalloc,
+            zfree,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        let ret = unsafe {
+            zlib::inflateInit2_(
+                &mut *strm,
+                window_bits,
+                zlib::zlibVersion(),
+                mem::size_of::<zlib::z_stream>() as c_int,
+            )
+        };
+        let inflater = Self { strm };
+        inflater.check("inflateInit2", ret)?;
+        Ok(inflater)
+    }
+
+    fn reset(&mut self, window_bits: c_int) -> io::Result<()> {
+        let ret = unsafe { zlib::inflateReset2(&mut *self.strm, window_bits) };
+        self.check("inflateReset2", ret)
+    }
+
+    /// Feed the low `bits` bits of `value` ahead of the next input byte
+    fn prime(&mut self, bits: u8, value: u8) -> io::Result<()> {
+        let ret = unsafe { zlib::inflatePrime(&mut *self.strm, c_int::from(bits), c_int::from(value)) };
+        self.check("inflatePrime", ret)
+    }
+
+    fn set_dictionary(&mut self, window: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            zlib::inflateSetDictionary(&mut *self.strm, window.as_ptr(), window.len() as zlib::uInt)
+        };
+        self.check("inflateSetDictionary", ret)
+    }
+
+    /// Inflate from `input` into `output`, returning the bytes consumed, the
+    /// bytes produced and zlib's return code (Z_OK, Z_STREAM_END or
+    /// Z_BUF_ERROR when no progress was possible)
+    fn inflate(&mut self, input: &[u8], output: &mut [u8], flush: c_int) -> io::Result<(usize, usize, c_int)> {
+        let avail_in = input.len().min(zlib::uInt::MAX as usize);
+        let avail_out = output.len().min(zlib::uInt::MAX as usize);
+        self.strm.next_in = input.as_ptr() as *mut u8;
+        self.strm.avail_in = avail_in as zlib::uInt;
+        self.strm.next_out = output.as_mut_ptr();
+        self.strm.avail_out = avail_out as zlib::uInt;
+
+        let ret = unsafe { zlib::inflate(&mut *self.strm, flush) };
+
+        let consumed = avail_in - self.strm.avail_in as usize;
+        let produced = avail_out - self.strm.avail_out as usize;
+        // Don't keep pointers into the caller's buffers
+        self.strm.next_in = ptr::null_mut();
+        self.strm.avail_in = 0;
+        self.strm.next_out = ptr::null_mut();
+        self.strm.avail_out = 0;
+
+        match ret {
+            zlib::Z_OK | zlib::Z_STREAM_END | zlib::Z_BUF_ERROR => Ok((consumed, produced, ret)),
+            _ => self.check("inflate", ret).map(|_| (consumed, produced, ret)),
+        }
+    }
+
+    fn data_type(&self) -> c_int {
+        self.strm.data_type
+    }
+
+    fn check(&self, context: &str, ret: c_int) -> io::Result<()> {
+        if ret == zlib::Z_OK {
+            return Ok(());
+        }
+        let detail = if self.strm.msg.is_null() {
+            format!("zlib error {}", ret)
+        } else {
+            unsafe { std::ffi::CStr::from_ptr(self.strm.msg) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        let kind = if ret == zlib::Z_DATA_ERROR || ret == zlib::Z_NEED_DICT {
+            io::ErrorKind::InvalidData
+        } else {
+            io::ErrorKind::Other
+        };
+        Err(io::Error::new(kind, format!("{}: {}", context, detail)))
+    }
+}
+
+impl Drop for Inflater {
+    fn drop(&mut self) {
+        unsafe {
+            zlib::inflateEnd(&mut *self.strm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::index::{split_header, IndexReader};
+    use crate::testutil::log_lines;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn chunks_extract_from_their_access_points() {
+        // Two members, the second starting inside the second chunk
+        let data = log_lines(0, 40_000);
+        let member_end = CHUNK_SIZE + CHUNK_SIZE / 2;
+        let mut gz = gzip(&data[..member_end]);
+        gz.extend(gzip(&data[member_end..]));
+
+        let filter_spec = FilterSpec::default();
+        let (index_data, count) = index_gzip(&gz[..], Vec::new(), filter_spec).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        assert_eq!(header.kind, IndexKind::GzipAccessPoints);
+        let points: Vec<_> = IndexReader::<AccessPoint>::new(&header, records).collect();
+        assert_eq!(points.len(), count);
+        assert!(count >= 2);
+
+        // Chunks cover the data in order, each starting at a line
+        assert_eq!(points[0].0.start, 0);
+        assert_eq!(points[count - 1].0.end, data.len() as u64);
+        for pair in points.windows(2) {
+            assert_eq!(pair[0].0.end, pair[1].0.start);
+            assert_eq!(data[pair[1].0.start as usize - 1], b'\n');
+        }
+
+        // Search for a line of the interior chunk: its filter matches, and it
+        // inflates from its access point alone
+        let (point, _) = &points[1];
+        assert!(point.point_offset > 0 && !point.window.is_empty());
+        let line_start = point.start as usize + 1000;
+        let line_start = line_start + data[line_start..].iter().position(|&c| c == b'\n').unwrap() + 1;
+        let line_end = line_start + data[line_start..].iter().position(|&c| c == b'\n').unwrap();
+        let pattern = &data[line_start..line_end];
+        let keys: Vec<u64> = filter_spec.keys(pattern).collect();
+        let candidates: Vec<&AccessPoint> = points
+            .iter()
+            .filter(|(_, filter)| keys.iter().all(|key| filter.contains(key)))
+            .map(|(point, _)| point)
+            .collect();
+        assert!(candidates.iter().any(|candidate| candidate.start == point.start));
+
+        for (point, _) in &points {
+            let (offset, size) = point.compressed_range();
+            let mut out = Vec::new();
+            extract_chunk(point, &gz[offset as usize..(offset + size) as usize], &mut out).unwrap();
+            assert!(out[..] == data[point.start as usize..point.end as usize]);
+        }
+    }
+}
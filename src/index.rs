@@ -0,0 +1,383 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use bincode::{
+    config::standard,
+    decode_from_slice,
+    encode_into_std_write,
+    Decode,
+    Encode,
+};
+use xorf::{BinaryFuse16, BinaryFuse8, Filter};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::fastu64set::FastSet;
+use crate::timestamp::TimeRange;
+
+/// Magic at the start of every `.mg` written since the header was introduced.
+/// Older files start directly with the first FrameInfo record, whose leading
+/// byte is never `M`, and are read as format version 0.
+const INDEX_MAGIC: &[u8; 4] = b"MGIX";
+
+/// Current `.mg` format version. Bump it whenever a record layout changes.
+/// Version 2 added a checksum, source, line positions, uncompressed size and
+/// time range to FrameInfo, and the filter settings to the header.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Last version whose FrameInfo records hold only the frame location and
+/// whose filters are built with the default settings
+const LEGACY_VERSION: u32 = 1;
+
+/// What the records of a `.mg` describe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum IndexKind {
+    /// FrameInfo records for a multi-frame `.zst`
+    ZstdFrames,
+    /// AccessPoint records for a plain (single or multi-member) `.gz`
+    GzipAccessPoints,
+}
+
+#[derive(Debug, Clone, Encode)]
+pub struct IndexHeader {
+    pub version: u32,
+    pub kind: IndexKind,
+    /// How the filters were built; searches derive their keys the same way
+    pub filter: FilterSpec,
+}
+
+/// Filter types a `.mg` can hold
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub enum FilterType {
+    /// 8-bit fingerprints, about 0.4% false positives per key
+    #[default]
+    Fuse8,
+    /// 16-bit fingerprints, about 0.0015% false positives per key, twice the size
+    Fuse16,
+}
+
+impl FilterType {
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterType::Fuse8 => "binary-fuse8",
+            FilterType::Fuse16 => "binary-fuse16",
+        }
+    }
+
+    pub fn fingerprint_bits(self) -> u32 {
+        match self {
+            FilterType::Fuse8 => 8,
+            FilterType::Fuse16 => 16,
+        }
+    }
+}
+
+impl FromStr for FilterType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fuse8" => Ok(FilterType::Fuse8),
+            "fuse16" => Ok(FilterType::Fuse16),
+            _ => Err(format!("unknown filter type {:?} (expected fuse8 or fuse16)", s)),
+        }
+    }
+}
+
+/// How the keys of a frame's filter are derived from its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct FilterSpec {
+    pub filter_type: FilterType,
+    /// Bytes per key, at most 8; patterns shorter than this can't use the index
+    pub ngram: u8,
+    /// Whether ASCII letters are lower-cased before taking keys, so a search
+    /// finds every case of its pattern
+    pub casefold: bool,
+}
+
+impl Default for FilterSpec {
+    fn default() -> Self {
+        Self {
+            filter_type: FilterType::Fuse8,
+            ngram: 8,
+            casefold: false,
+        }
+    }
+}
+
+impl fmt::Display for FilterSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}-byte n-grams", self.filter_type.name(), self.ngram)?;
+        if self.casefold {
+            write!(f, ", case-folded")?;
+        }
+        Ok(())
+    }
+}
+
+impl FilterSpec {
+    /// The key of every n-gram of `data`, repeats included
+    pub fn keys<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = u64> + 'a {
+        let casefold = self.casefold;
+        data.windows(self.ngram as usize).map(move |window| {
+            let mut key = [0u8; 8];
+            key[..window.len()].copy_from_slice(window);
+            if casefold {
+                key.make_ascii_lowercase();
+            }
+            u64::from_le_bytes(key)
+        })
+    }
+
+    /// Build the filter of `chunk`'s n-grams, using `set` to drop repeats
+    pub fn build_filter(&self, chunk: &[u8], set: &mut FastSet) -> FrameFilter {
+        for key in self.keys(chunk) {
+            set.insert(key);
+        }
+
+        let keys = set.extract();
+        self.filter_from_keys(&keys)
+    }
+
+    /// Build a filter of this type over distinct `keys`
+    pub fn filter_from_keys(&self, keys: &[u64]) -> FrameFilter {
+        match self.filter_type {
+            FilterType::Fuse8 => {
+                FrameFilter::Fuse8(BinaryFuse8::try_from(keys).expect("Failed to build BinaryFuse8 filter"))
+            }
+            FilterType::Fuse16 => {
+                FrameFilter::Fuse16(BinaryFuse16::try_from(keys).expect("Failed to build BinaryFuse16 filter"))
+            }
+        }
+    }
+}
+
+/// The filter stored after each record, of the type the header names
+#[derive(Debug, Clone)]
+pub enum FrameFilter {
+    Fuse8(BinaryFuse8),
+    Fuse16(BinaryFuse16),
+}
+
+impl FrameFilter {
+    pub fn contains(&self, key: &u64) -> bool {
+        match self {
+            FrameFilter::Fuse8(filter) => filter.contains(key),
+            FrameFilter::Fuse16(filter) => filter.contains(key),
+        }
+    }
+
+    /// Number of fingerprints
+    pub fn len(&self) -> usize {
+        match self {
+            FrameFilter::Fuse8(filter) => filter.len(),
+            FrameFilter::Fuse16(filter) => filter.len(),
+        }
+    }
+
+    fn encode_into<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let result = match self {
+            FrameFilter::Fuse8(filter) => encode_into_std_write(filter, out, standard()),
+            FrameFilter::Fuse16(filter) => encode_into_std_write(filter, out, standard()),
+        };
+        result.map(|_| ()).map_err(io::Error::other)
+    }
+
+    /// Decode a filter of `filter_type` from the start of `data`, returning it and its length
+    fn decode(data: &[u8], filter_type: FilterType) -> Option<(Self, usize)> {
+        match filter_type {
+            FilterType::Fuse8 => decode_from_slice(data, standard())
+                .ok()
+                .map(|(filter, n)| (FrameFilter::Fuse8(filter), n)),
+            FilterType::Fuse16 => decode_from_slice(data, standard())
+                .ok()
+                .map(|(filter, n)| (FrameFilter::Fuse16(filter), n)),
+        }
+    }
+}
+
+/// Split a `.mg` into its header and the records that follow it.
+/// Headerless files are legacy zstd frame indexes.
+pub fn split_header(data: &[u8]) -> io::Result<(IndexHeader, &[u8])> {
+    let Some(rest) = data.strip_prefix(INDEX_MAGIC) else {
+        let legacy = IndexHeader {
+            version: 0,
+            kind: IndexKind::ZstdFrames,
+            filter: FilterSpec::default(),
+        };
+        return Ok((legacy, data));
+    };
+    let bad_header = |e: bincode::error::DecodeError| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("bad index header: {}", e))
+    };
+    let ((version, kind), mut n): ((u32, IndexKind), usize) =
+        decode_from_slice(rest, standard()).map_err(bad_header)?;
+    let mut header = IndexHeader {
+        version,
+        kind,
+        filter: FilterSpec::default(),
+    };
+    if header.version > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "index format version {} is newer than this mg supports ({})",
+                header.version, FORMAT_VERSION
+            ),
+        ));
+    }
+    if header.version > LEGACY_VERSION {
+        let (filter, m) = decode_from_slice(&rest[n..], standard()).map_err(bad_header)?;
+        header.filter = filter;
+        n += m;
+    }
+    Ok((header, &rest[n..]))
+}
+
+impl IndexHeader {
+    /// Whether the records are FrameInfo in the legacy layout, which lacks
+    /// sources, time ranges and line numbers
+    pub fn is_legacy_frames(&self) -> bool {
+        self.kind == IndexKind::ZstdFrames && self.version <= LEGACY_VERSION
+    }
+}
+
+/// Location of one zstd frame in the `.zst`. In the `.mg` (makigami index)
+/// each FrameInfo is followed by the filter of the frame's n-grams.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct FrameInfo {
+    pub frame_offset: u64,
+    pub frame_size: u64,
+    /// `frame_checksum` of the frame's compressed bytes; None for legacy indexes
+    pub checksum: Option<u64>,
+    /// Input the frame's lines were read from; empty when unknown, as for
+    /// `.zst` files indexed in place and legacy indexes
+    pub source: String,
+    /// Offset of the frame's first line within `source`, after decompression
+    pub source_offset: u64,
+    /// Lines of `source` before the frame
+    pub source_line: u64,
+    /// Length of the frame's data after decompression
+    pub uncompressed_size: u64,
+    /// Lines in the frame, counting an unterminated last line
+    pub line_count: u64,
+    /// Earliest and latest line timestamp, if any line had one
+    pub time_range: Option<TimeRange>,
+}
+
+/// Hash of a frame's compressed bytes as recorded in FrameInfo (xxh3), so
+/// damaged data is caught before it is decompressed
+pub fn frame_checksum(frame: &[u8]) -> u64 {
+    xxh3_64(frame)
+}
+
+/// FrameInfo as laid out up to `LEGACY_VERSION`
+#[derive(Decode)]
+struct LegacyFrameInfo {
+    frame_offset: u64,
+    frame_size: u64,
+}
+
+/// A record type of the `.mg`, decoded according to the file's format version
+pub trait IndexRecord: Sized {
+    /// Decode one record from the start of `data`, returning it and its length
+    fn decode(data: &[u8], version: u32) -> Option<(Self, usize)>;
+}
+
+impl IndexRecord for FrameInfo {
+    fn decode(data: &[u8], version: u32) -> Option<(Self, usize)> {
+        if version <= LEGACY_VERSION {
+            let (legacy, n): (LegacyFrameInfo, usize) = decode_from_slice(data, standard()).ok()?;
+            let frame_info = FrameInfo {
+                frame_offset: legacy.frame_offset,
+                frame_size: legacy.frame_size,
+                checksum: None,
+                source: String::new(),
+                source_offset: 0,
+                source_line: 0,
+                uncompressed_size: 0,
+                line_count: 0,
+                time_range: None,
+            };
+            return Some((frame_info, n));
+        }
+        decode_from_slice(data, standard()).ok()
+    }
+}
+
+/// Writes record + filter pairs to a `.mg`
+pub struct IndexWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> IndexWriter<W> {
+    /// Start a new `.mg`, writing the header for `kind` and `filter`
+    pub fn create(mut out: W, kind: IndexKind, filter: FilterSpec) -> io::Result<Self> {
+        out.write_all(INDEX_MAGIC)?;
+        let header = IndexHeader {
+            version: FORMAT_VERSION,
+            kind,
+            filter,
+        };
+        encode_into_std_write(&header, &mut out, standard()).map_err(io::Error::other)?;
+        Ok(Self { out })
+    }
+
+    /// Continue an existing `.mg` whose header (if any) is already written
+    pub fn append(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn write_record<T: Encode>(&mut self, info: &T, filter: &FrameFilter) -> io::Result<()> {
+        encode_into_std_write(info, &mut self.out, standard()).map_err(io::Error::other)?;
+        filter.encode_into(&mut self.out)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Iterates the record + filter pairs following the header of a `.mg` held
+/// in memory, stopping at the end of the data or at the first record that
+/// doesn't decode
+pub struct IndexReader<'a, T> {
+    data: &'a [u8],
+    version: u32,
+    filter_type: FilterType,
+    record: PhantomData<T>,
+}
+
+impl<'a, T> IndexReader<'a, T> {
+    /// `records` is the part of the file after `header` (see `split_header`)
+    pub fn new(header: &IndexHeader, records: &'a [u8]) -> Self {
+        Self {
+            data: records,
+            version: header.version,
+            filter_type: header.filter.filter_type,
+            record: PhantomData,
+        }
+    }
+
+    /// The bytes not consumed yet: after iteration ends, whatever follows the
+    /// last complete record
+    pub fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl<T: IndexRecord> Iterator for IndexReader<'_, T> {
+    type Item = (T, FrameFilter);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (info, n) = T::decode(self.data, self.version)?;
+        let (filter, m) = FrameFilter::decode(&self.data[n..], self.filter_type)?;
+        self.data = &self.data[n + m..];
+        Some((info, filter))
+    }
+}
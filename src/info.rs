@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use chrono::{DateTime, SecondsFormat};
+use serde::Serialize;
+
+use crate::gzindex::AccessPoint;
+use crate::index::{split_header, FrameFilter, FrameInfo, IndexKind, IndexReader};
+use crate::storage::{create_storage, StorageError, StorageOptions};
+use crate::timestamp::TimeRange;
+
+/// What `mg info` reports about an archive
+#[derive(Debug, Serialize)]
+pub struct ArchiveInfo {
+    pub archive: String,
+    pub index: String,
+    pub format_version: u32,
+    /// `zstd-frames` or `gzip-access-points`
+    pub kind: &'static str,
+    pub filter: FilterInfo,
+    /// Frames, or access points of a `.gz`
+    pub frames: usize,
+    pub compressed_bytes: u64,
+    /// Unknown for indexes that predate uncompressed sizes
+    pub uncompressed_bytes: Option<u64>,
+    pub index_bytes: u64,
+    /// Index size relative to the compressed data
+    pub index_ratio: f64,
+    /// Distinct n-grams per frame, estimated from the filter sizes
+    pub ngrams_per_frame: Option<Distribution>,
+    pub time_range: Option<TimeRangeInfo>,
+    /// Distinct input files recorded for the frames
+    pub sources: usize,
+    /// Frames whose compressed bytes have a checksum in the index
+    pub checksummed_frames: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilterInfo {
+    #[serde(rename = "type")]
+    pub filter_type: &'static str,
+    pub fingerprint_bits: u32,
+    /// Bytes per key
+    pub ngram: u8,
+    /// Whether keys were taken from lower-cased data
+    pub casefold: bool,
+    /// Chance that a frame not containing an n-gram still matches it
+    pub false_positive_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Distribution {
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub max: u64,
+    pub mean: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeRangeInfo {
+    pub min: String,
+    pub max: String,
+    /// Frames with any timestamped line
+    pub frames: usize,
+}
+
+/// Main entry point for the "info" subcommand. Only the index is read, through
+/// `LogStorage`, so remote archives cost a single fetch.
+pub fn run_info(
+    zst_path: &str,
+    maybe_idx_path: Option<&str>,
+    json: bool,
+    options: &StorageOptions,
+) -> Result<(), StorageError> {
+    let storage = create_storage(zst_path, maybe_idx_path, options)?;
+    let index_data = storage.fetch_index()?;
+    let index_name = match maybe_idx_path {
+        Some(idx) => idx.to_string(),
+        None => Path::new(zst_path).with_extension("mg").display().to_string(),
+    };
+    let info = archive_info(zst_path, index_name, &index_data)?;
+
+    if json {
+        let text = serde_json::to_string_pretty(&info).map_err(std::io::Error::other)?;
+        println!("{}", text);
+    } else {
+        print_info(&info);
+    }
+    Ok(())
+}
+
+/// Summarize the `.mg` held in `index_data`
+pub fn archive_info(archive: &str, index: String, index_data: &[u8]) -> std::io::Result<ArchiveInfo> {
+    let (header, records) = split_header(index_data)?;
+
+    let mut key_counts = Vec::new();
+    let mut compressed_bytes = 0;
+    let mut uncompressed_bytes = Some(0);
+    let mut time_range: Option<TimeRange> = None;
+    let mut timed_frames = 0;
+    let mut sources = HashSet::new();
+    let mut checksummed_frames = 0;
+    let kind = match header.kind {
+        IndexKind::ZstdFrames => {
+            let legacy = header.is_legacy_frames();
+            for (frame_info, filter) in IndexReader::<FrameInfo>::new(&header, records) {
+                key_counts.push(estimate_keys(&filter));
+                compressed_bytes += frame_info.frame_size;
+                uncompressed_bytes = uncompressed_bytes
+                    .filter(|_| !legacy)
+                    .map(|total| total + frame_info.uncompressed_size);
+                if let Some(range) = frame_info.time_range {
+                    timed_frames += 1;
+                    time_range = Some(match time_range {
+                        Some(total) => TimeRange {
+                            min: total.min.min(range.min),
+                            max: total.max.max(range.max),
+                        },
+                        None => range,
+                    });
+                }
+                checksummed_frames += usize::from(frame_info.checksum.is_some());
+                if !frame_info.source.is_empty() {
+                    sources.insert(frame_info.source);
+                }
+            }
+            "zstd-frames"
+        }
+        IndexKind::GzipAccessPoints => {
+            for (point, filter) in IndexReader::<AccessPoint>::new(&header, records) {
+                key_counts.push(estimate_keys(&filter));
+                // Access points cover the file back to back
+                compressed_bytes = point.comp_end;
+                uncompressed_bytes = Some(point.end);
+            }
+            "gzip-access-points"
+        }
+    };
+
+    Ok(ArchiveInfo {
+        archive: archive.to_string(),
+        index,
+        format_version: header.version,
+        kind,
+        filter: FilterInfo {
+            filter_type: header.filter.filter_type.name(),
+            fingerprint_bits: header.filter.filter_type.fingerprint_bits(),
+            ngram: header.filter.ngram,
+            casefold: header.filter.casefold,
+            false_positive_rate: 0.5f64.powi(header.filter.filter_type.fingerprint_bits() as i32),
+        },
+        frames: key_counts.len(),
+        compressed_bytes,
+        uncompressed_bytes,
+        index_bytes: index_data.len() as u64,
+        index_ratio: index_data.len() as f64 / compressed_bytes.max(1) as f64,
+        ngrams_per_frame: distribution(&mut key_counts),
+        time_range: time_range.map(|range| TimeRangeInfo {
+            min: format_time(range.min),
+            max: format_time(range.max),
+            frames: timed_frames,
+        }),
+        sources: sources.len(),
+        checksummed_frames,
+    })
+}
+
+fn print_info(info: &ArchiveInfo) {
+    println!("Archive:        {}", info.archive);
+    println!("Index:          {} (format version {}, {})", info.index, info.format_version, info.kind);
+    println!(
+        "Filter:         {} ({}-bit fingerprints, {}-byte n-grams{}, false-positive rate {:.4}% per n-gram)",
+        info.filter.filter_type,
+        info.filter.fingerprint_bits,
+        info.filter.ngram,
+        if info.filter.casefold { ", case-folded" } else { "" },
+        info.filter.false_positive_rate * 100.0
+    );
+    println!("Frames:         {}", info.frames);
+    println!("Compressed:     {} bytes", info.compressed_bytes);
+    match info.uncompressed_bytes {
+        Some(bytes) => println!(
+            "Uncompressed:   {} bytes (compression ratio {:.2})",
+            bytes,
+            bytes as f64 / info.compressed_bytes.max(1) as f64
+        ),
+        None => println!("Uncompressed:   unknown (index predates uncompressed sizes)"),
+    }
+    println!(
+        "Index size:     {} bytes ({:.1}% of the compressed data)",
+        info.index_bytes,
+        info.index_ratio * 100.0
+    );
+    if let Some(d) = &info.ngrams_per_frame {
+        println!(
+            "N-grams/frame:  min {}, median {}, p90 {}, max {}, mean {} (estimated)",
+            d.min, d.p50, d.p90, d.max, d.mean
+        );
+    }
+    match &info.time_range {
+        Some(range) => println!(
+            "Time range:     {} .. {} ({} of {} frames timestamped)",
+            range.min, range.max, range.frames, info.frames
+        ),
+        None => println!("Time range:     none recorded"),
+    }
+    if info.kind == "zstd-frames" {
+        println!(
+            "Checksums:      {} of {} frames (xxh3 of the compressed bytes)",
+            info.checksummed_frames, info.frames
+        );
+    }
+    if info.sources > 0 {
+        println!("Sources:        {}", info.sources);
+    }
+}
+
+fn distribution(values: &mut [u64]) -> Option<Distribution> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let at = |q: f64| values[((values.len() - 1) as f64 * q).round() as usize];
+    Some(Distribution {
+        min: values[0],
+        p50: at(0.5),
+        p90: at(0.9),
+        max: values[values.len() - 1],
+        mean: values.iter().sum::<u64>() / values.len() as u64,
+    })
+}
+
+fn format_time(ms: i64) -> String {
+    DateTime::from_timestamp_millis(ms)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_else(|| ms.to_string())
+}
+
+/// Number of keys a binary fuse filter was built from, estimated by inverting the
+/// sizing xorf applies when building it: the fingerprint array holds about
+/// 1.125-1.5 slots per key, rounded up to whole segments. Every key count
+/// that gives the filter's length is equally likely, so the middle of that
+/// range is returned.
+pub fn estimate_keys(filter: &FrameFilter) -> u64 {
+    let len = filter.len() as u64;
+    // First key count whose array is at least `len`, and first one past it
+    let lower = first_keys_with_len_at_least(len);
+    let upper = first_keys_with_len_at_least(len + 1);
+    (lower + upper.saturating_sub(1)) / 2
+}
+
+fn first_keys_with_len_at_least(len: u64) -> u64 {
+    let (mut lo, mut hi) = (0u64, u32::MAX as u64);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if fuse_array_len(mid) >= len {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    lo
+}
+
+/// Fingerprint array length of a 3-wise binary fuse filter over `size` keys,
+/// as computed by xorf
+fn fuse_array_len(size: u64) -> u64 {
+    if size <= 1 {
+        // Degenerate filters have a single minimal segment
+        return 0;
+    }
+    let size_f = size as f64;
+    let segment_length = (1u64 << (size_f.ln() / 3.33f64.ln() + 2.25).floor() as u32).min(262_144);
+    let size_factor = (0.875 + 0.25 * 1_000_000f64.ln() / size_f.ln()).max(1.125);
+    let capacity = (size_f * size_factor).round() as u64;
+    let segments = capacity.div_ceil(segment_length).max(3);
+    segments * segment_length
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::testutil::{log_lines, temp_dir};
+
+    /// Build `log` into `app.zst` in `dir`, returning the data path
+    fn build(dir: &Path, log: &[u8]) -> PathBuf {
+        let log_path = dir.join("app.log");
+        let zst_path = dir.join("app.zst");
+        fs::write(&log_path, log).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        run_build(&[log_path.display().to_string()], Some(zst), None, &BuildOptions::default()).unwrap();
+        zst_path
+    }
+
+    #[test]
+    fn info_needs_only_the_index() {
+        let dir = temp_dir();
+        let zst_path = build(dir.path(), &log_lines(0, 1000));
+        fs::remove_file(&zst_path).unwrap();
+
+        run_info(zst_path.to_str().unwrap(), None, true, &StorageOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn json_report_has_the_documented_shape() {
+        let dir = temp_dir();
+        let log = log_lines(0, 40_000);
+        let zst_path = build(dir.path(), &log);
+        let idx_path = zst_path.with_extension("mg");
+        let index_data = fs::read(&idx_path).unwrap();
+        let info = archive_info("app.zst", "app.mg".to_string(), &index_data).unwrap();
+        let value = serde_json::to_value(&info).unwrap();
+
+        let frames = value["frames"].as_u64().unwrap();
+        assert!(frames >= 3);
+        let mut fixed = value.clone();
+        for volatile in ["index_ratio", "ngrams_per_frame", "time_range"] {
+            fixed.as_object_mut().unwrap().remove(volatile);
+        }
+        fixed["filter"].as_object_mut().unwrap().remove("false_positive_rate");
+        assert_eq!(fixed, json!({
+            "archive": "app.zst",
+            "index": "app.mg",
+            "format_version": 2,
+            "kind": "zstd-frames",
+            "filter": {"type": "binary-fuse8", "fingerprint_bits": 8, "ngram": 8, "casefold": false},
+            "frames": frames,
+            "compressed_bytes": fs::metadata(&zst_path).unwrap().len(),
+            "uncompressed_bytes": log.len(),
+            "index_bytes": index_data.len(),
+            "sources": 1,
+            "checksummed_frames": frames,
+        }));
+
+        assert!(value["index_ratio"].as_f64().unwrap() > 0.0);
+        assert_eq!(value["filter"]["false_positive_rate"], json!(1.0 / 256.0));
+        let ngrams = &value["ngrams_per_frame"];
+        let [min, p50, p90, max] = ["min", "p50", "p90", "max"].map(|key| ngrams[key].as_u64().unwrap());
+        assert!(0 < min && min <= p50 && p50 <= p90 && p90 <= max);
+        assert!(ngrams["mean"].is_u64());
+        let time_range = &value["time_range"];
+        assert!(time_range["min"].as_str().unwrap().starts_with("2026-10-01T00:00:00"));
+        assert!(time_range["max"].as_str().unwrap().starts_with("2026-10-01T11:06:39"));
+        assert_eq!(time_range["frames"], json!(frames));
+    }
+}
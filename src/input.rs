@@ -0,0 +1,58 @@
+use std::io::{self, BufReader, Cursor, Read};
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+/// Compression formats accepted as `mg build` input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+/// File name extensions of compressed inputs, stripped when deriving output names
+pub const COMPRESSED_EXTENSIONS: &[&str] = &["gz", "bz2", "xz", "zst"];
+
+/// Longest magic number we look for (xz)
+pub const MAGIC_LEN: usize = 6;
+
+impl Compression {
+    /// Identify the format from the first bytes of the input
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if matches!(magic, [b'B', b'Z', b'h', b'1'..=b'9', ..]) {
+            // The digit is the block size; text starting "BZh" is not bzip2
+            Compression::Bzip2
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Sniff the compression format of `reader` and wrap it in the matching
+/// decoder. Multi-member gzip/bzip2 files (e.g. `cat a.gz b.gz`) and
+/// multi-stream xz/zstd files are decoded to the end.
+pub fn decompressing_reader<'a>(mut reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    (&mut reader).take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+    let compression = Compression::detect(&magic);
+    // Put the sniffed bytes back in front of the rest of the input
+    let reader = BufReader::new(Cursor::new(magic).chain(reader));
+
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+    })
+}
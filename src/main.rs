@@ -4,7 +4,30 @@ mod search;
 mod utils;
 mod fastu64set;
 mod storage;
+mod cache;
+// Only remote backends retry; local builds have none
+#[cfg(feature = "gcs")]
+mod retry;
+mod push;
+mod input;
+mod index;
+mod reindex;
+mod gzindex;
+mod follow;
+mod checkpoint;
+mod timestamp;
+mod info;
+mod verify;
+mod parity;
+mod salvage;
+mod concat;
+mod slice;
+#[cfg(test)]
+mod testutil;
 
+use std::time::Duration;
+
+use build::{BuildMode, BuildOptions};
 use clap::Parser;
 use cli::{Cli, Commands};
 
@@ -13,14 +36,166 @@ fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Build { input, zst, idx } => {
+        Commands::Build {
+            inputs,
+            input_list,
+            zst,
+            idx,
+            append,
+            follow,
+            resume,
+            idle_timeout,
+            max_memory,
+            time,
+            filter,
+            storage,
+        } => {
             // Build subcommand
-            build::run_build(input, zst.as_deref(), idx.as_deref())?;
+            let mut inputs = inputs.clone();
+            if let Some(list) = input_list {
+                inputs.extend(build::read_input_list(list)?);
+            }
+            let mode = if *append {
+                BuildMode::Append
+            } else if *resume {
+                BuildMode::Resume
+            } else {
+                BuildMode::Create
+            };
+            let options = BuildOptions {
+                mode,
+                max_memory: *max_memory,
+                time_detector: time.to_detector()?,
+                filter: filter.to_spec(),
+            };
+            match zst.as_deref() {
+                Some(dest) if dest.starts_with("gs://") => {
+                    if *append || *follow || *resume {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--append, --follow and --resume work on local archives only",
+                        ));
+                    }
+                    let [input] = &inputs[..] else {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "building for a gs:// URL takes a single input; build locally, then mg push",
+                        ));
+                    };
+                    push::run_build_and_push(input, dest, idx.as_deref(), &options, &storage.to_options())
+                        .map_err(|e| std::io::Error::other(format!("{}", e)))?;
+                }
+                _ if *follow => {
+                    let [input] = &inputs[..] else {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "--follow takes a single input",
+                        ));
+                    };
+                    let idle_timeout = Duration::from_secs(*idle_timeout);
+                    follow::run_follow(input, zst.as_deref(), idx.as_deref(), idle_timeout, &options)?;
+                }
+                _ => build::run_build(&inputs, zst.as_deref(), idx.as_deref(), &options)?,
+            }
+        }
+        Commands::Append { zst, input, idx, max_memory, time } => {
+            // Append subcommand
+            let options = BuildOptions {
+                mode: BuildMode::Append,
+                max_memory: *max_memory,
+                time_detector: time.to_detector()?,
+                ..BuildOptions::default()
+            };
+            build::run_build(std::slice::from_ref(input), Some(zst), idx.as_deref(), &options)?;
         }
-        Commands::Search { zst, idx, pattern } => {
+        Commands::Search {
+            zst,
+            idx,
+            pattern,
+            with_filename,
+            line_number,
+            byte_offset,
+            since,
+            until,
+            time,
+            block_cache,
+            block_cache_size,
+            storage,
+        } => {
             // Search subcommand
-            search::run_search(zst, idx.as_deref(), pattern)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))?;
+            let mut options = storage.to_options();
+            options.block_cache = block_cache.clone().map(|dir| (dir, *block_cache_size));
+            let search_options = search::SearchOptions {
+                with_filename: *with_filename,
+                line_number: *line_number,
+                byte_offset: *byte_offset,
+                since: *since,
+                until: *until,
+                time_detector: time.to_detector()?,
+            };
+            search::run_search(zst, idx.as_deref(), pattern, &search_options, &options)
+                .map_err(|e| std::io::Error::other(format!("{}", e)))?;
+        }
+        Commands::Index { zst, idx, time, filter } => {
+            // Index subcommand
+            reindex::run_index(zst, idx.as_deref(), &time.to_detector()?, filter.to_spec())?;
+        }
+        Commands::Reindex { zst, idx, time, filter } => {
+            // Reindex subcommand
+            reindex::run_reindex(zst, idx.as_deref(), &time.to_detector()?, filter.to_spec())?;
+        }
+        Commands::Concat { inputs, input_list, zst, idx } => {
+            // Concat subcommand
+            let mut inputs = inputs.clone();
+            if let Some(list) = input_list {
+                inputs.extend(build::read_input_list(list)?);
+            }
+            concat::run_concat(&inputs, zst, idx.as_deref())?;
+        }
+        Commands::Slice { zst, out, idx, frames, since, until } => {
+            // Slice subcommand
+            let options = slice::SliceOptions {
+                frames: frames.clone(),
+                since: *since,
+                until: *until,
+            };
+            slice::run_slice(zst, out, idx.as_deref(), &options)?;
+        }
+        Commands::Split { zst, out, max_size } => {
+            // Split subcommand
+            slice::run_split(zst, out.as_deref(), *max_size)?;
+        }
+        Commands::Info { zst, idx, json, storage } => {
+            // Info subcommand
+            info::run_info(zst, idx.as_deref(), *json, &storage.to_options())
+                .map_err(|e| std::io::Error::other(format!("{}", e)))?;
+        }
+        Commands::Verify { zst, idx, deep } => {
+            // Verify subcommand
+            verify::run_verify(zst, idx.as_deref(), *deep)?;
+        }
+        Commands::Parity { zst, idx, mgpar, overhead, group, storage } => {
+            // Parity subcommand
+            let parity_options = parity::ParityOptions {
+                group: *group,
+                overhead: *overhead,
+            };
+            parity::run_parity(zst, idx.as_deref(), mgpar.as_deref(), &parity_options, &storage.to_options())
+                .map_err(|e| std::io::Error::other(format!("{}", e)))?;
+        }
+        Commands::Repair { zst, mgpar } => {
+            // Repair subcommand
+            parity::run_repair(zst, mgpar.as_deref())
+                .map_err(|e| std::io::Error::other(format!("{}", e)))?;
+        }
+        Commands::Salvage { zst, idx, time } => {
+            // Salvage subcommand
+            salvage::run_salvage(zst, idx.as_deref(), &time.to_detector()?)?;
+        }
+        Commands::Push { zst, dest, idx, dest_idx, force, storage } => {
+            // Push subcommand
+            push::run_push(zst, idx.as_deref(), dest, dest_idx.as_deref(), *force, &storage.to_options())
+                .map_err(|e| std::io::Error::other(format!("{}", e)))?;
         }
     }
 
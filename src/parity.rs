@@ -0,0 +1,432 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::{config::standard, decode_from_slice, encode_to_vec, Decode, Encode};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::index::{frame_checksum, split_header, FrameInfo, IndexKind, IndexReader};
+use crate::storage::{create_storage, LogStorage, StorageError, StorageOptions};
+
+/// Magic at the start and the very end of every `.mgpar`
+const PARITY_MAGIC: &[u8; 4] = b"MGPR";
+
+/// Current `.mgpar` format version
+const PARITY_VERSION: u32 = 1;
+
+/// Footer after the trailer: its length and checksum (u64 LE each), then the magic
+const FOOTER_LEN: usize = 8 + 8 + PARITY_MAGIC.len();
+
+/// Reed-Solomon over GF(2^8) takes at most this many shards per group
+const MAX_SHARDS: usize = 256;
+
+/// Frames covered by one set of parity frames, and how much parity they get
+#[derive(Debug, Clone, Copy)]
+pub struct ParityOptions {
+    /// Frames per group
+    pub group: usize,
+    /// Parity size as a percentage of the data, rounded up to whole parity
+    /// frames per group (at least one)
+    pub overhead: f64,
+}
+
+/// Layout of a `.mgpar`: the parity frames of every group back to back after
+/// the magic and version, then this trailer
+#[derive(Debug, Encode, Decode)]
+struct ParityTrailer {
+    groups: Vec<ParityGroup>,
+}
+
+/// One group of data frames and where its parity frames are
+#[derive(Debug, Encode, Decode)]
+struct ParityGroup {
+    frames: Vec<ProtectedFrame>,
+    /// Length of every shard; frames are zero-padded to it
+    shard_size: u64,
+    /// Offset of the group's first parity frame in the `.mgpar`
+    parity_offset: u64,
+    /// `frame_checksum` of each parity frame
+    parity_checksums: Vec<u64>,
+}
+
+/// A data frame as it was when the parity was computed
+#[derive(Debug, Clone, Encode, Decode)]
+struct ProtectedFrame {
+    offset: u64,
+    size: u64,
+    checksum: u64,
+}
+
+/// Default `.mgpar` path: next to the data, or required for remote archives
+fn parity_path(zst_path: &str, maybe_parity_path: Option<&str>) -> io::Result<PathBuf> {
+    match maybe_parity_path {
+        Some(path) => Ok(PathBuf::from(path)),
+        None if zst_path.starts_with("gs://") => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "parity files are kept locally; pass --mgpar PATH for a gs:// archive",
+        )),
+        None => Ok(Path::new(zst_path).with_extension("mgpar")),
+    }
+}
+
+/// Main entry point for the "parity" subcommand: write a `.mgpar` holding
+/// Reed-Solomon parity over groups of the archive's frames, read through
+/// `LogStorage`. Frames that no longer match the checksum in the index are
+/// refused, as parity over them would preserve the damage.
+pub fn run_parity(
+    zst_path: &str,
+    maybe_idx_path: Option<&str>,
+    maybe_parity_path: Option<&str>,
+    parity_options: &ParityOptions,
+    options: &StorageOptions,
+) -> Result<(), StorageError> {
+    let parity_path = parity_path(zst_path, maybe_parity_path)?;
+    let storage = create_storage(zst_path, maybe_idx_path, options)?;
+    let index_data = storage.fetch_index()?;
+    let (header, records) = split_header(&index_data)?;
+    if header.kind != IndexKind::ZstdFrames {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "parity covers the frames of .zst archives only",
+        )));
+    }
+    let frames: Vec<FrameInfo> = IndexReader::<FrameInfo>::new(&header, records)
+        .map(|(frame_info, _)| frame_info)
+        .collect();
+    let group_len = parity_options.group.clamp(1, MAX_SHARDS - 1);
+
+    // Write to a temporary file and rename it into place once complete,
+    // removing it if anything fails on the way
+    let mut tmp_name = parity_path.clone().into_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let result = (|| {
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        out.write_all(PARITY_MAGIC)?;
+        out.write_all(&PARITY_VERSION.to_le_bytes())?;
+
+        let mut groups = Vec::new();
+        let mut data_bytes = 0u64;
+        let mut parity_bytes = 0u64;
+        let mut frame = Vec::new();
+        for (group_index, group_frames) in frames.chunks(group_len).enumerate() {
+            let first = group_index * group_len;
+            let parity_count = parity_shards(group_frames.len(), parity_options.overhead);
+            let shard_size = group_frames.iter().map(|f| f.frame_size).max().unwrap_or(0);
+            let codec = ReedSolomon::new(group_frames.len(), parity_count).map_err(rs_error)?;
+
+            // Accumulate the parity one data frame at a time
+            let mut parity = vec![vec![0u8; shard_size as usize]; parity_count];
+            let mut protected = Vec::with_capacity(group_frames.len());
+            for (i, frame_info) in group_frames.iter().enumerate() {
+                storage.read_block_into(frame_info.frame_offset, frame_info.frame_size, &mut frame)?;
+                let checksum = frame_checksum(&frame);
+                if frame_info.checksum.is_some_and(|expected| expected != checksum) {
+                    return Err(StorageError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "frame {} at offset {} doesn't match its checksum; repair or rebuild the archive first",
+                            first + i,
+                            frame_info.frame_offset
+                        ),
+                    )));
+                }
+                frame.resize(shard_size as usize, 0);
+                codec.encode_single_sep(i, &frame, &mut parity).map_err(rs_error)?;
+                protected.push(ProtectedFrame {
+                    offset: frame_info.frame_offset,
+                    size: frame_info.frame_size,
+                    checksum,
+                });
+                data_bytes += frame_info.frame_size;
+            }
+
+            let parity_offset = out.stream_position()?;
+            for shard in &parity {
+                out.write_all(shard)?;
+            }
+            parity_bytes += shard_size * parity_count as u64;
+            groups.push(ParityGroup {
+                frames: protected,
+                shard_size,
+                parity_offset,
+                parity_checksums: parity.iter().map(|shard| frame_checksum(shard)).collect(),
+            });
+        }
+
+        let trailer = encode_to_vec(ParityTrailer { groups }, standard()).map_err(io::Error::other)?;
+        out.write_all(&trailer)?;
+        out.write_all(&(trailer.len() as u64).to_le_bytes())?;
+        out.write_all(&frame_checksum(&trailer).to_le_bytes())?;
+        out.write_all(PARITY_MAGIC)?;
+        let out = out.into_inner().map_err(|e| e.into_error())?;
+        out.sync_all()?;
+        fs::rename(&tmp_path, &parity_path)?;
+        Ok::<_, StorageError>((data_bytes, parity_bytes))
+    })();
+    let (data_bytes, parity_bytes) = match result {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    println!(
+        "frames: {} in groups of {} | parity: {} bytes ({:.1}% of {} bytes)",
+        frames.len(),
+        group_len,
+        parity_bytes,
+        parity_bytes as f64 * 100.0 / data_bytes.max(1) as f64,
+        data_bytes
+    );
+    println!("Parity complete. MGPAR: {:?}", parity_path);
+    Ok(())
+}
+
+/// Parity frames for a group of `data_shards` frames at `overhead` percent
+fn parity_shards(data_shards: usize, overhead: f64) -> usize {
+    let wanted = (data_shards as f64 * overhead / 100.0).ceil() as usize;
+    wanted.clamp(1, MAX_SHARDS - data_shards)
+}
+
+fn rs_error(e: reed_solomon_erasure::Error) -> StorageError {
+    StorageError::Io(io::Error::other(format!("Reed-Solomon: {:?}", e)))
+}
+
+/// Read and check the trailer of a `.mgpar`
+fn read_trailer(parity_file: &[u8]) -> io::Result<ParityTrailer> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad parity file: {}", message));
+    if parity_file.len() < PARITY_MAGIC.len() + 4 + FOOTER_LEN || !parity_file.starts_with(PARITY_MAGIC) {
+        return Err(invalid("not a .mgpar"));
+    }
+    let version = u32::from_le_bytes(parity_file[4..8].try_into().unwrap());
+    if version != PARITY_VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+    let footer = &parity_file[parity_file.len() - FOOTER_LEN..];
+    if !footer.ends_with(PARITY_MAGIC) {
+        return Err(invalid("truncated"));
+    }
+    let trailer_len = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    let trailer_checksum = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+    let trailer_end = parity_file.len() - FOOTER_LEN;
+    let trailer = trailer_end
+        .checked_sub(trailer_len as usize)
+        .map(|start| &parity_file[start..trailer_end])
+        .ok_or_else(|| invalid("trailer length out of range"))?;
+    if frame_checksum(trailer) != trailer_checksum {
+        return Err(invalid("damaged trailer"));
+    }
+    let (trailer, _): (ParityTrailer, usize) =
+        decode_from_slice(trailer, standard()).map_err(|e| invalid(&e.to_string()))?;
+    Ok(trailer)
+}
+
+/// Main entry point for the "repair" subcommand: find frames that no longer
+/// match the checksums recorded in the `.mgpar`, rebuild them from the rest
+/// of their group and its parity, and write them back in place. Frames are
+/// read through `LogStorage`; only local archives can be written.
+pub fn run_repair(zst_path: &str, maybe_parity_path: Option<&str>) -> Result<(), StorageError> {
+    if zst_path.starts_with("gs://") {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "mg repair rewrites frames in place; copy the archive locally first",
+        )));
+    }
+    let parity_path = parity_path(zst_path, maybe_parity_path)?;
+    let parity_file = fs::read(&parity_path)?;
+    let trailer = read_trailer(&parity_file)?;
+    // The index isn't needed: the `.mgpar` records every frame it covers
+    let storage = create_storage(zst_path, None, &StorageOptions::default())?;
+    let data_len = fs::metadata(zst_path)?.len();
+
+    let mut repaired = 0;
+    let mut unrecoverable = 0;
+    let mut first = 0;
+    let mut buf = Vec::new();
+    for (group_index, group) in trailer.groups.iter().enumerate() {
+        let damaged: Vec<usize> = (0..group.frames.len())
+            .filter(|&i| read_frame(&*storage, &group.frames[i], data_len, &mut buf).is_none())
+            .collect();
+        if damaged.is_empty() {
+            first += group.frames.len();
+            continue;
+        }
+
+        // Only damaged groups are held in memory whole
+        let frames = group.frames.iter().map(|frame| {
+            read_frame(&*storage, frame, data_len, &mut buf).map(|data| {
+                let mut shard = data.to_vec();
+                shard.resize(group.shard_size as usize, 0);
+                shard
+            })
+        });
+
+        let parity = group.parity_checksums.iter().enumerate().map(|(j, &checksum)| {
+            let start = group.parity_offset as usize + j * group.shard_size as usize;
+            parity_file
+                .get(start..start + group.shard_size as usize)
+                .filter(|shard| frame_checksum(shard) == checksum)
+                .map(<[u8]>::to_vec)
+        });
+        let mut shards: Vec<Option<Vec<u8>>> = frames.chain(parity).collect();
+        let codec = ReedSolomon::new(group.frames.len(), group.parity_checksums.len()).map_err(rs_error)?;
+        if codec.reconstruct_data(&mut shards).is_err() {
+            let intact_parity = shards[group.frames.len()..].iter().filter(|s| s.is_some()).count();
+            for &i in &damaged {
+                let frame = &group.frames[i];
+                println!("frame {} at offset {} ({} bytes): damaged", first + i, frame.offset, frame.size);
+            }
+            println!(
+                "group {}: {} damaged frames but only {} intact parity frames; can't repair",
+                group_index,
+                damaged.len(),
+                intact_parity
+            );
+            unrecoverable += damaged.len();
+            first += group.frames.len();
+            continue;
+        }
+
+        let zst_file = OpenOptions::new().write(true).open(zst_path)?;
+        for &i in &damaged {
+            let frame = &group.frames[i];
+            let rebuilt = &shards[i].as_ref().expect("reconstructed")[..frame.size as usize];
+            if frame_checksum(rebuilt) != frame.checksum {
+                return Err(StorageError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("rebuilt frame {} doesn't match its checksum; the parity file is damaged", first + i),
+                )));
+            }
+            write_all_at(&zst_file, rebuilt, frame.offset)?;
+            println!("frame {} at offset {} ({} bytes): repaired", first + i, frame.offset, frame.size);
+            repaired += 1;
+        }
+        zst_file.sync_data()?;
+        first += group.frames.len();
+    }
+
+    if unrecoverable > 0 {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("repaired {} frames; {} damaged frames could not be repaired", repaired, unrecoverable),
+        )));
+    }
+    println!("{:?}: {} frames covered, {} repaired", zst_path, first, repaired);
+    Ok(())
+}
+
+/// Read `frame` into `buf`, returning it if intact. Frames past the end of
+/// the data, unreadable ones and ones that don't match their checksum are
+/// damaged.
+fn read_frame<'a>(
+    storage: &dyn LogStorage,
+    frame: &ProtectedFrame,
+    data_len: u64,
+    buf: &'a mut Vec<u8>,
+) -> Option<&'a [u8]> {
+    if frame.offset + frame.size > data_len {
+        return None;
+    }
+    storage.read_block_into(frame.offset, frame.size, buf).ok()?;
+    (frame_checksum(buf) == frame.checksum).then_some(&buf[..])
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(mut file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::testutil::{log_lines, temp_dir};
+
+    const OPTIONS: ParityOptions = ParityOptions { group: 4, overhead: 50.0 };
+
+    /// A local archive of several frames and its parity, returning the
+    /// frames in index order
+    fn archive_with_parity(zst_path: &Path) -> Vec<FrameInfo> {
+        let log_path = zst_path.with_extension("log");
+        fs::write(&log_path, log_lines(0, 70_000)).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        run_build(&[log_path.display().to_string()], Some(zst), None, &BuildOptions::default()).unwrap();
+        run_parity(zst, None, None, &OPTIONS, &StorageOptions::default()).unwrap();
+
+        let index_data = fs::read(zst_path.with_extension("mg")).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        IndexReader::<FrameInfo>::new(&header, records)
+            .map(|(frame_info, _)| frame_info)
+            .collect()
+    }
+
+    /// Overwrite the middle of `frame` in `data`
+    fn corrupt(data: &mut [u8], frame: &FrameInfo) {
+        let start = (frame.frame_offset + frame.frame_size / 2) as usize;
+        for b in &mut data[start..start + 16] {
+            *b ^= 0xa5;
+        }
+    }
+
+    #[test]
+    fn repair_restores_damaged_frames() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let frames = archive_with_parity(&zst_path);
+        assert!(frames.len() > OPTIONS.group);
+        let original = fs::read(&zst_path).unwrap();
+
+        // Two frames of the first group, as many as it has parity frames,
+        // and one of the second
+        let mut damaged = original.clone();
+        for i in [0, 2, OPTIONS.group] {
+            corrupt(&mut damaged, &frames[i]);
+        }
+        fs::write(&zst_path, &damaged).unwrap();
+        run_repair(zst_path.to_str().unwrap(), None).unwrap();
+        assert!(fs::read(&zst_path).unwrap() == original);
+
+        // A frame cut off the end of the data is rebuilt too
+        let last = frames.last().unwrap();
+        fs::write(&zst_path, &original[..last.frame_offset as usize + 10]).unwrap();
+        run_repair(zst_path.to_str().unwrap(), None).unwrap();
+        assert!(fs::read(&zst_path).unwrap() == original);
+    }
+
+    #[test]
+    fn repair_fails_with_too_little_parity() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let frames = archive_with_parity(&zst_path);
+        let mut damaged = fs::read(&zst_path).unwrap();
+        for frame in &frames[..3] {
+            corrupt(&mut damaged, frame);
+        }
+        fs::write(&zst_path, &damaged).unwrap();
+        assert!(run_repair(zst_path.to_str().unwrap(), None).is_err());
+        assert!(fs::read(&zst_path).unwrap() == damaged);
+    }
+
+    #[test]
+    fn parity_refuses_damaged_frames() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let frames = archive_with_parity(&zst_path);
+        let parity = fs::read(dir.path().join("app.mgpar")).unwrap();
+        let mut damaged = fs::read(&zst_path).unwrap();
+        corrupt(&mut damaged, &frames[1]);
+        fs::write(&zst_path, &damaged).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        assert!(run_parity(zst, None, None, &OPTIONS, &StorageOptions::default()).is_err());
+        assert!(fs::read(dir.path().join("app.mgpar")).unwrap() == parity);
+        assert!(!dir.path().join("app.mgpar.tmp").exists());
+    }
+}
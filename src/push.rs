@@ -0,0 +1,63 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::build::{run_build, BuildOptions};
+use crate::storage::{check_upload_destination, upload_archive, StorageError, StorageOptions};
+use crate::utils::default_output_names_if_omitted;
+
+/// Main entry point for the "push" subcommand
+/// - `zst_path`: local .zst file
+/// - `maybe_idx_path`: local .mg file, derived from `zst_path` if omitted
+/// - `dest`: gs:// URL of the .zst; the index goes next to it unless `maybe_dest_idx` is given
+pub fn run_push(
+    zst_path: &str,
+    maybe_idx_path: Option<&str>,
+    dest: &str,
+    maybe_dest_idx: Option<&str>,
+    overwrite: bool,
+    options: &StorageOptions,
+) -> Result<(), StorageError> {
+    let zst_path = PathBuf::from(zst_path);
+    let idx_path = match maybe_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => zst_path.with_extension("mg"),
+    };
+    // Catch a missing index before the data upload, not after it
+    for path in [&zst_path, &idx_path] {
+        std::fs::metadata(path)?;
+    }
+
+    upload_archive(&zst_path, &idx_path, dest, maybe_dest_idx, overwrite, options)?;
+    println!("Push complete: {}", dest);
+    Ok(())
+}
+
+/// `mg build -z gs://...`: build next to the input as usual, then push.
+/// The destination is checked before the build, which may take a while.
+pub fn run_build_and_push(
+    input_path: &str,
+    dest: &str,
+    maybe_dest_idx: Option<&str>,
+    build_options: &BuildOptions,
+    options: &StorageOptions,
+) -> Result<(), StorageError> {
+    check_upload_destination(dest)?;
+    // The local archive is named after the input, which stdin doesn't have
+    if input_path == "-" {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "building from stdin for a gs:// URL needs a local archive first: \
+             mg build - -z app.zst, then mg push app.zst gs://...",
+        )));
+    }
+    let (zst_path, idx_path) = default_output_names_if_omitted(input_path, None, None);
+    run_build(&[input_path.to_string()], None, None, build_options)?;
+    run_push(
+        &zst_path.to_string_lossy(),
+        Some(&idx_path.to_string_lossy()),
+        dest,
+        maybe_dest_idx,
+        false,
+        options,
+    )
+}
@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use memmap2::Mmap;
+
+use crate::build::{count_lines, partial_path};
+use crate::fastu64set::FastSet;
+use crate::gzindex::index_gzip;
+use crate::index::{
+    frame_checksum, split_header, FilterSpec, FrameFilter, FrameInfo, IndexKind, IndexReader, IndexWriter,
+};
+use crate::input::{Compression, MAGIC_LEN};
+use crate::timestamp::{TimeDetector, TimeRange};
+use crate::utils::{CHUNK_SIZE, HASH_CAPACITY};
+
+/// Main entry point for the "index" subcommand: write a `.mg` for an existing
+/// multi-frame `.zst` or a plain `.gz`. The data file is only read, never
+/// modified, so its frames and any recorded checksums stay valid.
+pub fn run_index(
+    zst_path: &str,
+    maybe_idx_path: Option<&str>,
+    time_detector: &TimeDetector,
+    filter_spec: FilterSpec,
+) -> io::Result<()> {
+    let zst_path = Path::new(zst_path);
+    let idx_path = match maybe_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => zst_path.with_extension("mg"),
+    };
+    if idx_path == zst_path {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "index path must differ from the data path",
+        ));
+    }
+
+    let zst_file = File::open(zst_path)?;
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    (&zst_file).take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+    match Compression::detect(&magic) {
+        Compression::Zstd => {}
+        Compression::Gzip => {
+            (&zst_file).rewind()?;
+            let points = write_index(&idx_path, |index_file| index_gzip(&zst_file, index_file, filter_spec))?;
+            println!("access points: {}", points);
+            println!("Index complete. GZ: {:?} | MG: {:?}", zst_path, idx_path);
+            return Ok(());
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only .zst and .gz files can be indexed in place; recompress with mg build",
+            ));
+        }
+    }
+
+    let mmap = unsafe { Mmap::map(&zst_file)? };
+    let frames = scan_frames(&mmap)?;
+    println!("frames: {}", frames.len());
+    let mut time_detector = time_detector.clone();
+    time_detector.set_last_write(zst_file.metadata()?.modified().ok());
+
+    write_index(&idx_path, |index_file| {
+        Ok((index_frames(&mmap, &frames, index_file, &time_detector, filter_spec)?, ()))
+    })?;
+
+    println!("Index complete. ZST: {:?} | MG: {:?}", zst_path, idx_path);
+    Ok(())
+}
+
+/// Main entry point for the "reindex" subcommand: write a fresh `.mg` for an
+/// archive from its data alone, for instance with other filter settings. The
+/// frames recorded in the existing index are kept along with their sources;
+/// if it is lost or damaged, the data is scanned for frames instead. The new
+/// index replaces the old one only once complete, and the data file is only read.
+pub fn run_reindex(
+    zst_path: &str,
+    maybe_idx_path: Option<&str>,
+    time_detector: &TimeDetector,
+    filter_spec: FilterSpec,
+) -> io::Result<()> {
+    let zst = Path::new(zst_path);
+    let idx_path = match maybe_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => zst.with_extension("mg"),
+    };
+    if idx_path == zst {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "index path must differ from the data path",
+        ));
+    }
+
+    let zst_file = File::open(zst)?;
+    let mmap = unsafe { Mmap::map(&zst_file)? };
+    if Compression::detect(&mmap[..MAGIC_LEN.min(mmap.len())]) != Compression::Zstd {
+        // A .gz index holds nothing that can't be found again
+        return run_index(zst_path, maybe_idx_path, time_detector, filter_spec);
+    }
+
+    let frames = match existing_frames(&idx_path, mmap.len() as u64) {
+        Ok(frames) => {
+            println!("frames: {} (from {:?})", frames.len(), idx_path);
+            frames
+        }
+        Err(e) => {
+            eprintln!("Can't use the existing index ({}); scanning the data for frames", e);
+            let frames = scan_frames(&mmap)?;
+            println!("frames: {}", frames.len());
+            frames
+        }
+    };
+
+    write_index(&idx_path, |index_file| {
+        Ok((index_frames(&mmap, &frames, index_file, time_detector, filter_spec)?, ()))
+    })?;
+
+    println!("Reindex complete. ZST: {:?} | MG: {:?}", zst, idx_path);
+    Ok(())
+}
+
+/// Write an index with `write` to a partial file next to `idx_path`, and
+/// rename it into place only once complete and synced, so a failed run
+/// leaves any existing index alone
+fn write_index<T>(
+    idx_path: &Path,
+    write: impl FnOnce(BufWriter<File>) -> io::Result<(BufWriter<File>, T)>,
+) -> io::Result<T> {
+    let partial_idx_path = partial_path(idx_path);
+    let result = File::create(&partial_idx_path)
+        .and_then(|index_file| write(BufWriter::new(index_file)))
+        .and_then(|(index_file, value)| {
+            let index_file = index_file.into_inner().map_err(|e| e.into_error())?;
+            index_file.sync_all()?;
+            fs::rename(&partial_idx_path, idx_path)?;
+            Ok(value)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial_idx_path);
+    }
+    result
+}
+
+/// The frames recorded in the `.mg` at `idx_path`, if every record decodes
+/// and points inside `data_len` bytes of data
+fn existing_frames(idx_path: &Path, data_len: u64) -> io::Result<Vec<FrameInfo>> {
+    let index_data = fs::read(idx_path)?;
+    let (header, records) = split_header(&index_data)?;
+    if header.kind != IndexKind::ZstdFrames {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "it doesn't index zstd frames"));
+    }
+    let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+    let frames: Vec<FrameInfo> = reader.by_ref().map(|(frame_info, _)| frame_info).collect();
+    if !reader.remaining().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("records after frame {} don't decode", frames.len()),
+        ));
+    }
+    if frames.iter().any(|f| f.frame_offset.saturating_add(f.frame_size) > data_len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "it points past the end of the data",
+        ));
+    }
+    Ok(frames)
+}
+
+/// Locate every zstd frame in `data` by walking the frame headers.
+/// Skippable frames carry no log data and are left out.
+pub fn scan_frames(data: &[u8]) -> io::Result<Vec<FrameInfo>> {
+    let (frames, end) = complete_frames(data);
+    if end < data.len() {
+        // complete_frames stopped because this fails
+        let code = zstd::zstd_safe::find_frame_compressed_size(&data[end..]).unwrap_err();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "no complete zstd frame at offset {}: {}",
+                end,
+                zstd::zstd_safe::get_error_name(code)
+            ),
+        ));
+    }
+    Ok(frames)
+}
+
+/// The frames of `data` up to the first one that isn't complete, and where
+/// that one starts (the length of `data` if there is none)
+pub fn complete_frames(data: &[u8]) -> (Vec<FrameInfo>, usize) {
+    let mut frames = Vec::new();
+    let mut pos = 0usize;
+    while pos < data.len() {
+        let Ok(frame_size) = zstd::zstd_safe::find_frame_compressed_size(&data[pos..]) else {
+            break;
+        };
+        if !is_skippable_frame(&data[pos..]) {
+            frames.push(FrameInfo {
+                frame_offset: pos as u64,
+                frame_size: frame_size as u64,
+                checksum: None,
+                source: String::new(),
+                source_offset: 0,
+                source_line: 0,
+                uncompressed_size: 0,
+                line_count: 0,
+                time_range: None,
+            });
+        }
+        pos += frame_size;
+    }
+    (frames, pos)
+}
+
+/// Skippable frames use magic numbers 0x184D2A50..=0x184D2A5F
+fn is_skippable_frame(frame: &[u8]) -> bool {
+    frame.len() >= 4 && u32::from_le_bytes(frame[..4].try_into().unwrap()) & 0xFFFF_FFF0 == 0x184D_2A50
+}
+
+/// Largest decompressed frame that can be indexed. Filters are built from a
+/// set of up to twice as many keys as the frame has bytes, in memory.
+const MAX_FRAME_SIZE: usize = 2 * CHUNK_SIZE;
+
+/// Decompress each frame of `data` and build its filter on all cores,
+/// writing a header and the FrameInfo + filter records to `out` in frame
+/// order. Frames whose compressed bytes don't match a checksum they already
+/// have fail. With no input file to refer to, a frame's source offset and
+/// line are where its data starts in the decompressed `.zst`.
+pub fn index_frames<W: Write>(
+    data: &[u8],
+    frames: &[FrameInfo],
+    out: W,
+    time_detector: &TimeDetector,
+    filter_spec: FilterSpec,
+) -> io::Result<W> {
+    let mut index_writer = IndexWriter::create(out, IndexKind::ZstdFrames, filter_spec)?;
+    write_frame_records(data, frames, &mut index_writer, (0, 0), time_detector, filter_spec)?;
+    Ok(index_writer.into_inner())
+}
+
+/// Like `index_frames`, but add the records to `index_writer`. Frames with
+/// no source are placed in the decompressed `.zst` starting at `stream_start`,
+/// the (offset, line) where the first of `frames` begins. Returns where the
+/// last one ends.
+pub fn write_frame_records<W: Write>(
+    data: &[u8],
+    frames: &[FrameInfo],
+    index_writer: &mut IndexWriter<W>,
+    stream_start: (u64, u64),
+    time_detector: &TimeDetector,
+    filter_spec: FilterSpec,
+) -> io::Result<(u64, u64)> {
+    let next_frame = AtomicUsize::new(0);
+    let large_frames = Mutex::new(());
+    let num_workers = num_cpus::get().min(frames.len()).max(1);
+    let (result_sender, result_receiver) =
+        mpsc::sync_channel::<(usize, io::Result<FrameSummary>)>(num_workers);
+
+    thread::scope(|scope| {
+        for _ in 0..num_workers {
+            let result_sender = result_sender.clone();
+            let next_frame = &next_frame;
+            let large_frames = &large_frames;
+            let mut time_detector = time_detector.clone();
+            scope.spawn(move || {
+                let mut set_capacity = HASH_CAPACITY;
+                let mut set = FastSet::new(set_capacity);
+                let mut decompressed = Vec::new();
+                loop {
+                    let i = next_frame.fetch_add(1, Ordering::Relaxed);
+                    let Some(frame) = frames.get(i) else {
+                        break;
+                    };
+                    let start = frame.frame_offset as usize;
+                    let compressed = &data[start..start + frame.frame_size as usize];
+                    let checksum = frame_checksum(compressed);
+
+                    decompressed.clear();
+                    let result = if frame.checksum.is_some_and(|expected| expected != checksum) {
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "compressed bytes don't match the checksum in the index; repair the archive first",
+                        ))
+                    } else {
+                        zstd::Decoder::with_buffer(compressed).and_then(|decoder| {
+                            decoder.take(MAX_FRAME_SIZE as u64 + 1).read_to_end(&mut decompressed)
+                        })
+                    };
+                    let result = result.and_then(|len| {
+                        if len > MAX_FRAME_SIZE {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!(
+                                    "decompresses to more than {} MiB, too large to index; a .zst written \
+                                     by plain `zstd` is often a single frame, recompress it with `mg build`",
+                                    MAX_FRAME_SIZE >> 20
+                                ),
+                            ));
+                        }
+                        // Keep the set at most half full. Frames larger than
+                        // ours get a set of their own, one worker at a time.
+                        let mut large_set;
+                        let (_large_frame, set) = if len > CHUNK_SIZE {
+                            let guard = large_frames.lock().unwrap_or_else(|e| e.into_inner());
+                            large_set = FastSet::new((len * 2).next_power_of_two());
+                            (Some(guard), &mut large_set)
+                        } else {
+                            if len * 2 > set_capacity {
+                                set_capacity = (len * 2).next_power_of_two();
+                                set = FastSet::new(set_capacity);
+                            }
+                            set.clear();
+                            (None, &mut set)
+                        };
+                        Ok(FrameSummary {
+                            filter: filter_spec.build_filter(&decompressed, set),
+                            checksum,
+                            len: len as u64,
+                            line_count: count_lines(&decompressed),
+                            time_range: time_detector.range(&decompressed),
+                        })
+                    });
+                    if decompressed.capacity() > CHUNK_SIZE {
+                        decompressed = Vec::new();
+                    }
+                    // The receiver is gone once the writer has failed
+                    if result_sender.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        // Write records in frame order as they become ready
+        let mut pending: HashMap<usize, FrameSummary> = HashMap::new();
+        let mut next_to_write = 0usize;
+        let (mut source_offset, mut source_line) = stream_start;
+        for (i, result) in result_receiver {
+            let summary = result.map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame {} at offset {}: {}", i, frames[i].frame_offset, e),
+                )
+            })?;
+            pending.insert(i, summary);
+            while let Some(summary) = pending.remove(&next_to_write) {
+                let frame = &frames[next_to_write];
+                // Frames read from an input keep their place in it, and the
+                // time range it was dated by if no timestamps are found now
+                let (frame_source_offset, frame_source_line) = if frame.source.is_empty() {
+                    (source_offset, source_line)
+                } else {
+                    (frame.source_offset, frame.source_line)
+                };
+                let frame_info = FrameInfo {
+                    checksum: Some(summary.checksum),
+                    source_offset: frame_source_offset,
+                    source_line: frame_source_line,
+                    uncompressed_size: summary.len,
+                    line_count: summary.line_count,
+                    time_range: summary.time_range.or(frame.time_range),
+                    ..frame.clone()
+                };
+                index_writer.write_record(&frame_info, &summary.filter)?;
+                source_offset += summary.len;
+                source_line += summary.line_count;
+                next_to_write += 1;
+            }
+        }
+        Ok((source_offset, source_line))
+    })
+}
+
+/// What a worker learned about one decompressed frame
+struct FrameSummary {
+    filter: FrameFilter,
+    /// `frame_checksum` of the compressed frame
+    checksum: u64,
+    /// Decompressed length
+    len: u64,
+    line_count: u64,
+    time_range: Option<TimeRange>,
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::{config::standard, encode_to_vec};
+
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::testutil::{log_lines, temp_dir};
+
+    fn records(idx_path: &Path) -> Vec<(FrameInfo, FrameFilter)> {
+        let index_data = fs::read(idx_path).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+        let entries = reader.by_ref().collect();
+        assert!(reader.remaining().is_empty());
+        entries
+    }
+
+    /// Rewrite the `.mg` at `idx_path` as format version 0 (no header) or 1
+    fn write_legacy(idx_path: &Path, version: u32) {
+        let entries = records(idx_path);
+        let mut out = Vec::new();
+        if version > 0 {
+            out.extend_from_slice(b"MGIX");
+            out.extend(encode_to_vec((version, IndexKind::ZstdFrames), standard()).unwrap());
+        }
+        let mut index_writer = IndexWriter::append(out);
+        for (frame, filter) in &entries {
+            index_writer.write_record(&(frame.frame_offset, frame.frame_size), filter).unwrap();
+        }
+        fs::write(idx_path, index_writer.into_inner()).unwrap();
+    }
+
+    #[test]
+    fn reindex_keeps_frames_and_fills_in_legacy_indexes() {
+        let dir = temp_dir();
+        let log_path = dir.path().join("app.log");
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = zst_path.with_extension("mg");
+        fs::write(&log_path, log_lines(0, 40_000)).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        run_build(&[log_path.display().to_string()], Some(zst), None, &BuildOptions::default()).unwrap();
+        let built: Vec<FrameInfo> = records(&idx_path).into_iter().map(|(frame, _)| frame).collect();
+        assert!(built.len() >= 3);
+        let data = fs::read(&zst_path).unwrap();
+        let time_detector = TimeDetector::default();
+
+        // A current index keeps its sources
+        run_reindex(zst, None, &time_detector, FilterSpec::default()).unwrap();
+        let reindexed = records(&idx_path);
+        assert_eq!(reindexed.len(), built.len());
+        for ((frame, _), built) in reindexed.iter().zip(&built) {
+            assert_eq!(format!("{:?}", frame), format!("{:?}", built));
+        }
+
+        // Legacy indexes have no sources, but their frames get checksums,
+        // sizes, line positions and time ranges from the data
+        for version in [0, 1] {
+            write_legacy(&idx_path, version);
+            assert!(split_header(&fs::read(&idx_path).unwrap()).unwrap().0.is_legacy_frames());
+            run_reindex(zst, None, &time_detector, FilterSpec::default()).unwrap();
+            let reindexed = records(&idx_path);
+            assert_eq!(reindexed.len(), built.len());
+            for ((frame, filter), built) in reindexed.iter().zip(&built) {
+                let expected = FrameInfo { source: String::new(), ..built.clone() };
+                assert_eq!(format!("{:?}", frame), format!("{:?}", expected));
+                let end = (frame.frame_offset + frame.frame_size) as usize;
+                let chunk = zstd::decode_all(&data[frame.frame_offset as usize..end]).unwrap();
+                assert!(FilterSpec::default().keys(&chunk).all(|key| filter.contains(&key)));
+            }
+        }
+        assert!(fs::read(&zst_path).unwrap() == data);
+    }
+}
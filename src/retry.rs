@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::storage::StorageError;
+
+/// Retry behaviour shared by all remote storage backends.
+///
+/// Each attempt gets at most `request_timeout` (less if the overall deadline
+/// is closer). Transient failures are retried with exponential backoff and
+/// full jitter; permanent ones (not found, permission denied, ...) are
+/// returned immediately.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Upper bound for a single request, including streaming the body
+    pub request_timeout: Duration,
+    /// Upper bound for all attempts together
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(60),
+            deadline: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `attempt` until it succeeds, fails permanently, or the attempt
+    /// count or deadline runs out. `attempt` receives the time it may take.
+    ///
+    /// Backends that stream data should keep what they received in state
+    /// captured by `attempt`, so a retry can resume instead of starting over.
+    pub fn run<T>(
+        &self,
+        mut attempt: impl FnMut(Duration) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        let started = Instant::now();
+        let mut backoff = self.initial_backoff;
+        let mut attempts = 0;
+
+        loop {
+            let remaining = self.deadline.saturating_sub(started.elapsed());
+            attempts += 1;
+            let err = match attempt(self.request_timeout.min(remaining)) {
+                Ok(value) => return Ok(value),
+                Err(err) if !err.is_transient() => return Err(err),
+                Err(err) => err,
+            };
+
+            let sleep = Duration::from_millis(
+                rand::thread_rng().gen_range(0..=backoff.as_millis() as u64),
+            );
+            let out_of_time = started.elapsed() + sleep >= self.deadline;
+            if attempts >= self.max_attempts || out_of_time {
+                return Err(StorageError::RetriesExhausted {
+                    attempts,
+                    last: err.to_string(),
+                });
+            }
+
+            std::thread::sleep(sleep);
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quick(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            request_timeout: Duration::from_millis(100),
+            deadline: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn transient_failures_are_retried_and_keep_progress() {
+        // Each attempt streams one more byte before the connection drops
+        let mut received = Vec::new();
+        let result = quick(5).run(|timeout| {
+            assert!(timeout <= Duration::from_millis(100));
+            received.push(received.len() as u8);
+            if received.len() < 3 {
+                return Err(StorageError::Transient("connection reset".to_string()));
+            }
+            Ok(received.clone())
+        });
+        assert_eq!(result.unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        let mut attempts = 0;
+        let result: Result<(), _> = quick(5).run(|_| {
+            attempts += 1;
+            Err(StorageError::NotFound("gs://bucket/app.zst".to_string()))
+        });
+        assert!(matches!(result, Err(StorageError::NotFound(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn retries_stop_at_the_attempt_limit_and_deadline() {
+        let result: Result<(), _> = quick(3).run(|_| Err(StorageError::Transient("503".to_string())));
+        assert!(matches!(result, Err(StorageError::RetriesExhausted { attempts: 3, .. })));
+
+        let policy = RetryPolicy { deadline: Duration::from_millis(50), ..quick(u32::MAX) };
+        let started = Instant::now();
+        let result: Result<(), _> = policy.run(|timeout| {
+            assert!(timeout <= Duration::from_millis(50));
+            Err(StorageError::Transient("timed out".to_string()))
+        });
+        assert!(matches!(result, Err(StorageError::RetriesExhausted { .. })));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
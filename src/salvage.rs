@@ -0,0 +1,436 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+
+use crate::build::{partial_path, remove_if_exists, sync_parent_dir};
+use crate::checkpoint::Checkpoint;
+use crate::index::{frame_checksum, split_header, FilterSpec, FrameInfo, IndexKind, IndexReader, IndexWriter};
+use crate::input::{Compression, MAGIC_LEN};
+use crate::reindex::{complete_frames, write_frame_records};
+use crate::timestamp::TimeDetector;
+
+/// The part of an existing `.mg` that still describes the data
+struct KeptIndex {
+    filter: FilterSpec,
+    /// Header and kept records, verbatim; empty if the index is unusable
+    bytes: Vec<u8>,
+    /// One for each of the first complete frames
+    records: Vec<FrameInfo>,
+    /// Records after the kept ones, counting one cut short
+    dropped: usize,
+}
+
+/// Main entry point for the "salvage" subcommand: cut a local `.zst` back to
+/// its last complete frame and make the `.mg` match it, keeping the records
+/// that still describe the data and indexing any complete frames past them.
+/// With only the partial files of an interrupted build around, those are
+/// salvaged and renamed into place, and the build can no longer be resumed.
+pub fn run_salvage(zst_path: &str, maybe_idx_path: Option<&str>, time_detector: &TimeDetector) -> io::Result<()> {
+    let zst = Path::new(zst_path);
+    let idx_path = match maybe_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => zst.with_extension("mg"),
+    };
+    if idx_path == zst {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "index path must differ from the data path",
+        ));
+    }
+    let from_partial = !zst.exists() && partial_path(zst).exists();
+    let (data_path, old_idx_path) = if from_partial {
+        println!("Salvaging the interrupted build {:?}", partial_path(zst));
+        (partial_path(zst), partial_path(&idx_path))
+    } else {
+        (zst.to_path_buf(), idx_path.clone())
+    };
+
+    let data_file = OpenOptions::new().read(true).write(true).open(&data_path)?;
+    let mmap = unsafe { Mmap::map(&data_file)? };
+    // Fewer bytes than a magic number are what is left of a cut-off first frame
+    if mmap.len() >= MAGIC_LEN && Compression::detect(&mmap[..MAGIC_LEN]) != Compression::Zstd {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a zstd archive; only those can be salvaged", data_path),
+        ));
+    }
+
+    let (frames, data_end) = complete_frames(&mmap);
+    let kept = kept_index(&old_idx_path, &mmap, &frames)?;
+    // A crash only ever cuts off the last frame. Complete frames after one
+    // that doesn't parse mean damage in the middle, and cutting there would
+    // throw them away.
+    if let Some(next) = next_complete_frame(&mmap[data_end..]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the frame at offset {} is damaged but a complete frame follows at offset {}; \
+                 salvaging would cut off everything from the damaged frame on, so fix it first \
+                 with `mg repair` or from a copy",
+                data_end,
+                data_end + next
+            ),
+        ));
+    }
+    let unrecorded = &frames[kept.records.len()..];
+    if data_end == mmap.len() && kept.dropped == 0 && unrecorded.is_empty() && !from_partial {
+        println!("Nothing to salvage: {:?} ends with a complete frame and {:?} matches it", zst, idx_path);
+        return Ok(());
+    }
+
+    println!("frames: {} complete", frames.len());
+    if data_end < mmap.len() {
+        println!(
+            "Dropping {} bytes of a cut-off frame at offset {}",
+            mmap.len() - data_end,
+            data_end
+        );
+    }
+    println!(
+        "index: kept {} records, dropped {}, indexing {} frames",
+        kept.records.len(),
+        kept.dropped,
+        unrecorded.len()
+    );
+
+    // The data of frames without a source follows that of the kept ones
+    let recorded_end = kept.records.iter().fold((0, 0), |(offset, line), frame| {
+        (offset + frame.uncompressed_size, line + frame.line_count)
+    });
+    let staging = staging_path(&idx_path);
+    let result = (|| {
+        let mut index_file = BufWriter::new(File::create(&staging)?);
+        let mut index_writer = if kept.bytes.is_empty() {
+            IndexWriter::create(index_file, IndexKind::ZstdFrames, kept.filter)?
+        } else {
+            index_file.write_all(&kept.bytes)?;
+            IndexWriter::append(index_file)
+        };
+        let stream_end = write_frame_records(
+            &mmap,
+            unrecorded,
+            &mut index_writer,
+            recorded_end,
+            time_detector,
+            kept.filter,
+        )?;
+        index_writer
+            .into_inner()
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok::<_, io::Error>(stream_end)
+    })();
+    let stream_end = match result {
+        Ok(stream_end) => stream_end,
+        Err(e) => {
+            let _ = fs::remove_file(&staging);
+            return Err(e);
+        }
+    };
+
+    // Data first, so the new index never points past the end of it
+    drop(mmap);
+    data_file.set_len(data_end as u64)?;
+    data_file.sync_all()?;
+    if from_partial {
+        fs::rename(&data_path, zst)?;
+    }
+    fs::rename(&staging, &idx_path)?;
+    sync_parent_dir(&idx_path)?;
+    if from_partial {
+        remove_if_exists(&old_idx_path)?;
+        remove_if_exists(&Checkpoint::path(zst))?;
+    }
+
+    report_missing(&kept.records, unrecorded.len(), recorded_end, stream_end);
+    println!("Salvage complete. ZST: {:?} | MG: {:?}", zst, idx_path);
+    Ok(())
+}
+
+/// Keep the records of `idx_path` up to the first one that doesn't match its
+/// frame in `frames`, by position, size and checksum. A missing or unreadable
+/// index keeps nothing. Records past a mismatch that still match the data
+/// mean frames were damaged rather than cut off, which is for `mg repair`.
+fn kept_index(idx_path: &Path, data: &[u8], frames: &[FrameInfo]) -> io::Result<KeptIndex> {
+    let mut kept = KeptIndex {
+        filter: FilterSpec::default(),
+        bytes: Vec::new(),
+        records: Vec::new(),
+        dropped: 0,
+    };
+    let index_data = match fs::read(idx_path) {
+        Ok(index_data) => index_data,
+        Err(e) => {
+            eprintln!("Can't read {:?} ({}); indexing every frame", idx_path, e);
+            return Ok(kept);
+        }
+    };
+    let (header, records) = match split_header(&index_data) {
+        Ok((header, _)) if header.kind != IndexKind::ZstdFrames => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} does not index a zstd archive", idx_path),
+            ));
+        }
+        Ok((header, _)) if header.is_legacy_frames() => {
+            eprintln!(
+                "{:?} has index format version {}; indexing every frame",
+                idx_path, header.version
+            );
+            return Ok(kept);
+        }
+        Ok(split) => split,
+        Err(e) => {
+            eprintln!("Can't use {:?} ({}); indexing every frame", idx_path, e);
+            return Ok(kept);
+        }
+    };
+    kept.filter = header.filter;
+
+    // Whether the data holds what `record` describes
+    let intact = |record: &FrameInfo| {
+        let end = record.frame_offset.saturating_add(record.frame_size);
+        end <= data.len() as u64
+            && record.checksum.is_none_or(|checksum| {
+                checksum == frame_checksum(&data[record.frame_offset as usize..end as usize])
+            })
+    };
+    let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+    let mut kept_len = index_data.len() - records.len();
+    let mut first_dropped = None;
+    while let Some((record, _)) = reader.next() {
+        let same_frame = frames.get(kept.records.len()).is_some_and(|frame| {
+            frame.frame_offset == record.frame_offset && frame.frame_size == record.frame_size
+        });
+        if !same_frame || !intact(&record) {
+            first_dropped = Some(record);
+            break;
+        }
+        kept_len = index_data.len() - reader.remaining().len();
+        kept.records.push(record);
+    }
+    if let Some(first_dropped) = first_dropped {
+        kept.dropped = 1;
+        for (record, _) in reader.by_ref() {
+            // A build writes in order, so a cut-off one leaves nothing
+            // intact past the first frame it lost
+            if intact(&record) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "frame {} at offset {} is damaged but frames after it are intact; \
+                         check the archive with `mg verify` and fix it with `mg repair`",
+                        kept.records.len(),
+                        first_dropped.frame_offset
+                    ),
+                ));
+            }
+            kept.dropped += 1;
+        }
+    }
+    // A record cut short doesn't decode
+    if !reader.remaining().is_empty() {
+        kept.dropped += 1;
+    }
+    kept.bytes = index_data[..kept_len].to_vec();
+    Ok(kept)
+}
+
+/// Where in `rest`, the data after the last complete frame, another complete
+/// zstd frame starts, if anywhere but at its very start
+fn next_complete_frame(rest: &[u8]) -> Option<usize> {
+    const ZSTD_MAGIC: [u8; 4] = 0xFD2F_B528u32.to_le_bytes();
+    (1..rest.len().saturating_sub(ZSTD_MAGIC.len() - 1))
+        .filter(|&pos| rest[pos..pos + ZSTD_MAGIC.len()] == ZSTD_MAGIC)
+        .find(|&pos| zstd::zstd_safe::find_frame_compressed_size(&rest[pos..]).is_ok())
+}
+
+/// Say how far into its input the salvaged data gets, from the last frame
+/// with a recorded source, so the rest can be rebuilt with `mg build --append`.
+/// `recorded_end` and `stream_end` are where the data of the recorded and of
+/// all frames ends in the decompressed `.zst`.
+fn report_missing(records: &[FrameInfo], unrecorded_frames: usize, recorded_end: (u64, u64), stream_end: (u64, u64)) {
+    let Some(last) = records.last().filter(|frame| !frame.source.is_empty()) else {
+        println!(
+            "Salvaged {} bytes ({} lines) of log data; the archive doesn't record which input they came from",
+            stream_end.0, stream_end.1
+        );
+        return;
+    };
+    let end_offset = last.source_offset + last.uncompressed_size;
+    let end_line = last.source_line + last.line_count;
+    if unrecorded_frames == 0 {
+        println!(
+            "Missing: {} from byte {} (line {}) on, and any inputs after it",
+            last.source, end_offset, end_line
+        );
+    } else {
+        println!(
+            "Missing: {} from byte {} (line {}) on, and any inputs after it, \
+             less up to {} bytes in {} frames whose input isn't recorded",
+            last.source,
+            end_offset,
+            end_line,
+            stream_end.0 - recorded_end.0,
+            unrecorded_frames
+        );
+    }
+}
+
+/// Side file holding the new index until the data has been cut
+fn staging_path(idx_path: &Path) -> PathBuf {
+    let mut name = OsString::from(idx_path.as_os_str());
+    name.push(".salvage");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::testutil::{log_lines, temp_dir};
+
+    /// Build `log` into `zst_path`, returning the index records
+    fn build(log: &[u8], zst_path: &Path) -> Vec<FrameInfo> {
+        let log_path = zst_path.with_extension("log");
+        fs::write(&log_path, log).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        run_build(&[log_path.display().to_string()], Some(zst), None, &BuildOptions::default()).unwrap();
+        records(&zst_path.with_extension("mg"))
+    }
+
+    fn records(idx_path: &Path) -> Vec<FrameInfo> {
+        let index_data = fs::read(idx_path).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+        let frames = reader.by_ref().map(|(frame_info, _)| frame_info).collect();
+        assert!(reader.remaining().is_empty());
+        frames
+    }
+
+    /// The archive holds the frames before `last` and the index describes them
+    fn assert_cut_before(zst_path: &Path, frames: &[FrameInfo], last: usize, log: &[u8]) {
+        let data = fs::read(zst_path).unwrap();
+        let kept = &frames[..last];
+        assert_eq!(data.len() as u64, frames[last].frame_offset);
+        let salvaged = records(&zst_path.with_extension("mg"));
+        assert_eq!(format!("{:?}", salvaged), format!("{:?}", kept));
+        let decompressed = zstd::decode_all(&data[..]).unwrap();
+        assert!(decompressed[..] == log[..frames[last].source_offset as usize]);
+    }
+
+    #[test]
+    fn salvage_cuts_truncated_last_frame() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let log = log_lines(0, 40_000);
+        let frames = build(&log, &zst_path);
+        assert!(frames.len() >= 3);
+
+        // A crash partway through the last frame
+        let last = frames.len() - 1;
+        let file = OpenOptions::new().write(true).open(&zst_path).unwrap();
+        file.set_len(frames[last].frame_offset + frames[last].frame_size / 2).unwrap();
+        drop(file);
+        run_salvage(zst_path.to_str().unwrap(), None, &TimeDetector::default()).unwrap();
+        assert_cut_before(&zst_path, &frames, last, &log);
+        assert!(!staging_path(&zst_path.with_extension("mg")).exists());
+
+        // Nothing left to do the second time
+        let data = fs::read(&zst_path).unwrap();
+        run_salvage(zst_path.to_str().unwrap(), None, &TimeDetector::default()).unwrap();
+        assert!(fs::read(&zst_path).unwrap() == data);
+    }
+
+    #[test]
+    fn salvage_refuses_to_cut_off_frames_after_damage() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let log = log_lines(0, 40_000);
+        let frames = build(&log, &zst_path);
+        assert!(frames.len() >= 3);
+
+        // Without an index, only the frame headers tell where frames are
+        fs::remove_file(zst_path.with_extension("mg")).unwrap();
+        let mut data = fs::read(&zst_path).unwrap();
+        data[frames[1].frame_offset as usize] ^= 0xff;
+        fs::write(&zst_path, &data).unwrap();
+        let err = run_salvage(zst_path.to_str().unwrap(), None, &TimeDetector::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let expected = format!("a complete frame follows at offset {}", frames[2].frame_offset);
+        assert!(err.to_string().contains(&expected), "{}", err);
+        assert!(fs::read(&zst_path).unwrap() == data);
+
+        // Damage to the last frame is indistinguishable from a cut-off one
+        data[frames[1].frame_offset as usize] ^= 0xff;
+        let last = frames.len() - 1;
+        data[frames[last].frame_offset as usize] ^= 0xff;
+        fs::write(&zst_path, &data).unwrap();
+        run_salvage(zst_path.to_str().unwrap(), None, &TimeDetector::default()).unwrap();
+        assert_eq!(fs::metadata(&zst_path).unwrap().len(), frames[last].frame_offset);
+    }
+
+    #[test]
+    fn salvage_indexes_frames_missing_from_index() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = zst_path.with_extension("mg");
+        let log = log_lines(0, 60_000);
+        let frames = build(&log, &zst_path);
+        assert!(frames.len() >= 4);
+
+        // The index lost its last record and a half, the data its last frame
+        let index_data = fs::read(&idx_path).unwrap();
+        let (header, record_data) = split_header(&index_data).unwrap();
+        let mut reader = IndexReader::<FrameInfo>::new(&header, record_data);
+        for _ in 0..frames.len() - 3 {
+            reader.next().unwrap();
+        }
+        let kept_len = index_data.len() - reader.remaining().len();
+        fs::write(&idx_path, &index_data[..kept_len + 20]).unwrap();
+        let last = frames.len() - 1;
+        let data = fs::read(&zst_path).unwrap();
+        fs::write(&zst_path, &data[..frames[last].frame_offset as usize + 100]).unwrap();
+
+        run_salvage(zst_path.to_str().unwrap(), None, &TimeDetector::default()).unwrap();
+        let salvaged = records(&idx_path);
+        assert_eq!(salvaged.len(), last);
+        assert_eq!(format!("{:?}", &salvaged[..last - 2]), format!("{:?}", &frames[..last - 2]));
+        // Frames indexed from the data alone have no source, only their position
+        for (frame, original) in salvaged[last - 2..].iter().zip(&frames[last - 2..]) {
+            assert_eq!((frame.frame_offset, frame.frame_size), (original.frame_offset, original.frame_size));
+            assert_eq!(
+                (frame.uncompressed_size, frame.line_count),
+                (original.uncompressed_size, original.line_count)
+            );
+        }
+        assert_eq!(fs::metadata(&zst_path).unwrap().len(), frames[last].frame_offset);
+    }
+
+    #[test]
+    fn salvage_finishes_interrupted_build() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let idx_path = zst_path.with_extension("mg");
+        let log = log_lines(0, 40_000);
+        let frames = build(&log, &zst_path);
+
+        // What a build killed while writing its last frame leaves behind
+        let last = frames.len() - 1;
+        let data = fs::read(&zst_path).unwrap();
+        fs::write(partial_path(&zst_path), &data[..frames[last].frame_offset as usize + 100]).unwrap();
+        fs::rename(&idx_path, partial_path(&idx_path)).unwrap();
+        fs::remove_file(&zst_path).unwrap();
+        fs::write(Checkpoint::path(&zst_path), b"").unwrap();
+
+        run_salvage(zst_path.to_str().unwrap(), None, &TimeDetector::default()).unwrap();
+        assert_cut_before(&zst_path, &frames, last, &log);
+        assert!(!partial_path(&zst_path).exists());
+        assert!(!partial_path(&idx_path).exists());
+        assert!(!Checkpoint::path(&zst_path).exists());
+    }
+}
@@ -1,81 +1,437 @@
-use std::io::{self, Write};
-use std::io::Cursor;
+use std::io::{self, Read, Write};
 
-use bincode::{config::standard, decode_from_std_read, Decode};
-use xorf::{BinaryFuse8, Filter};
+use crate::gzindex::{extract_chunk, AccessPoint};
+use crate::index::{frame_checksum, split_header, FrameInfo, IndexHeader, IndexKind, IndexReader};
+use crate::storage::{create_storage, LogStorage, StorageError, StorageOptions};
+use crate::timestamp::{TimeDetector, TimeRange};
 
-use crate::storage::{create_storage, StorageError};
+/// How many candidate frames ahead of the current one to hint to storage
+const PREFETCH_FRAMES: usize = 4;
 
-/// Mirror the FrameInfo from build.rs for decoding
-#[derive(Debug, Decode)]
-struct FrameInfo {
-    frame_offset: u64,
-    frame_size: u64,
+/// What `mg search` looks at and how it prints what it finds
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// Prefix every line with the source it was built from, like `grep -H`
+    pub with_filename: bool,
+    /// Prefix every line with its 1-based line number in the source, like `grep -n`
+    pub line_number: bool,
+    /// Prefix every line with the offset of its first byte in the source, like `grep -b`
+    pub byte_offset: bool,
+    /// Only output lines timestamped at or after this (ms since the epoch)
+    pub since: Option<i64>,
+    /// Only output lines timestamped before this (ms since the epoch)
+    pub until: Option<i64>,
+    /// Finds line timestamps, configured as for the build
+    pub time_detector: TimeDetector,
+}
+
+impl SearchOptions {
+    /// Whether lines get any prefix
+    fn prefixed(&self) -> bool {
+        self.with_filename || self.line_number || self.byte_offset
+    }
+
+    fn time_bounded(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+
+    fn in_window(&self, t: i64) -> bool {
+        self.since.is_none_or(|since| t >= since) && self.until.is_none_or(|until| t < until)
+    }
+
+    /// Whether a frame with `range` may hold lines in the window; frames
+    /// without a range might
+    fn overlaps(&self, range: Option<TimeRange>) -> bool {
+        range.is_none_or(|range| {
+            self.since.is_none_or(|since| range.max >= since) && self.until.is_none_or(|until| range.min < until)
+        })
+    }
+
+    /// Whether every line of a frame with `range` is in the window
+    fn covers(&self, range: Option<TimeRange>) -> bool {
+        match range {
+            Some(range) => self.in_window(range.min) && self.in_window(range.max),
+            None => !self.time_bounded(),
+        }
+    }
 }
 
 /// Main entry point for the "search" subcommand
-/// - `zst_path`: .zst file (local path or gs://bucket/path)
+/// - `zst_path`: .zst or indexed .gz file (local path or gs://bucket/path)
 /// - `maybe_idx_path`: optional index path (local path or gs://bucket/path)
 /// - `pattern_str`: string pattern to search
+/// - `search_options`: output settings
+/// - `options`: storage layers such as the block cache
 pub fn run_search(
     zst_path: &str,
     maybe_idx_path: Option<&str>,
     pattern_str: &str,
+    search_options: &SearchOptions,
+    options: &StorageOptions,
 ) -> Result<(), StorageError> {
     // Create storage backend (local or GCS)
-    let storage = create_storage(zst_path, maybe_idx_path)?;
-
-    // Convert pattern to 8-byte windows (u64 keys)
-    let pattern_bytes = pattern_str.as_bytes();
-    let mut keys = Vec::new();
-    for window in pattern_bytes.windows(8) {
-        let key = u64::from_le_bytes(window.try_into().unwrap());
-        keys.push(key);
-    }
+    let storage = create_storage(zst_path, maybe_idx_path, options)?;
 
     // Fetch index file
     let index_data = storage.fetch_index()?;
-    let mut cursor = Cursor::new(&index_data);
-    let bin_cfg = standard();
-
-    // We'll read FrameInfo + BinaryFuse8 pairs until EOF
-    loop {
-        // 1) Read FrameInfo
-        let frame_info: FrameInfo = match decode_from_std_read(&mut cursor, bin_cfg) {
-            Ok(fi) => fi,
-            Err(_err) => {
-                // Likely EOF
-                break;
+    let (header, records) = split_header(&index_data)?;
+
+    // Convert the pattern to keys the way the filters were built
+    let keys: Vec<u64> = header.filter.keys(pattern_str.as_bytes()).collect();
+    if header.is_legacy_frames() && (search_options.line_number || search_options.byte_offset) {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "this index records no line positions; rewrite it with `mg reindex` to use -n or --byte-offset",
+        )));
+    }
+    if header.kind == IndexKind::GzipAccessPoints && search_options.line_number {
+        return Err(StorageError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "line numbers are not recorded for .gz indexes; --byte-offset works",
+        )));
+    }
+
+    // Frames without a recorded source are named after the archive itself
+    let mut out = SearchOutput {
+        out: io::stdout().lock(),
+        options: search_options,
+        time_detector: search_options.time_detector.clone(),
+        archive_name: zst_path,
+    };
+    match header.kind {
+        IndexKind::ZstdFrames => search_zstd_frames(&*storage, &header, records, &keys, &mut out),
+        IndexKind::GzipAccessPoints => search_gzip_chunks(&*storage, &header, records, &keys, &mut out),
+    }
+}
+
+/// Where matching data goes, trimmed to the time window and with the
+/// per-line prefixes asked for
+struct SearchOutput<'a, W: Write> {
+    out: W,
+    options: &'a SearchOptions,
+    time_detector: TimeDetector,
+    archive_name: &'a str,
+}
+
+/// Where a piece of decompressed output came from
+struct DataOrigin<'a> {
+    /// Input the data was read from; empty if unknown
+    source: &'a str,
+    /// Offset of the data within `source`
+    offset: u64,
+    /// Lines of `source` before the data
+    line: u64,
+    /// Time range of the data's lines, if recorded
+    time_range: Option<TimeRange>,
+}
+
+impl<W: Write> SearchOutput<'_, W> {
+    /// Write decompressed data read from `origin`
+    fn write_data(&mut self, origin: &DataOrigin, data: &[u8]) -> io::Result<()> {
+        let options = self.options;
+        let trim = !options.covers(origin.time_range);
+        if !trim && !options.prefixed() {
+            return self.out.write_all(data);
+        }
+        let source = if origin.source.is_empty() { self.archive_name } else { origin.source };
+
+        // Lines without a timestamp go with the line before them; lines
+        // before the first timestamp are kept
+        self.time_detector.reset();
+        let mut keep = true;
+        let mut offset = origin.offset;
+        for (line_number, line) in (origin.line + 1..).zip(data.split_inclusive(|&c| c == b'\n')) {
+            let line_offset = offset;
+            offset += line.len() as u64;
+            if trim && let Some(t) = self.time_detector.detect(line) {
+                keep = options.in_window(t);
             }
-        };
-        
-        // 2) Read BinaryFuse filter
-        let filter: BinaryFuse8 = match decode_from_std_read(&mut cursor, bin_cfg) {
-            Ok(flt) => flt,
-            Err(_) => {
-                // Likely EOF
+            if !keep {
+                continue;
+            }
+            // Prefixes in grep's order
+            if options.with_filename {
+                write!(self.out, "{}:", source)?;
+            }
+            if options.line_number {
+                write!(self.out, "{}:", line_number)?;
+            }
+            if options.byte_offset {
+                write!(self.out, "{}:", line_offset)?;
+            }
+            self.out.write_all(line)?;
+            // Keep the next prefix at the start of a line
+            if options.prefixed() && !line.ends_with(b"\n") {
+                self.out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output every zstd frame whose filter might contain all `keys`
+fn search_zstd_frames(
+    storage: &dyn LogStorage,
+    header: &IndexHeader,
+    records: &[u8],
+    keys: &[u64],
+    out: &mut SearchOutput<impl Write>,
+) -> Result<(), StorageError> {
+    // Walk the FrameInfo + filter pairs until EOF, keeping the frames
+    // whose filter might contain all n-grams of the pattern. Frames
+    // outside the time window are dropped first.
+    let frames: Vec<FrameInfo> = IndexReader::<FrameInfo>::new(header, records)
+        .filter(|(frame_info, _)| out.options.overlaps(frame_info.time_range))
+        .filter(|(_, filter)| keys.iter().all(|key| filter.contains(key)))
+        .map(|(frame_info, _)| frame_info)
+        .collect();
+    let candidates: Vec<(u64, u64)> = frames.iter().map(|f| (f.frame_offset, f.frame_size)).collect();
+
+    // Read candidates in order, keeping a few frames of read-ahead in flight.
+    // The compressed and decompressed buffers are reused across frames.
+    storage.prefetch(&candidates[..PREFETCH_FRAMES.min(candidates.len())]);
+    let mut compressed_chunk = Vec::new();
+    let mut decompressed = Vec::new();
+    for (i, &(frame_offset, frame_size)) in candidates.iter().enumerate() {
+        if let Some(ahead) = candidates.get(i + PREFETCH_FRAMES) {
+            storage.prefetch(std::slice::from_ref(ahead));
+        }
+
+        // Damaged data must not turn into plausible log lines. A bad copy
+        // may come from a block cache or a flaky download, so it is dropped
+        // and fetched once more before giving up.
+        let frame_info = &frames[i];
+        let mut attempts = 0;
+        loop {
+            storage.read_block_into(frame_offset, frame_size, &mut compressed_chunk)?;
+            if frame_info.checksum.is_none_or(|checksum| frame_checksum(&compressed_chunk) == checksum) {
                 break;
             }
+            storage.discard_block(frame_offset, frame_size);
+            attempts += 1;
+            if attempts == 2 {
+                return Err(StorageError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "frame at offset {} is corrupt: its checksum doesn't match the index; check the archive with `mg verify`",
+                        frame_offset
+                    ),
+                )));
+            }
+        }
+
+        decompressed.clear();
+        zstd::Decoder::with_buffer(&compressed_chunk[..])
+            .and_then(|mut decoder| decoder.read_to_end(&mut decompressed))
+            .map_err(|e| StorageError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Decompression failed: {}", e)
+            )))?;
+
+        // Output the decompressed data
+        let origin = DataOrigin {
+            source: &frame_info.source,
+            offset: frame_info.source_offset,
+            line: frame_info.source_line,
+            time_range: frame_info.time_range,
         };
+        out.write_data(&origin, &decompressed)?;
+    }
 
-        // 3) Check if chunk might contain all 8-byte windows
-        let might_contain = keys.iter().all(|key| filter.contains(key));
-        if might_contain {
-            // Read the block from storage
-            let compressed_chunk = storage.read_block(frame_info.frame_offset, frame_info.frame_size)?;
-
-            // Decompress the chunk
-            let decompressed = zstd::decode_all(&compressed_chunk[..])
-                .map_err(|e| StorageError::Io(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Decompression failed: {}", e)
-                )))?;
-
-            // Output the decompressed data
-            io::stdout().write_all(&decompressed)
-                .map_err(|e| StorageError::Io(e))?;
+    Ok(())
+}
+
+/// Output every chunk of a `.gz` whose filter might contain all `keys`,
+/// inflating from the chunk's access point only
+fn search_gzip_chunks(
+    storage: &dyn LogStorage,
+    header: &IndexHeader,
+    records: &[u8],
+    keys: &[u64],
+    out: &mut SearchOutput<impl Write>,
+) -> Result<(), StorageError> {
+    let candidates: Vec<AccessPoint> = IndexReader::<AccessPoint>::new(header, records)
+        .filter(|(_, filter)| keys.iter().all(|key| filter.contains(key)))
+        .map(|(point, _)| point)
+        .collect();
+    let ranges: Vec<(u64, u64)> = candidates.iter().map(AccessPoint::compressed_range).collect();
+
+    storage.prefetch(&ranges[..PREFETCH_FRAMES.min(ranges.len())]);
+    let mut compressed_chunk = Vec::new();
+    let mut decompressed = Vec::new();
+    for (i, (point, &(offset, size))) in candidates.iter().zip(&ranges).enumerate() {
+        if let Some(ahead) = ranges.get(i + PREFETCH_FRAMES) {
+            storage.prefetch(std::slice::from_ref(ahead));
         }
+
+        storage.read_block_into(offset, size, &mut compressed_chunk)?;
+
+        decompressed.clear();
+        extract_chunk(point, &compressed_chunk, &mut decompressed).map_err(|e| {
+            StorageError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Decompression failed: {}", e),
+            ))
+        })?;
+
+        let origin = DataOrigin {
+            source: "",
+            offset: point.start,
+            line: 0,
+            time_range: None,
+        };
+        out.write_data(&origin, &decompressed)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::cache::CachedStorage;
+    use crate::storage::LocalFileStorage;
+    use crate::testutil::{log_lines, temp_dir};
+
+    /// Build `log` into `zst_path` and the `.mg` next to it
+    fn build(log: &[u8], zst_path: &Path) {
+        let log_path = zst_path.with_extension("log");
+        fs::write(&log_path, log).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        run_build(&[log_path.display().to_string()], Some(zst), None, &BuildOptions::default()).unwrap();
+    }
+
+    fn cached_storage(zst_path: &Path, cache_dir: &Path) -> CachedStorage {
+        let zst = zst_path.to_str().unwrap();
+        let local = LocalFileStorage::new(zst, None).unwrap();
+        CachedStorage::new(Box::new(local), zst, cache_dir, 1 << 30).unwrap()
+    }
+
+    /// Search `storage` for `pattern` as `mg search` does, output collected
+    fn search(storage: &dyn LogStorage, pattern: &str) -> Result<Vec<u8>, StorageError> {
+        search_with(storage, pattern, &SearchOptions::default())
+    }
+
+    fn search_with(storage: &dyn LogStorage, pattern: &str, options: &SearchOptions) -> Result<Vec<u8>, StorageError> {
+        let index_data = storage.fetch_index()?;
+        let (header, records) = split_header(&index_data)?;
+        let keys: Vec<u64> = header.filter.keys(pattern.as_bytes()).collect();
+        let mut out = SearchOutput {
+            out: Vec::new(),
+            options,
+            time_detector: TimeDetector::default(),
+            archive_name: "",
+        };
+        search_zstd_frames(storage, &header, records, &keys, &mut out)?;
+        Ok(out.out)
+    }
+
+    fn cached_blocks(cache_dir: &Path) -> Vec<std::path::PathBuf> {
+        let mut blocks: Vec<_> = fs::read_dir(cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "blk"))
+            .collect();
+        blocks.sort();
+        blocks
+    }
+
+    #[test]
+    fn damaged_cached_block_is_fetched_again() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let cache_dir = dir.path().join("cache");
+        let log = log_lines(0, 30_000);
+        build(&log, &zst_path);
+
+        // Every frame has a status=500 line
+        assert!(search(&cached_storage(&zst_path, &cache_dir), "status=500").unwrap() == log);
+        let blocks = cached_blocks(&cache_dir);
+        assert!(blocks.len() >= 2);
+        let good = fs::read(&blocks[1]).unwrap();
+        let mut bad = good.clone();
+        bad[good.len() / 2] ^= 0xff;
+        fs::write(&blocks[1], &bad).unwrap();
+
+        assert!(search(&cached_storage(&zst_path, &cache_dir), "status=500").unwrap() == log);
+        assert!(fs::read(&blocks[1]).unwrap() == good);
+    }
+
+    #[test]
+    fn corrupt_frame_fails_and_stays_uncached() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let cache_dir = dir.path().join("cache");
+        build(&log_lines(0, 30_000), &zst_path);
+        let index_data = fs::read(zst_path.with_extension("mg")).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        let frames: Vec<FrameInfo> = IndexReader::<FrameInfo>::new(&header, records)
+            .map(|(frame_info, _)| frame_info)
+            .collect();
+        assert!(frames.len() >= 2);
+
+        let mut data = fs::read(&zst_path).unwrap();
+        data[(frames[1].frame_offset + frames[1].frame_size / 2) as usize] ^= 0xff;
+        fs::write(&zst_path, &data).unwrap();
+        let e = search(&cached_storage(&zst_path, &cache_dir), "status=500").unwrap_err();
+        let expected = format!("frame at offset {} is corrupt", frames[1].frame_offset);
+        assert!(e.to_string().contains(&expected), "{}", e);
+        // The frame before the damaged one was cached, the damaged one wasn't
+        let blocks = cached_blocks(&cache_dir);
+        assert_eq!(blocks.len(), 1);
+        assert!(fs::read(&blocks[0]).unwrap()[..] == data[..frames[0].frame_size as usize]);
+    }
+
+    #[test]
+    fn lines_are_prefixed_with_their_source() {
+        let dir = temp_dir();
+        let (first, second) = (dir.path().join("a.log"), dir.path().join("b.log"));
+        fs::write(&first, log_lines(0, 30_000)).unwrap();
+        fs::write(&second, log_lines(30_000, 100)).unwrap();
+        let inputs = vec![first.display().to_string(), second.display().to_string()];
+        let zst_path = dir.path().join("day.zst");
+        run_build(&inputs, zst_path.to_str(), None, &BuildOptions::default()).unwrap();
+        let storage = LocalFileStorage::new(zst_path.to_str().unwrap(), None).unwrap();
+
+        // The second input is a frame of its own
+        let options = SearchOptions { with_filename: true, ..Default::default() };
+        let request_id = format!("id={:016x}", 30_050u64.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let mut expected = Vec::new();
+        for line in log_lines(30_000, 100).split_inclusive(|&c| c == b'\n') {
+            expected.extend_from_slice(format!("{}:", inputs[1]).as_bytes());
+            expected.extend_from_slice(line);
+        }
+        assert!(search_with(&storage, &request_id, &options).unwrap() == expected);
+    }
+
+    #[test]
+    fn line_numbers_and_offsets_are_those_of_the_source() {
+        let dir = temp_dir();
+        let zst_path = dir.path().join("app.zst");
+        let log = log_lines(0, 30_000);
+        build(&log, &zst_path);
+        let storage = LocalFileStorage::new(zst_path.to_str().unwrap(), None).unwrap();
+        let lines: Vec<&[u8]> = log.split_inclusive(|&c| c == b'\n').collect();
+
+        // Line 25001 is well past the first frame
+        let options = SearchOptions { line_number: true, byte_offset: true, ..Default::default() };
+        let request_id = format!("id={:016x}", 25_000u64.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        let out = search_with(&storage, &request_id, &options).unwrap();
+        let mut found = false;
+        for line in out.split_inclusive(|&c| c == b'\n') {
+            let text = std::str::from_utf8(line).unwrap();
+            let mut fields = text.splitn(3, ':');
+            let number: usize = fields.next().unwrap().parse().unwrap();
+            let offset: usize = fields.next().unwrap().parse().unwrap();
+            let rest = fields.next().unwrap().as_bytes();
+            assert!(lines[number - 1] == rest);
+            assert!(log[offset..offset + rest.len()] == *rest);
+            found |= number == 25_001;
+        }
+        assert!(found);
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::build::{partial_path, same_file, sync_parent_dir};
+use crate::index::{split_header, FilterSpec, FrameFilter, FrameInfo, IndexKind, IndexReader, IndexWriter};
+
+/// Which frames of an archive `mg slice` copies
+#[derive(Debug, Clone, Default)]
+pub struct SliceOptions {
+    /// Frame numbers, counting from 0
+    pub frames: Option<Range<u64>>,
+    /// Only frames that may hold lines timestamped at or after this (ms since the epoch)
+    pub since: Option<i64>,
+    /// Only frames that may hold lines timestamped before this
+    pub until: Option<i64>,
+}
+
+impl SliceOptions {
+    /// Whether frame number `i` with `frame_info` is copied. As for `mg
+    /// search`, frames without a time range might hold lines in the window.
+    fn selects(&self, i: u64, frame_info: &FrameInfo) -> bool {
+        self.frames.as_ref().is_none_or(|frames| frames.contains(&i))
+            && frame_info.time_range.is_none_or(|range| {
+                self.since.is_none_or(|since| range.max >= since) && self.until.is_none_or(|until| range.min < until)
+            })
+    }
+}
+
+/// An archive being read frame by frame to be copied from
+struct SourceArchive {
+    zst_path: PathBuf,
+    data_file: File,
+    index_data: Vec<u8>,
+}
+
+impl SourceArchive {
+    /// Open a local archive whose `.mg` sits next to it
+    fn open(zst_path: &Path) -> io::Result<Self> {
+        let idx_path = zst_path.with_extension("mg");
+        let index_data = fs::read(&idx_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("can't read the index {:?}: {}", idx_path, e)))?;
+        let (header, _) = split_header(&index_data)?;
+        if header.kind != IndexKind::ZstdFrames {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} does not index a zstd archive; only those can be sliced", idx_path),
+            ));
+        }
+        if header.is_legacy_frames() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} has index format version {}; rewrite it with `mg reindex` first",
+                    idx_path, header.version
+                ),
+            ));
+        }
+        Ok(SourceArchive {
+            zst_path: zst_path.to_path_buf(),
+            data_file: File::open(zst_path)?,
+            index_data,
+        })
+    }
+
+    fn filter_spec(&self) -> io::Result<FilterSpec> {
+        Ok(split_header(&self.index_data)?.0.filter)
+    }
+
+    /// Every record of the index, in frame order. Frames with no recorded
+    /// source are given the archive's path, as their line positions are
+    /// within its decompressed data.
+    fn records(&self) -> io::Result<impl Iterator<Item = (FrameInfo, FrameFilter)> + '_> {
+        let (header, records) = split_header(&self.index_data)?;
+        let mut reader = IndexReader::<FrameInfo>::new(&header, records);
+        // Check the whole index decodes before anything is written
+        let frames = reader.by_ref().count();
+        if !reader.remaining().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the index of {:?} is damaged after frame record {}; fix it with `mg salvage` or `mg reindex`",
+                    self.zst_path, frames
+                ),
+            ));
+        }
+        let source = self.zst_path.display().to_string();
+        Ok(IndexReader::<FrameInfo>::new(&header, records).map(move |(frame_info, filter)| {
+            if frame_info.source.is_empty() {
+                (FrameInfo { source: source.clone(), ..frame_info }, filter)
+            } else {
+                (frame_info, filter)
+            }
+        }))
+    }
+}
+
+/// A new archive being written from whole frames of another. Frames go to
+/// partial files that `finish` renames into place, data first.
+struct SubsetWriter {
+    zst_path: PathBuf,
+    idx_path: PathBuf,
+    output_file: File,
+    index_writer: IndexWriter<BufWriter<File>>,
+    frames: u64,
+    len: u64,
+}
+
+impl SubsetWriter {
+    fn create(zst_path: &Path, idx_path: &Path, filter_spec: FilterSpec) -> io::Result<Self> {
+        let output_file = File::create(partial_path(zst_path))?;
+        let index_file = BufWriter::new(File::create(partial_path(idx_path))?);
+        Ok(SubsetWriter {
+            zst_path: zst_path.to_path_buf(),
+            idx_path: idx_path.to_path_buf(),
+            output_file,
+            index_writer: IndexWriter::create(index_file, IndexKind::ZstdFrames, filter_spec)?,
+            frames: 0,
+            len: 0,
+        })
+    }
+
+    /// Copy one frame of `data_file` (with `copy_file_range` where it can)
+    /// and add its record with the offset it now has
+    fn add_frame(&mut self, mut data_file: &File, frame_info: FrameInfo, filter: &FrameFilter) -> io::Result<()> {
+        data_file.seek(SeekFrom::Start(frame_info.frame_offset))?;
+        let copied = io::copy(&mut data_file.take(frame_info.frame_size), &mut self.output_file)?;
+        if copied < frame_info.frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("frame at offset {} extends past the end of the data", frame_info.frame_offset),
+            ));
+        }
+        let frame_info = FrameInfo {
+            frame_offset: self.len,
+            ..frame_info
+        };
+        self.index_writer.write_record(&frame_info, filter)?;
+        self.len += frame_info.frame_size;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.output_file.sync_all()?;
+        self.index_writer
+            .into_inner()
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(partial_path(&self.zst_path), &self.zst_path)?;
+        fs::rename(partial_path(&self.idx_path), &self.idx_path)?;
+        sync_parent_dir(&self.idx_path)?;
+        println!("{:?}: {} frames, {} bytes", self.zst_path, self.frames, self.len);
+        Ok(())
+    }
+
+    fn abandon(self) {
+        let _ = fs::remove_file(partial_path(&self.zst_path));
+        let _ = fs::remove_file(partial_path(&self.idx_path));
+    }
+}
+
+/// Write to `writer` with `write`, finishing it if that succeeds and
+/// removing its partial files if not
+fn write_subset(
+    mut writer: SubsetWriter,
+    write: impl FnOnce(&mut SubsetWriter) -> io::Result<()>,
+) -> io::Result<()> {
+    match write(&mut writer) {
+        Ok(()) => writer.finish(),
+        Err(e) => {
+            writer.abandon();
+            Err(e)
+        }
+    }
+}
+
+/// Main entry point for the "slice" subcommand: copy the frames of a local
+/// archive chosen by `options` into a new `.zst` + `.mg` pair, without
+/// recompressing. Frames are copied whole, so lines just outside a time
+/// window come along with the frames at its edges.
+pub fn run_slice(
+    zst_path: &str,
+    out_zst_path: &str,
+    maybe_out_idx_path: Option<&str>,
+    options: &SliceOptions,
+) -> io::Result<()> {
+    let zst_path = Path::new(zst_path);
+    let out_zst_path = Path::new(out_zst_path);
+    let out_idx_path = match maybe_out_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => out_zst_path.with_extension("mg"),
+    };
+    check_outputs(zst_path, &[out_zst_path, &out_idx_path])?;
+
+    let archive = SourceArchive::open(zst_path)?;
+    let records = archive.records()?;
+    let writer = SubsetWriter::create(out_zst_path, &out_idx_path, archive.filter_spec()?)?;
+    write_subset(writer, |writer| {
+        for (i, (frame_info, filter)) in records.enumerate() {
+            if options.selects(i as u64, &frame_info) {
+                writer.add_frame(&archive.data_file, frame_info, &filter)?;
+            }
+        }
+        if writer.frames == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no frames match the selection"));
+        }
+        Ok(())
+    })?;
+    println!("Slice complete. ZST: {:?} | MG: {:?}", out_zst_path, out_idx_path);
+    Ok(())
+}
+
+/// Main entry point for the "split" subcommand: copy the frames of a local
+/// archive, in order, into numbered `.zst` + `.mg` pairs whose data stays
+/// within `max_size`, without recompressing. Parts are named after
+/// `out_zst_path` (by default the archive itself) with their number before
+/// the extension, e.g. `day.0000.zst`. A frame larger than `max_size` gets a
+/// part of its own.
+pub fn run_split(zst_path: &str, maybe_out_zst_path: Option<&str>, max_size: u64) -> io::Result<()> {
+    let zst_path = Path::new(zst_path);
+    let out_zst_path = maybe_out_zst_path.map_or(zst_path, Path::new);
+
+    let archive = SourceArchive::open(zst_path)?;
+    let filter_spec = archive.filter_spec()?;
+    let mut records = archive.records()?.peekable();
+    let mut parts = 0;
+    while records.peek().is_some() {
+        let part_zst_path = part_path(out_zst_path, parts);
+        let part_idx_path = part_zst_path.with_extension("mg");
+        check_outputs(zst_path, &[&part_zst_path, &part_idx_path])?;
+        let writer = SubsetWriter::create(&part_zst_path, &part_idx_path, filter_spec)?;
+        write_subset(writer, |writer| {
+            while let Some((frame_info, filter)) =
+                records.next_if(|(frame_info, _)| writer.frames == 0 || writer.len + frame_info.frame_size <= max_size)
+            {
+                if frame_info.frame_size > max_size {
+                    eprintln!(
+                        "frame at offset {} is {} bytes, more than --max-size; it gets a part of its own",
+                        frame_info.frame_offset, frame_info.frame_size
+                    );
+                }
+                writer.add_frame(&archive.data_file, frame_info, &filter)?;
+            }
+            Ok(())
+        })?;
+        parts += 1;
+    }
+    println!("Split complete: {} parts", parts);
+    Ok(())
+}
+
+/// `day.zst` -> `day.0003.zst`; a name without extension gets `.zst`
+fn part_path(zst_path: &Path, part: usize) -> PathBuf {
+    let ext = zst_path.extension().map_or("zst".into(), |ext| ext.to_string_lossy());
+    zst_path.with_extension(format!("{:04}.{}", part, ext))
+}
+
+/// Refuse outputs that would overwrite the archive being read
+fn check_outputs(zst_path: &Path, outputs: &[&Path]) -> io::Result<()> {
+    let idx_path = zst_path.with_extension("mg");
+    if outputs
+        .iter()
+        .any(|out| same_file(out, zst_path) || same_file(out, &idx_path))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("output would overwrite the archive {:?}; pass another --zst/--idx", zst_path),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_paths() {
+        assert_eq!(part_path(Path::new("day.zst"), 3), PathBuf::from("day.0003.zst"));
+        assert_eq!(part_path(Path::new("/data/day.zst"), 12345), PathBuf::from("/data/day.12345.zst"));
+        assert_eq!(part_path(Path::new("logs/day"), 0), PathBuf::from("logs/day.0000.zst"));
+        assert_eq!(part_path(Path::new("app.log.zst"), 1), PathBuf::from("app.log.0001.zst"));
+        assert_eq!(part_path(Path::new("day.archive"), 2), PathBuf::from("day.0002.archive"));
+    }
+}
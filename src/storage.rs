@@ -1,7 +1,13 @@
+use std::cell::OnceCell;
+use std::fs::File;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+use crate::cache::CachedStorage;
+#[cfg(feature = "gcs")]
+use crate::retry::RetryPolicy;
+
 /// Error type for storage operations
 #[derive(Error, Debug)]
 pub enum StorageError {
@@ -14,8 +20,39 @@ pub enum StorageError {
     #[error("GCS error: {0}")]
     Gcs(String),
     
+    #[cfg(feature = "gcs")]
     #[error("Invalid range: offset={offset}, size={size}")]
     InvalidRange { offset: u64, size: u64 },
+
+    #[cfg(feature = "gcs")]
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[cfg(feature = "gcs")]
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    /// A create-only upload found the object already there
+    #[cfg(feature = "gcs")]
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+
+    /// A failure worth retrying: timeouts, throttling, 5xx, dropped connections
+    #[cfg(feature = "gcs")]
+    #[error("Transient error: {0}")]
+    Transient(String),
+
+    #[cfg(feature = "gcs")]
+    #[error("Gave up after {attempts} attempts: {last}")]
+    RetriesExhausted { attempts: u32, last: String },
+}
+
+impl StorageError {
+    /// Whether retrying the same request may succeed
+    #[cfg(feature = "gcs")]
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StorageError::Transient(_))
+    }
 }
 
 /// Storage abstraction trait for reading index and data blocks
@@ -29,11 +66,32 @@ pub trait LogStorage {
     /// * `offset` - Byte offset in the compressed file
     /// * `size` - Number of bytes to read
     fn read_block(&self, offset: u64, size: u64) -> Result<Vec<u8>, StorageError>;
+
+    /// Read a block into `buf`, replacing its contents. Callers reading many
+    /// frames reuse one buffer; backends that can fill it in place override this.
+    fn read_block_into(&self, offset: u64, size: u64, buf: &mut Vec<u8>) -> Result<(), StorageError> {
+        *buf = self.read_block(offset, size)?;
+        Ok(())
+    }
+
+    /// Hint that the given `(offset, size)` blocks will be read soon.
+    /// Purely advisory; the default does nothing.
+    fn prefetch(&self, _blocks: &[(u64, u64)]) {}
+
+    /// Drop any copy of a block kept by a caching layer, e.g. one that
+    /// turned out to be damaged, so the next read fetches it afresh.
+    /// The default does nothing.
+    fn discard_block(&self, _offset: u64, _size: u64) {}
 }
 
-/// Local file system storage implementation
+/// Local file system storage implementation.
+///
+/// The data file is opened on the first read, so an index can be fetched
+/// without it, and then read with positional reads, so repeated `read_block`
+/// calls never seek or reopen it.
 pub struct LocalFileStorage {
     zst_path: PathBuf,
+    zst_file: OnceCell<File>,
     idx_path: PathBuf,
 }
 
@@ -46,8 +104,18 @@ impl LocalFileStorage {
             // Derive index path from zst path
             zst_path.with_extension("mg")
         };
-        
-        Ok(Self { zst_path, idx_path })
+
+        Ok(Self { zst_path, zst_file: OnceCell::new(), idx_path })
+    }
+
+    fn zst_file(&self) -> io::Result<&File> {
+        if let Some(zst_file) = self.zst_file.get() {
+            return Ok(zst_file);
+        }
+        let zst_file = File::open(&self.zst_path)?;
+        // Candidate frames are read in ascending offset order
+        fadvise(&zst_file, 0, 0, Advice::Sequential);
+        Ok(self.zst_file.get_or_init(|| zst_file))
     }
 }
 
@@ -55,23 +123,73 @@ impl LogStorage for LocalFileStorage {
     fn fetch_index(&self) -> Result<Vec<u8>, StorageError> {
         std::fs::read(&self.idx_path).map_err(StorageError::Io)
     }
-    
+
     fn read_block(&self, offset: u64, size: u64) -> Result<Vec<u8>, StorageError> {
-        use std::io::{Read, Seek, SeekFrom};
-        
-        let mut file = std::fs::File::open(&self.zst_path)?;
-        file.seek(SeekFrom::Start(offset))?;
-        
-        let mut buffer = vec![0u8; size as usize];
-        file.read_exact(&mut buffer)?;
-        
+        let mut buffer = Vec::new();
+        self.read_block_into(offset, size, &mut buffer)?;
         Ok(buffer)
     }
+
+    fn read_block_into(&self, offset: u64, size: u64, buf: &mut Vec<u8>) -> Result<(), StorageError> {
+        // resize() only zero-fills bytes beyond the previous length, so a
+        // reused buffer costs nothing once it has grown to the largest frame
+        buf.resize(size as usize, 0);
+        read_exact_at(self.zst_file()?, buf, offset)?;
+        Ok(())
+    }
+
+    fn prefetch(&self, blocks: &[(u64, u64)]) {
+        // A data file that can't be opened fails the reads themselves
+        let Ok(zst_file) = self.zst_file() else {
+            return;
+        };
+        for &(offset, size) in blocks {
+            fadvise(zst_file, offset, size, Advice::WillNeed);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+#[derive(Clone, Copy)]
+enum Advice {
+    Sequential,
+    WillNeed,
 }
 
+/// Page-cache hint for a byte range (`len == 0` means to end of file).
+/// Only Linux has posix_fadvise; elsewhere this is a no-op.
+#[cfg(target_os = "linux")]
+fn fadvise(file: &File, offset: u64, len: u64, advice: Advice) {
+    use std::os::fd::AsRawFd;
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+    };
+    // Advisory only: a failure just means no read-ahead
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fadvise(_file: &File, _offset: u64, _len: u64, _advice: Advice) {}
+
 #[cfg(feature = "gcs")]
 mod gcs_storage {
     use super::*;
+    use google_cloud_auth::credentials::anonymous::Builder as Anonymous;
     use google_cloud_storage::client::Storage;
     use google_cloud_storage::model_ext::ReadRange;
     use tokio::runtime::Runtime;
@@ -81,14 +199,61 @@ mod gcs_storage {
         format!("projects/_/buckets/{}", name)
     }
 
+    /// Map a client error onto the transient/permanent split used for retries
+    fn classify(context: &str, e: google_cloud_storage::Error) -> StorageError {
+        let msg = format!("{}: {}", context, e);
+        match e.http_status_code() {
+            Some(404) => StorageError::NotFound(msg),
+            Some(401 | 403) => StorageError::PermissionDenied(msg),
+            // The only precondition we set is that the object doesn't exist yet
+            Some(412) => StorageError::AlreadyExists(msg),
+            Some(408 | 429 | 500..=599) => StorageError::Transient(msg),
+            Some(_) => StorageError::Gcs(msg),
+            // No HTTP status: the connection or stream failed underneath us
+            None => StorageError::Transient(msg),
+        }
+    }
+
+    /// Endpoint override: `--gcs-endpoint` first, then `STORAGE_EMULATOR_HOST`.
+    /// Returns the endpoint URL and whether it came from the emulator variable.
+    fn resolve_endpoint(explicit: Option<&str>) -> Option<(String, bool)> {
+        let (endpoint, from_env) = match explicit {
+            Some(endpoint) => (endpoint.to_string(), false),
+            None => (std::env::var("STORAGE_EMULATOR_HOST").ok().filter(|s| !s.is_empty())?, true),
+        };
+        // The emulator variable is conventionally just host:port
+        if endpoint.contains("://") {
+            Some((endpoint, from_env))
+        } else {
+            Some((format!("http://{}", endpoint), from_env))
+        }
+    }
+
+    /// Build a client honouring the endpoint override and anonymous mode.
+    /// Emulators don't check credentials, so `STORAGE_EMULATOR_HOST` implies anonymous.
+    fn build_client(runtime: &Runtime, endpoint: Option<&(String, bool)>, anonymous: bool) -> Result<Storage, StorageError> {
+        let mut builder = Storage::builder();
+        if let Some((url, _)) = endpoint {
+            builder = builder.with_endpoint(url);
+        }
+        if anonymous || endpoint.is_some_and(|(_, from_env)| *from_env) {
+            builder = builder.with_credentials(Anonymous::new().build());
+        }
+        runtime
+            .block_on(builder.build())
+            .map_err(|e| StorageError::Gcs(format!("Failed to build GCS client: {}", e)))
+    }
+
     /// GCS storage implementation using google-cloud-storage crate. Async is confined here; trait remains sync via block_on.
     pub struct GcsStorage {
         bucket: String,
         zst_object: String,
         idx_object: String,
         cache_dir: Option<PathBuf>,
+        endpoint: Option<String>,
         client: Storage,
         runtime: Runtime,
+        retry: RetryPolicy,
     }
 
     impl GcsStorage {
@@ -97,6 +262,7 @@ mod gcs_storage {
             zst_object: &str,
             idx_object: Option<&str>,
             cache_dir: Option<PathBuf>,
+            options: &StorageOptions,
         ) -> Result<Self, StorageError> {
             let idx_object = if let Some(idx) = idx_object {
                 idx.to_string()
@@ -107,18 +273,18 @@ mod gcs_storage {
             };
 
             let runtime = Runtime::new().map_err(|e| StorageError::Gcs(format!("Failed to create runtime: {}", e)))?;
-            let client = runtime
-                .block_on(async {
-                    Storage::builder().build().await.map_err(|e| StorageError::Gcs(format!("Failed to build GCS client: {}", e)))
-                })?;
+            let endpoint = resolve_endpoint(options.gcs_endpoint.as_deref());
+            let client = build_client(&runtime, endpoint.as_ref(), options.gcs_anonymous)?;
 
             Ok(Self {
                 bucket: bucket.to_string(),
                 zst_object: zst_object.to_string(),
                 idx_object,
                 cache_dir,
+                endpoint: endpoint.map(|(url, _)| url),
                 client,
                 runtime,
+                retry: options.retry.clone(),
             })
         }
 
@@ -130,39 +296,148 @@ mod gcs_storage {
                 home
             });
             let sanitized = self.idx_object.replace('/', "_");
+            // Keep indexes fetched from an emulator apart from the real buckets
+            let cache_base = match &self.endpoint {
+                Some(url) => cache_base.join(url.replace([':', '/'], "_")),
+                None => cache_base,
+            };
             cache_base.join(&self.bucket).join(sanitized)
         }
 
         /// Download full object or a byte range. Runs async GCS calls via block_on.
+        ///
+        /// Retries follow `self.retry`; bytes already streamed survive a failed
+        /// attempt and the next one only asks for the remainder.
         fn download(&self, object: &str, range: Option<(u64, u64)>) -> Result<Vec<u8>, StorageError> {
             let bucket = bucket_resource(&self.bucket);
-            let object = object.to_string();
-            let client = self.client.clone();
+            let mut contents = Vec::new();
 
-            let fut = async move {
-                let mut request = client.read_object(&bucket, &object);
-                if let Some((offset, count)) = range {
-                    request = request.set_read_range(ReadRange::segment(offset, count));
-                }
-                let mut resp = request.send().await.map_err(|e| StorageError::Gcs(format!("GCS read failed: {}", e)))?;
-                let mut contents = Vec::new();
-                while let Some(chunk) = resp.next().await.transpose().map_err(|e| StorageError::Gcs(format!("GCS stream error: {}", e)))? {
-                    contents.extend_from_slice(&chunk);
+            self.retry.run(|timeout| {
+                let received = contents.len() as u64;
+                let read_range = match range {
+                    Some((offset, count)) => ReadRange::segment(offset + received, count - received),
+                    None => ReadRange::offset(received),
+                };
+                let contents = &mut contents;
+                let fut = async {
+                    let mut resp = self.client
+                        .read_object(&bucket, object)
+                        .set_read_range(read_range)
+                        .send()
+                        .await
+                        .map_err(|e| classify("GCS read failed", e))?;
+                    while let Some(chunk) = resp.next().await.transpose().map_err(|e| classify("GCS stream error", e))? {
+                        contents.extend_from_slice(&chunk);
+                    }
+                    Ok(())
+                };
+                self.runtime.block_on(async {
+                    tokio::time::timeout(timeout, fut).await.unwrap_or_else(|_| {
+                        Err(StorageError::Transient(format!("GCS read of {} timed out after {:?}", object, timeout)))
+                    })
+                })
+            })?;
+
+            Ok(contents)
+        }
+    }
+
+    impl GcsStorage {
+        /// Upload data first and index last; see `storage::upload_archive`
+        pub fn upload_archive(&self, zst_path: &Path, idx_path: &Path, overwrite: bool) -> Result<(), StorageError> {
+            self.upload(zst_path, &self.zst_object, overwrite)?;
+            self.upload(idx_path, &self.idx_object, overwrite)
+        }
+
+        /// Resumable upload of one file, verified against its local size and CRC32C
+        fn upload(&self, path: &Path, object: &str, overwrite: bool) -> Result<(), StorageError> {
+            let (size, crc) = file_crc32c(path)?;
+            let bucket = bucket_resource(&self.bucket);
+            println!("Uploading {} -> gs://{}/{} ({} bytes)", path.display(), self.bucket, object, size);
+
+            // An upload may well take longer than `request_timeout`, so only
+            // the attempt count and the deadline apply. A failed attempt
+            // sends the whole file again.
+            let result = self.retry.run(|_timeout| {
+                self.runtime.block_on(async {
+                    let payload = tokio::fs::File::open(path).await?;
+                    let mut request = self.client.write_object(&bucket, object, payload);
+                    if !overwrite {
+                        // Only create; never replace an object someone else may be reading
+                        request = request.set_if_generation_match(0);
+                    }
+                    request
+                        .send_buffered()
+                        .await
+                        .map_err(|e| classify("GCS upload failed", e))
+                })
+            });
+            let uploaded = match result {
+                // An attempt whose response got lost may have created the
+                // object already; that is a success if it holds this file
+                Err(StorageError::AlreadyExists(msg)) => {
+                    let (remote_size, remote_crc) = self.object_checksum(object)?;
+                    if remote_size == size && remote_crc == Some(crc) {
+                        println!("gs://{}/{} already holds {}", self.bucket, object, path.display());
+                        return Ok(());
+                    }
+                    return Err(StorageError::AlreadyExists(msg));
                 }
-                Ok(contents)
+                result => result?,
             };
 
-            self.runtime.block_on(fut)
+            if uploaded.size as u64 != size {
+                return Err(StorageError::Gcs(format!(
+                    "Size mismatch after upload of {}: local {} bytes, remote {} bytes", object, size, uploaded.size
+                )));
+            }
+            match uploaded.checksums.as_ref().and_then(|c| c.crc32c) {
+                Some(remote) if remote != crc => Err(StorageError::Gcs(format!(
+                    "CRC32C mismatch after upload of {}: local {:08x}, remote {:08x}", object, crc, remote
+                ))),
+                _ => Ok(()),
+            }
+        }
+
+        /// Size and CRC32C of an existing object, from the response headers
+        /// of a read whose body is never fetched
+        fn object_checksum(&self, object: &str) -> Result<(u64, Option<u32>), StorageError> {
+            let bucket = bucket_resource(&self.bucket);
+            let object_info = self.retry.run(|timeout| {
+                self.runtime.block_on(async {
+                    match tokio::time::timeout(timeout, self.client.read_object(&bucket, object).send()).await {
+                        Ok(resp) => resp.map(|resp| resp.object()).map_err(|e| classify("GCS read failed", e)),
+                        Err(_) => Err(StorageError::Transient(format!(
+                            "GCS read of {} timed out after {:?}", object, timeout
+                        ))),
+                    }
+                })
+            })?;
+            Ok((object_info.size as u64, object_info.checksums.and_then(|c| c.crc32c)))
+        }
+    }
+
+    /// Size and CRC32C of a local file, read in 1MB pieces
+    fn file_crc32c(path: &Path) -> Result<(u64, u32), StorageError> {
+        use std::io::Read;
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; 1 << 20];
+        let (mut size, mut crc) = (0u64, 0u32);
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok((size, crc));
+            }
+            crc = crc32c::crc32c_append(crc, &buf[..n]);
+            size += n as u64;
         }
     }
 
     impl LogStorage for GcsStorage {
         fn fetch_index(&self) -> Result<Vec<u8>, StorageError> {
             let cache_path = self.cache_path();
-            if cache_path.exists() {
-                if let Ok(data) = std::fs::read(&cache_path) {
-                    return Ok(data);
-                }
+            if let Ok(data) = std::fs::read(&cache_path) {
+                return Ok(data);
             }
             let data = self.download(&self.idx_object, None)?;
             if let Some(parent) = cache_path.parent() {
@@ -184,43 +459,52 @@ mod gcs_storage {
 #[cfg(feature = "gcs")]
 use gcs_storage::GcsStorage;
 
-/// Create appropriate storage backend based on URL/path
-pub fn create_storage(zst_path: &str, idx_path: Option<&str>) -> Result<Box<dyn LogStorage>, StorageError> {
+/// Optional behaviour layered on top of the storage backend
+#[derive(Default)]
+pub struct StorageOptions {
+    /// Directory and size budget (bytes) of the on-disk block cache
+    pub block_cache: Option<(PathBuf, u64)>,
+    /// Retry behaviour of remote backends
+    #[cfg(feature = "gcs")]
+    pub retry: RetryPolicy,
+    /// GCS endpoint override, e.g. a local fake-gcs-server
+    #[cfg(feature = "gcs")]
+    pub gcs_endpoint: Option<String>,
+    /// Send GCS requests without credentials
+    #[cfg(feature = "gcs")]
+    pub gcs_anonymous: bool,
+}
+
+/// Create appropriate storage backend based on URL/path, wrapped in any
+/// layers requested by `options`
+pub fn create_storage(
+    zst_path: &str,
+    idx_path: Option<&str>,
+    options: &StorageOptions,
+) -> Result<Box<dyn LogStorage>, StorageError> {
+    let storage = create_backend(zst_path, idx_path, options)?;
+    match &options.block_cache {
+        Some((dir, budget)) => Ok(Box::new(CachedStorage::new(storage, zst_path, dir, *budget)?)),
+        None => Ok(storage),
+    }
+}
+
+fn create_backend(
+    zst_path: &str,
+    idx_path: Option<&str>,
+    options: &StorageOptions,
+) -> Result<Box<dyn LogStorage>, StorageError> {
     // Check if it's a GCS URL
     if zst_path.starts_with("gs://") {
         #[cfg(not(feature = "gcs"))]
         {
-            return Err(StorageError::Gcs(
-                "GCS support not enabled. Build with --features gcs".to_string()
-            ));
+            let _ = (idx_path, options);
+            Err(gcs_disabled())
         }
-        
+
         #[cfg(feature = "gcs")]
         {
-            // Parse gs://bucket/path/to/file.zst
-            let path = &zst_path[5..]; // Remove "gs://"
-            let parts: Vec<&str> = path.splitn(2, '/').collect();
-            if parts.len() != 2 {
-                return Err(StorageError::InvalidUrl(format!(
-                    "Invalid GCS URL format: {}", zst_path
-                )));
-            }
-            
-            let bucket = parts[0];
-            let zst_object = parts[1];
-            
-            // Parse index path if provided
-            let idx_object = idx_path.map(|p| {
-                if p.starts_with("gs://") {
-                    let idx_path = &p[5..];
-                    idx_path.splitn(2, '/').nth(1).unwrap_or(idx_path).to_string()
-                } else {
-                    p.to_string()
-                }
-            });
-            
-            let storage = GcsStorage::new(bucket, zst_object, idx_object.as_deref(), None)?;
-            Ok(Box::new(storage))
+            Ok(Box::new(open_gcs(zst_path, idx_path, options)?))
         }
     } else {
         // Local file
@@ -229,3 +513,74 @@ pub fn create_storage(zst_path: &str, idx_path: Option<&str>) -> Result<Box<dyn
     }
 }
 
+/// Upload a locally built archive to `dest` (a `gs://` URL for the `.zst`).
+///
+/// The data object goes first and the index last, so a reader that finds the
+/// index always finds matching data. Unless `overwrite` is set, existing
+/// objects are left alone and the upload fails instead.
+pub fn upload_archive(
+    zst_path: &Path,
+    idx_path: &Path,
+    dest: &str,
+    dest_idx: Option<&str>,
+    overwrite: bool,
+    options: &StorageOptions,
+) -> Result<(), StorageError> {
+    check_upload_destination(dest)?;
+
+    #[cfg(not(feature = "gcs"))]
+    {
+        let _ = (zst_path, idx_path, dest_idx, overwrite, options);
+        Err(gcs_disabled())
+    }
+
+    #[cfg(feature = "gcs")]
+    {
+        let storage = open_gcs(dest, dest_idx, options)?;
+        storage.upload_archive(zst_path, idx_path, overwrite)
+    }
+}
+
+/// Check that `dest` is a `gs://bucket/object` URL this build can upload to,
+/// so `mg build -z gs://...` fails before building rather than after
+pub fn check_upload_destination(dest: &str) -> Result<(), StorageError> {
+    let object = dest.strip_prefix("gs://").and_then(|path| path.split_once('/')).map(|(_, object)| object);
+    if object.is_none_or(str::is_empty) {
+        return Err(StorageError::InvalidUrl(format!(
+            "Upload destination must be a gs://bucket/object URL: {}", dest
+        )));
+    }
+
+    #[cfg(not(feature = "gcs"))]
+    {
+        Err(gcs_disabled())
+    }
+
+    #[cfg(feature = "gcs")]
+    {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "gcs"))]
+fn gcs_disabled() -> StorageError {
+    StorageError::Gcs("GCS support not enabled. Build with --features gcs".to_string())
+}
+
+/// Parse gs://bucket/path/to/file.zst (and an optional index URL or object name)
+#[cfg(feature = "gcs")]
+fn open_gcs(zst_url: &str, idx_path: Option<&str>, options: &StorageOptions) -> Result<GcsStorage, StorageError> {
+    let Some((bucket, zst_object)) = zst_url.strip_prefix("gs://").and_then(|path| path.split_once('/')) else {
+        return Err(StorageError::InvalidUrl(format!(
+            "Invalid GCS URL format: {}", zst_url
+        )));
+    };
+
+    // Parse index path if provided
+    let idx_object = idx_path.map(|p| match p.strip_prefix("gs://") {
+        Some(idx_path) => idx_path.split_once('/').map_or(idx_path, |(_, object)| object).to_string(),
+        None => p.to_string(),
+    });
+
+    GcsStorage::new(bucket, zst_object, idx_object.as_deref(), None, options)
+}
@@ -0,0 +1,52 @@
+//! Helpers shared by the unit tests
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fresh directory under the system temp dir, removed with its contents on drop
+pub struct TempDir(PathBuf);
+
+pub fn temp_dir() -> TempDir {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "mg-test-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&path).unwrap();
+    TempDir(path)
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `count` log lines, numbered from `first`, each with an ISO 8601 timestamp
+/// a second after the one before and a request id unique to the line
+pub fn log_lines(first: usize, count: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    for i in first..first + count {
+        data.extend_from_slice(
+            format!(
+                "2026-10-01T{:02}:{:02}:{:02}Z host{} request id={:016x} status={}\n",
+                i / 3600 % 24,
+                i / 60 % 60,
+                i % 60,
+                i % 7,
+                (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15),
+                [200, 404, 500][i % 3]
+            )
+            .as_bytes(),
+        );
+    }
+    data
+}
@@ -0,0 +1,532 @@
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use bincode::{Decode, Encode};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use regex::bytes::Regex;
+
+/// How far into a line to look for a timestamp
+const SCAN_LIMIT: usize = 256;
+
+/// Plausible epoch milliseconds: years 2000 through 2099
+const EPOCH_MS_RANGE: std::ops::Range<i64> = 946_684_800_000..4_102_444_800_000;
+
+const MONTHS: [&[u8; 3]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+
+/// Earliest and latest timestamp of a frame, in milliseconds since the epoch (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct TimeRange {
+    pub min: i64,
+    pub max: i64,
+}
+
+/// A timestamp layout recognized without configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinFormat {
+    /// `2026-10-01T12:34:56.789+02:00`, also with a space for `T`, without
+    /// seconds, fraction or zone; RFC 3339 is a subset
+    Iso8601,
+    /// Apache Common Log Format: `[10/Oct/2026:13:55:36 -0700]`
+    Clf,
+    /// BSD syslog: `Oct 11 22:14:15`, which has no year; lines are dated by
+    /// a given year or by when their input was last written, and have no
+    /// timestamp without either
+    Syslog,
+    /// 13-digit milliseconds since the epoch
+    EpochMillis,
+}
+
+/// Tried in this order when detecting the format of a chunk
+const AUTO_ORDER: [BuiltinFormat; 4] = [
+    BuiltinFormat::Iso8601,
+    BuiltinFormat::Clf,
+    BuiltinFormat::Syslog,
+    BuiltinFormat::EpochMillis,
+];
+
+/// How the timestamp of a log line is found. Timestamps without a zone are
+/// taken as UTC.
+#[derive(Debug, Clone, Default)]
+pub enum TimeFormat {
+    /// Detect a builtin format on the first line that has one, then stick to it
+    #[default]
+    Auto,
+    Builtin(BuiltinFormat),
+    /// A chrono strftime format, parsed at the start of the line
+    Strftime(String),
+}
+
+impl FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "auto" => TimeFormat::Auto,
+            "iso8601" | "rfc3339" => TimeFormat::Builtin(BuiltinFormat::Iso8601),
+            "clf" => TimeFormat::Builtin(BuiltinFormat::Clf),
+            "syslog" => TimeFormat::Builtin(BuiltinFormat::Syslog),
+            "epoch-ms" => TimeFormat::Builtin(BuiltinFormat::EpochMillis),
+            _ if s.contains('%') => TimeFormat::Strftime(s.to_string()),
+            _ => {
+                return Err(format!(
+                    "unknown time format {:?} (expected auto, iso8601, rfc3339, clf, syslog, epoch-ms or a strftime format)",
+                    s
+                ));
+            }
+        })
+    }
+}
+
+/// Finds the timestamps of log lines, per `TimeFormat` and optionally within
+/// the first capture group (or the whole match) of a regex
+#[derive(Debug, Clone, Default)]
+pub struct TimeDetector {
+    format: TimeFormat,
+    regex: Option<Regex>,
+    /// Format picked by `TimeFormat::Auto`
+    detected: Option<BuiltinFormat>,
+    /// Year of syslog timestamps, if given
+    syslog_year: Option<i32>,
+    /// When the input being read was last written (ms since the epoch),
+    /// which dates syslog timestamps without a given year
+    last_write: Option<i64>,
+}
+
+impl TimeDetector {
+    pub fn new(format: TimeFormat, regex: Option<&str>, syslog_year: Option<i32>) -> Result<Self, String> {
+        let regex = regex
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("invalid --time-regex: {}", e))?;
+        Ok(Self {
+            format,
+            regex,
+            detected: None,
+            syslog_year,
+            last_write: None,
+        })
+    }
+
+    /// Date the syslog timestamps of the input about to be read by when it
+    /// was last written: in that year, or the one before for dates after it.
+    /// Without this or a given year they are ignored, so that frames of old
+    /// files don't get time ranges a year off and get skipped by searches.
+    pub fn set_last_write(&mut self, last_write: Option<SystemTime>) {
+        self.last_write = last_write.map(|t| DateTime::<Utc>::from(t).timestamp_millis());
+    }
+
+    /// Latest time a syslog timestamp can stand for
+    fn syslog_latest(&self) -> Option<i64> {
+        match self.syslog_year {
+            Some(year) => NaiveDate::from_ymd_opt(year + 1, 1, 1)
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|t| t.and_utc().timestamp_millis() - 1),
+            None => self.last_write,
+        }
+    }
+
+    /// Forget the format detected so far, e.g. before a chunk of another input
+    pub fn reset(&mut self) {
+        self.detected = None;
+    }
+
+    /// Earliest and latest timestamp of the lines of a chunk, detecting its
+    /// format afresh
+    pub fn range(&mut self, chunk: &[u8]) -> Option<TimeRange> {
+        self.reset();
+        let mut range: Option<TimeRange> = None;
+        for t in chunk.split(|&c| c == b'\n').filter_map(|line| self.detect(line)) {
+            range = Some(match range {
+                Some(TimeRange { min, max }) => TimeRange { min: min.min(t), max: max.max(t) },
+                None => TimeRange { min: t, max: t },
+            });
+        }
+        range
+    }
+
+    /// Timestamp of `line` in milliseconds since the epoch, if it has one
+    pub fn detect(&mut self, line: &[u8]) -> Option<i64> {
+        let text = match &self.regex {
+            Some(regex) => {
+                let captures = regex.captures(line)?;
+                captures.get(1).or_else(|| captures.get(0))?.as_bytes()
+            }
+            None => &line[..line.len().min(SCAN_LIMIT)],
+        };
+        match &self.format {
+            TimeFormat::Auto => match self.detected {
+                Some(format) => self.find(format, text),
+                None => {
+                    let (format, t) = AUTO_ORDER
+                        .iter()
+                        .find_map(|&format| Some((format, self.find(format, text)?)))?;
+                    self.detected = Some(format);
+                    Some(t)
+                }
+            },
+            TimeFormat::Builtin(format) => self.find(*format, text),
+            TimeFormat::Strftime(format) => parse_strftime(text, format),
+        }
+    }
+
+    /// Look for a `format` timestamp anywhere in `text`
+    fn find(&self, format: BuiltinFormat, text: &[u8]) -> Option<i64> {
+        match format {
+            BuiltinFormat::Iso8601 => starts(text, |b| b.is_ascii_digit()).find_map(|i| Some(parse_iso(&text[i..])?.0)),
+            BuiltinFormat::EpochMillis => starts(text, |b| b.is_ascii_digit()).find_map(|i| parse_epoch_ms(&text[i..])),
+            BuiltinFormat::Clf => positions_of(b'[', text).find_map(|i| parse_clf(&text[i + 1..])),
+            BuiltinFormat::Syslog => {
+                let latest = self.syslog_latest()?;
+                starts(text, |b| b.is_ascii_uppercase()).find_map(|i| parse_syslog(&text[i..], latest))
+            }
+        }
+    }
+}
+
+/// Positions where a run of bytes matching `class` begins
+fn starts(text: &[u8], class: impl Fn(u8) -> bool + Copy) -> impl Iterator<Item = usize> {
+    (0..text.len()).filter(move |&i| class(text[i]) && (i == 0 || !class(text[i - 1])))
+}
+
+fn positions_of(needle: u8, text: &[u8]) -> impl Iterator<Item = usize> {
+    text.iter().enumerate().filter(move |&(_, &b)| b == needle).map(|(i, _)| i)
+}
+
+/// Parse a `--since`/`--until` argument: a date, or a date and time as in
+/// ISO 8601 (`2026-10-01`, `2026-10-01T12:00`, `2026-10-01 12:00:00+02:00`)
+pub fn parse_time_bound(s: &str) -> Result<i64, String> {
+    let bytes = s.trim().as_bytes();
+    let parsed = match parse_iso(bytes) {
+        Some((t, n)) if n == bytes.len() => Some(t),
+        _ if bytes.len() == 10 => parse_date(bytes)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|t| t.and_utc().timestamp_millis()),
+        _ => None,
+    };
+    parsed.ok_or_else(|| format!("invalid time {:?} (expected e.g. 2026-10-01 or 2026-10-01T12:00)", s))
+}
+
+/// Parse `digits` ASCII digits at the start of `b`
+fn number(b: &[u8], digits: usize) -> Option<u32> {
+    let field = b.get(..digits)?;
+    field.iter().try_fold(0u32, |n, &c| {
+        c.is_ascii_digit().then(|| n * 10 + u32::from(c - b'0'))
+    })
+}
+
+/// `YYYY-MM-DD`
+fn parse_date(b: &[u8]) -> Option<NaiveDate> {
+    if b.get(4) != Some(&b'-') || b.get(7) != Some(&b'-') {
+        return None;
+    }
+    NaiveDate::from_ymd_opt(number(b, 4)? as i32, number(&b[5..], 2)?, number(&b[8..], 2)?)
+}
+
+/// ISO 8601 date and time at the start of `b`; returns the timestamp and the
+/// number of bytes it took up
+fn parse_iso(b: &[u8]) -> Option<(i64, usize)> {
+    let date = parse_date(b)?;
+    if !matches!(b.get(10), Some(b'T' | b' ')) || b.get(13) != Some(&b':') {
+        return None;
+    }
+    let (hour, minute) = (number(&b[11..], 2)?, number(&b[14..], 2)?);
+    let mut pos = 16;
+    let mut second = 0;
+    let mut nanos = 0;
+    if b.get(pos) == Some(&b':') {
+        second = number(&b[pos + 1..], 2)?;
+        pos += 3;
+        if matches!(b.get(pos), Some(b'.' | b',')) {
+            let digits = b[pos + 1..].iter().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 {
+                return None;
+            }
+            let used = digits.min(9);
+            nanos = number(&b[pos + 1..], used)? * 10u32.pow(9 - used as u32);
+            pos += 1 + digits;
+        }
+    }
+    let local = date.and_hms_nano_opt(hour, minute, second, nanos)?;
+
+    // Zone: Z, +HH:MM, +HHMM or +HH; none means UTC
+    let offset_secs = match b.get(pos) {
+        Some(b'Z') => {
+            pos += 1;
+            0
+        }
+        Some(&sign @ (b'+' | b'-')) => {
+            let hours = number(&b[pos + 1..], 2)?;
+            pos += 3;
+            let minutes = if b.get(pos) == Some(&b':') {
+                let minutes = number(&b[pos + 1..], 2)?;
+                pos += 3;
+                minutes
+            } else if let Some(minutes) = number(&b[pos..], 2) {
+                pos += 2;
+                minutes
+            } else {
+                0
+            };
+            let secs = (hours * 3600 + minutes * 60) as i64;
+            if sign == b'-' { -secs } else { secs }
+        }
+        _ => 0,
+    };
+    Some((local.and_utc().timestamp_millis() - offset_secs * 1000, pos))
+}
+
+/// `10/Oct/2026:13:55:36 -0700]`, following the opening bracket
+fn parse_clf(b: &[u8]) -> Option<i64> {
+    if b.get(2) != Some(&b'/') || b.get(6) != Some(&b'/') || b.get(11) != Some(&b':') {
+        return None;
+    }
+    let day = number(b, 2)?;
+    let month = month_number(b.get(3..6)?)?;
+    let year = number(&b[7..], 4)? as i32;
+    let time = &b[12..];
+    if time.get(2) != Some(&b':') || time.get(5) != Some(&b':') {
+        return None;
+    }
+    let local = NaiveDate::from_ymd_opt(year, month, day)?
+        .and_hms_opt(number(time, 2)?, number(&time[3..], 2)?, number(&time[6..], 2)?)?;
+    let zone = time.get(8..14)?;
+    let offset_secs = match zone {
+        [b' ', sign @ (b'+' | b'-'), ..] => {
+            let secs = (number(&zone[2..], 2)? * 3600 + number(&zone[4..], 2)? * 60) as i64;
+            if *sign == b'-' { -secs } else { secs }
+        }
+        _ => return None,
+    };
+    Some(local.and_utc().timestamp_millis() - offset_secs * 1000)
+}
+
+/// `Oct 11 22:14:15` (single-digit days padded with a space or a zero), in
+/// the year of `latest` or, if that would be later than it, the year before
+fn parse_syslog(b: &[u8], latest: i64) -> Option<i64> {
+    let month = month_number(b.get(..3)?)?;
+    if b.get(3) != Some(&b' ') {
+        return None;
+    }
+    let day = match b.get(4..6)? {
+        [b' ', d] if d.is_ascii_digit() => u32::from(d - b'0'),
+        _ => number(&b[4..], 2)?,
+    };
+    let time = b.get(6..)?;
+    if time.first() != Some(&b' ') || time.get(3) != Some(&b':') || time.get(6) != Some(&b':') {
+        return None;
+    }
+    let at = |year| {
+        NaiveDate::from_ymd_opt(year, month, day)?
+            .and_hms_opt(number(&time[1..], 2)?, number(&time[4..], 2)?, number(&time[7..], 2)?)
+            .map(|t| t.and_utc().timestamp_millis())
+    };
+    // December lines in a file last written in January are from the year
+    // before; a day's slack allows for time zones
+    let year = DateTime::from_timestamp_millis(latest)?.year();
+    let t = at(year)?;
+    if t > latest + 86_400_000 { at(year - 1) } else { Some(t) }
+}
+
+/// A run of exactly 13 digits holding a plausible time
+fn parse_epoch_ms(b: &[u8]) -> Option<i64> {
+    if b.get(13).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    let t = b.get(..13)?.iter().try_fold(0i64, |n, &c| {
+        c.is_ascii_digit().then(|| n * 10 + i64::from(c - b'0'))
+    })?;
+    EPOCH_MS_RANGE.contains(&t).then_some(t)
+}
+
+fn month_number(name: &[u8]) -> Option<u32> {
+    MONTHS.iter().position(|m| &m[..] == name).map(|i| i as u32 + 1)
+}
+
+/// A strftime timestamp at the start of `text`, with or without a zone
+fn parse_strftime(text: &[u8], format: &str) -> Option<i64> {
+    let text = std::str::from_utf8(text)
+        .or_else(|e| std::str::from_utf8(&text[..e.valid_up_to()]))
+        .ok()?;
+    if let Ok((t, _)) = DateTime::<FixedOffset>::parse_and_remainder(text, format) {
+        return Some(t.timestamp_millis());
+    }
+    let (t, _) = NaiveDateTime::parse_and_remainder(text, format).ok()?;
+    Some(t.and_utc().timestamp_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(rfc3339: &str) -> i64 {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn iso8601_zones_and_fractions() {
+        let cases: [(&str, &str, usize); 10] = [
+            ("2026-10-01T12:34:56Z", "2026-10-01T12:34:56Z", 20),
+            ("2026-10-01T12:34:56+02:00 x", "2026-10-01T12:34:56+02:00", 25),
+            ("2026-10-01T12:34:56-0730", "2026-10-01T12:34:56-07:30", 24),
+            ("2026-10-01T12:34:56+02", "2026-10-01T12:34:56+02:00", 22),
+            ("2026-10-01T12:34:56.789Z", "2026-10-01T12:34:56.789Z", 24),
+            ("2026-10-01T12:34:56,5+01:00", "2026-10-01T12:34:56.5+01:00", 27),
+            ("2026-10-01T12:34:56.123456789123Z", "2026-10-01T12:34:56.123Z", 33),
+            ("2026-10-01 12:34:56 level=info", "2026-10-01T12:34:56Z", 19),
+            ("2026-10-01T12:34 x", "2026-10-01T12:34:00Z", 16),
+            ("2024-02-29T00:00:00Z", "2024-02-29T00:00:00Z", 20),
+        ];
+        for (text, expected, len) in cases {
+            assert_eq!(parse_iso(text.as_bytes()), Some((ms(expected), len)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn iso8601_rejects_malformed() {
+        for text in [
+            "2026-13-01T00:00:00Z",
+            "2025-02-29T00:00:00Z",
+            "2026-10-01T24:00:00Z",
+            "2026-10-01T12:60",
+            "2026-10-01X12:34:56",
+            "2026-10-01T12:34:56.Z",
+            "2026-10-01T12:34:56+x",
+            "2026-10-01",
+            "2026/10/01T12:34:56",
+            "26-10-01T12:34:56",
+        ] {
+            assert_eq!(parse_iso(text.as_bytes()), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn clf() {
+        assert_eq!(parse_clf(b"10/Oct/2026:13:55:36 -0700] \"GET /"), Some(ms("2026-10-10T13:55:36-07:00")));
+        assert_eq!(parse_clf(b"01/Jan/2026:00:00:00 +0000]"), Some(ms("2026-01-01T00:00:00Z")));
+        for text in [
+            "10/Foo/2026:13:55:36 -0700]",
+            "10/Oct/2026:13:55:36]",
+            "10/Oct/2026:13:55:36 0700]",
+            "31/Nov/2026:13:55:36 -0700]",
+            "10/Oct/2026 13:55:36 -0700]",
+            "10/Oct/2026:13:55",
+        ] {
+            assert_eq!(parse_clf(text.as_bytes()), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn syslog() {
+        let latest = ms("2020-12-31T23:59:59.999Z");
+        assert_eq!(parse_syslog(b"Oct 11 22:14:15 host sshd[1]:", latest), Some(ms("2020-10-11T22:14:15Z")));
+        assert_eq!(parse_syslog(b"Feb  3 01:02:03 host", latest), Some(ms("2020-02-03T01:02:03Z")));
+        assert_eq!(parse_syslog(b"Feb 03 01:02:03 host", latest), Some(ms("2020-02-03T01:02:03Z")));
+        // December lines of a file last written in January
+        let latest = ms("2021-01-02T08:00:00Z");
+        assert_eq!(parse_syslog(b"Dec 31 23:59:59", latest), Some(ms("2020-12-31T23:59:59Z")));
+        assert_eq!(parse_syslog(b"Jan  2 09:00:00", latest), Some(ms("2021-01-02T09:00:00Z")));
+        for text in [
+            "Foo 11 22:14:15",
+            "Oct 32 22:14:15",
+            "Oct 11 22:14",
+            "Oct 11 22-14-15",
+            "Oct11 22:14:15",
+            "oct 11 22:14:15",
+        ] {
+            assert_eq!(parse_syslog(text.as_bytes(), latest), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn syslog_year_comes_from_option_or_last_write() {
+        let syslog = || TimeFormat::Builtin(BuiltinFormat::Syslog);
+        let line = b"Dec 30 10:00:00 host cron[1]: job";
+
+        // Neither: no timestamp rather than a guess
+        let mut detector = TimeDetector::new(syslog(), None, None).unwrap();
+        assert_eq!(detector.detect(line), None);
+        let mut detector = TimeDetector::new(TimeFormat::Auto, None, None).unwrap();
+        assert_eq!(detector.range(line), None);
+
+        let last_write = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(ms("2019-01-05T00:00:00Z") as u64);
+        detector.set_last_write(Some(last_write));
+        assert_eq!(detector.detect(line), Some(ms("2018-12-30T10:00:00Z")));
+
+        // A given year wins, for every date in it
+        let mut detector = TimeDetector::new(syslog(), None, Some(2017)).unwrap();
+        detector.set_last_write(Some(last_write));
+        assert_eq!(detector.detect(line), Some(ms("2017-12-30T10:00:00Z")));
+        assert_eq!(detector.detect(b"Jan  1 00:00:00"), Some(ms("2017-01-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn epoch_millis() {
+        assert_eq!(parse_epoch_ms(b"1791000000123 GET /"), Some(1_791_000_000_123));
+        assert_eq!(parse_epoch_ms(b"946684800000"), None);
+        assert_eq!(parse_epoch_ms(b"17910000001234"), None);
+        assert_eq!(parse_epoch_ms(b"0000000000001"), None);
+        assert_eq!(parse_epoch_ms(b"9999999999999"), None);
+        assert_eq!(parse_epoch_ms(b"179100000012x"), None);
+    }
+
+    #[test]
+    fn strftime() {
+        assert_eq!(
+            parse_strftime(b"01.10.2026 12:34:56 rest", "%d.%m.%Y %H:%M:%S"),
+            Some(ms("2026-10-01T12:34:56Z"))
+        );
+        assert_eq!(
+            parse_strftime(b"2026-10-01 12:34:56 +0200 rest", "%Y-%m-%d %H:%M:%S %z"),
+            Some(ms("2026-10-01T12:34:56+02:00"))
+        );
+        // Invalid UTF-8 after the timestamp is ignored
+        assert_eq!(
+            parse_strftime(b"01.10.2026 12:34:56 \xff", "%d.%m.%Y %H:%M:%S"),
+            Some(ms("2026-10-01T12:34:56Z"))
+        );
+        assert_eq!(parse_strftime(b"32.10.2026 12:34:56", "%d.%m.%Y %H:%M:%S"), None);
+        assert_eq!(parse_strftime(b"level=info 01.10.2026 12:34:56", "%d.%m.%Y %H:%M:%S"), None);
+    }
+
+    #[test]
+    fn time_bounds() {
+        assert_eq!(parse_time_bound("2026-10-01"), Ok(ms("2026-10-01T00:00:00Z")));
+        assert_eq!(parse_time_bound("2026-10-01T12:00"), Ok(ms("2026-10-01T12:00:00Z")));
+        assert_eq!(parse_time_bound(" 2026-10-01 12:00:00+02:00 "), Ok(ms("2026-10-01T10:00:00Z")));
+        for text in ["yesterday", "2026-10-01T12:00 trailing", "2026-02-30", "2026-10-1", ""] {
+            assert!(parse_time_bound(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn format_names() {
+        assert!(matches!("rfc3339".parse(), Ok(TimeFormat::Builtin(BuiltinFormat::Iso8601))));
+        assert!(matches!("epoch-ms".parse(), Ok(TimeFormat::Builtin(BuiltinFormat::EpochMillis))));
+        assert!(matches!("%H:%M".parse(), Ok(TimeFormat::Strftime(format)) if format == "%H:%M"));
+        assert!("unix".parse::<TimeFormat>().is_err());
+    }
+
+    #[test]
+    fn detector_finds_range_of_detected_format() {
+        let chunk = b"127.0.0.1 - - [10/Oct/2026:13:55:36 -0700] \"GET / HTTP/1.1\" 200\n\
+                      no timestamp here\n\
+                      127.0.0.1 - - [10/Oct/2026:13:50:00 -0700] \"GET /a HTTP/1.1\" 404\n";
+        let mut detector = TimeDetector::new(TimeFormat::Auto, None, None).unwrap();
+        assert_eq!(
+            detector.range(chunk),
+            Some(TimeRange {
+                min: ms("2026-10-10T13:50:00-07:00"),
+                max: ms("2026-10-10T13:55:36-07:00"),
+            })
+        );
+        assert_eq!(detector.range(b"no timestamps\nat all\n"), None);
+
+        // Only the capture group is parsed, so the earlier epoch is skipped
+        let epoch_ms = TimeFormat::Builtin(BuiltinFormat::EpochMillis);
+        let mut detector = TimeDetector::new(epoch_ms, Some(r"at=(\d+)"), None).unwrap();
+        assert_eq!(detector.detect(b"id=1791000000000 at=1791000000123"), Some(1_791_000_000_123));
+        assert!(TimeDetector::new(TimeFormat::Auto, Some("("), None).is_err());
+    }
+}
@@ -1,50 +1,160 @@
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
+use crate::input::COMPRESSED_EXTENSIONS;
+
 /// Our default chunk size for splitting
+#[cfg(not(test))]
 pub const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+/// Small chunks keep tests of archives with several frames quick
+#[cfg(test)]
+pub const CHUNK_SIZE: usize = 1024 * 1024;
 pub const HASH_CAPACITY: usize = 2 << 22; // 4M, must be power of 2
 
 /// Derive default `.zst` and `.idx` output filenames from the input.
 /// If the user provided `--zst` or `--idx`, we respect that. Otherwise, 
 /// we generate something like `input.log` -> `input.zst`, `input.idx`.
+/// A compression suffix is dropped first, so `app.log.gz` is named like `app.log`.
+/// A rotation number takes the place of the extension before it:
+/// `app.log.1.gz` -> `app.1.zst`, so rotated files built one by one don't
+/// overwrite each other's archives. Where that would still be the input's
+/// own name (`app.1.zst`), the archive becomes `app.1.mg.zst`.
 pub fn default_output_names_if_omitted(
     input_path: &str,
     maybe_zst: Option<&str>,
     maybe_idx: Option<&str>,
 ) -> (PathBuf, PathBuf) {
-    let input = Path::new(input_path);
-    let stem = input.file_stem().unwrap_or_else(|| std::ffi::OsStr::new("output"));
+    let mut input = Path::new(input_path);
+    let uncompressed;
+    if input.extension().is_some_and(|ext| COMPRESSED_EXTENSIONS.iter().any(|c| ext == *c)) {
+        uncompressed = input.with_extension("");
+        input = &uncompressed;
+    }
+    let rotation = input
+        .extension()
+        .filter(|ext| !ext.is_empty() && ext.as_encoded_bytes().iter().all(u8::is_ascii_digit));
+    let stem = match rotation {
+        Some(number) => {
+            let base = Path::new(input.file_stem().unwrap_or_default());
+            let base = base.file_stem().unwrap_or_else(|| std::ffi::OsStr::new("output"));
+            format!("{}.{}", base.to_string_lossy(), number.to_string_lossy())
+        }
+        None => input
+            .file_stem()
+            .unwrap_or_else(|| std::ffi::OsStr::new("output"))
+            .to_string_lossy()
+            .into_owned(),
+    };
     
     // Build the .zst path
     let zst_path = if let Some(given) = maybe_zst {
         PathBuf::from(given)
     } else {
         // If extension is .log, we produce .zst
-        input.with_file_name(format!("{}.zst", stem.to_string_lossy()))
+        let zst_path = input.with_file_name(format!("{}.zst", stem));
+        if zst_path == Path::new(input_path) {
+            input.with_file_name(format!("{}.mg.zst", stem))
+        } else {
+            zst_path
+        }
     };
 
     // Build the .idx path
     let idx_path = if let Some(given) = maybe_idx {
         PathBuf::from(given)
     } else {
-        input.with_file_name(format!("{}.mg", stem.to_string_lossy()))
+        input.with_file_name(format!("{}.mg", stem))
     };
 
     (zst_path, idx_path)
 }
 
-/// If the user omits `--idx` in the search subcommand,
-/// we'll guess `XXX.idx` from `XXX.zst`.
-pub fn default_index_if_omitted(
-    maybe_idx: Option<&str>,
-    zst_path: &str,
-) -> PathBuf {
-    if let Some(idx) = maybe_idx {
-        PathBuf::from(idx)
-    } else {
-        // Replace .zst with .idx if extension is .zst
-        // or just append .idx if there's no extension
-        let path = Path::new(zst_path);
-        path.with_extension("mg")
+/// Parse a percentage such as `5` or `2.5%`, above 0 and at most 100
+pub fn parse_percent(s: &str) -> Result<f64, String> {
+    let value: f64 = s
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid percentage: {:?} (expected e.g. 5 or 5%)", s))?;
+    if !(value > 0.0 && value <= 100.0) {
+        return Err(format!("percentage out of range: {:?} (expected above 0, at most 100)", s));
+    }
+    Ok(value)
+}
+
+/// Parse a byte size such as `512M`, `4G` or `1048576` (binary multiples)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size: {:?} (expected e.g. 512M, 4G)", s))?;
+    value
+        .checked_mul(1u64 << shift)
+        .ok_or_else(|| format!("size too large: {:?}", s))
+}
+
+/// Parse a frame range such as `100..200` (frames 100 to 199), `100..` or `..200`
+pub fn parse_frame_range(s: &str) -> Result<Range<u64>, String> {
+    let invalid = || format!("invalid frame range: {:?} (expected e.g. 100..200, 100.. or ..200)", s);
+    let (start, end) = s.trim().split_once("..").ok_or_else(invalid)?;
+    let start = if start.is_empty() { 0 } else { start.parse().map_err(|_| invalid())? };
+    let end = if end.is_empty() { u64::MAX } else { end.parse().map_err(|_| invalid())? };
+    if start > end {
+        return Err(format!("frame range {:?} ends before it starts", s));
+    }
+    Ok(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(input_path: &str) -> (PathBuf, PathBuf) {
+        default_output_names_if_omitted(input_path, None, None)
+    }
+
+    #[test]
+    fn output_names() {
+        let cases = [
+            ("app.log", "app.zst", "app.mg"),
+            ("logs/app.log.gz", "logs/app.zst", "logs/app.mg"),
+            ("app.log.1", "app.1.zst", "app.1.mg"),
+            ("app.log.1.gz", "app.1.zst", "app.1.mg"),
+            ("app.log.2.xz", "app.2.zst", "app.2.mg"),
+            ("app.log.3.bz2", "app.3.zst", "app.3.mg"),
+            ("/var/log/app.log.4.zst", "/var/log/app.4.zst", "/var/log/app.4.mg"),
+            ("syslog.1.gz", "syslog.1.zst", "syslog.1.mg"),
+            ("app.log.zst", "app.zst", "app.mg"),
+            ("app.zst", "app.mg.zst", "app.mg"),
+            ("syslog.1.zst", "syslog.1.mg.zst", "syslog.1.mg"),
+            ("app", "app.zst", "app.mg"),
+        ];
+        for (input, zst, idx) in cases {
+            assert_eq!(names(input), (PathBuf::from(zst), PathBuf::from(idx)), "{}", input);
+        }
+        assert_eq!(
+            default_output_names_if_omitted("app.log.1.gz", Some("a.zst"), Some("b.mg")),
+            (PathBuf::from("a.zst"), PathBuf::from("b.mg"))
+        );
+    }
+
+    #[test]
+    fn frame_ranges() {
+        assert_eq!(parse_frame_range("100..200"), Ok(100..200));
+        assert_eq!(parse_frame_range(" 100.. "), Ok(100..u64::MAX));
+        assert_eq!(parse_frame_range("..200"), Ok(0..200));
+        assert_eq!(parse_frame_range(".."), Ok(0..u64::MAX));
+        assert_eq!(parse_frame_range("5..5"), Ok(5..5));
+        for s in ["100", "100-200", "a..b", "-1..5", "1..2..3", "1...2", ""] {
+            assert!(parse_frame_range(s).unwrap_err().starts_with("invalid frame range"), "{}", s);
+        }
+        assert!(parse_frame_range("200..100").unwrap_err().contains("ends before it starts"));
     }
 }
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use memmap2::Mmap;
+
+use crate::build::count_lines;
+use crate::fastu64set::FastSet;
+use crate::gzindex::{extract_chunk, AccessPoint};
+use crate::index::{frame_checksum, split_header, FilterSpec, FrameFilter, FrameInfo, IndexHeader, IndexKind, IndexReader};
+use crate::utils::HASH_CAPACITY;
+
+/// Zstd frame header bit announcing a content checksum after the last block
+const CONTENT_CHECKSUM_FLAG: u8 = 0x04;
+
+/// One frame (or `.gz` chunk) and what the index says about it
+struct Entry {
+    /// Compressed bytes holding the data
+    offset: u64,
+    size: u64,
+    /// Hash of the compressed bytes recorded in the index, if any
+    checksum: Option<u64>,
+    /// Decompressed length recorded in the index, if any
+    len: Option<u64>,
+    /// Lines recorded in the index, if any
+    line_count: Option<u64>,
+    filter: FrameFilter,
+    /// Where to start inflating, for `.gz` chunks
+    point: Option<AccessPoint>,
+}
+
+/// What checking one entry's data found
+#[derive(Default)]
+struct EntryReport {
+    problems: Vec<String>,
+    /// Whether a checksum of the frame, in the index or the frame itself, was compared
+    checksummed: bool,
+}
+
+/// Main entry point for the "verify" subcommand: check that the index points
+/// at whole, contiguous frames of the data file, that every frame matches the
+/// checksums recorded for it and decompresses to the recorded size and
+/// line count, and with `deep` that the stored filters match rebuilt ones.
+/// Damage is reported per frame and fails the command.
+pub fn run_verify(zst_path: &str, maybe_idx_path: Option<&str>, deep: bool) -> io::Result<()> {
+    let zst_path = Path::new(zst_path);
+    let idx_path = match maybe_idx_path {
+        Some(idx) => PathBuf::from(idx),
+        None => zst_path.with_extension("mg"),
+    };
+    let index_data = fs::read(&idx_path)?;
+    let zst_file = File::open(zst_path)?;
+    let data = unsafe { Mmap::map(&zst_file)? };
+
+    let (header, records) = split_header(&index_data)?;
+    let (entries, trailing) = read_entries(&header, records);
+    let unit = match header.kind {
+        IndexKind::ZstdFrames => "frame",
+        IndexKind::GzipAccessPoints => "chunk",
+    };
+
+    if trailing > 0 {
+        println!(
+            "index: {} bytes after {} {} record(s) don't decode",
+            trailing,
+            entries.len(),
+            unit
+        );
+    }
+
+    let mut problems = match header.kind {
+        IndexKind::ZstdFrames => check_frame_layout(&entries, &data),
+        IndexKind::GzipAccessPoints => check_chunk_layout(&entries, &data),
+    };
+    // Intact frames that had no checksum to compare
+    let mut unchecked = 0;
+    let filter_spec = deep.then_some(header.filter);
+    check_entries(&data, &entries, filter_spec, |i, report| {
+        unchecked += usize::from(report.problems.is_empty() && !report.checksummed);
+        problems.entry(i).or_default().extend(report.problems);
+    })?;
+
+    let mut damaged_entries: Vec<_> = problems.into_iter().filter(|(_, p)| !p.is_empty()).collect();
+    damaged_entries.sort_by_key(|&(i, _)| i);
+    for (i, entry_problems) in &damaged_entries {
+        let entry = &entries[*i];
+        for problem in entry_problems {
+            println!("{} {} at offset {} ({} bytes): {}", unit, i, entry.offset, entry.size, problem);
+        }
+    }
+
+    if header.kind == IndexKind::ZstdFrames && unchecked > 0 {
+        println!(
+            "note: {} of {} frames have no checksum in the index or the data; their decompressed size was checked instead",
+            unchecked,
+            entries.len()
+        );
+    }
+    if !damaged_entries.is_empty() {
+        if zst_path.with_extension("mgpar").exists() {
+            println!("note: `mg repair` can rebuild damaged frames covered by the .mgpar");
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is damaged: {} of {} {}s failed verification",
+                zst_path,
+                damaged_entries.len(),
+                entries.len(),
+                unit
+            ),
+        ));
+    }
+    if trailing > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is damaged past its last readable record", idx_path),
+        ));
+    }
+    println!("{:?}: {} {}s OK{}", zst_path, entries.len(), unit, if deep { ", filters match" } else { "" });
+    Ok(())
+}
+
+/// Decode every record of the index, returning them with the number of bytes
+/// after the last one that decodes
+fn read_entries(header: &IndexHeader, records: &[u8]) -> (Vec<Entry>, usize) {
+    match header.kind {
+        IndexKind::ZstdFrames => {
+            let legacy = header.is_legacy_frames();
+            let mut reader = IndexReader::<FrameInfo>::new(header, records);
+            let entries = reader
+                .by_ref()
+                .map(|(frame_info, filter)| Entry {
+                    offset: frame_info.frame_offset,
+                    size: frame_info.frame_size,
+                    checksum: frame_info.checksum,
+                    len: (!legacy).then_some(frame_info.uncompressed_size),
+                    line_count: (!legacy).then_some(frame_info.line_count),
+                    filter,
+                    point: None,
+                })
+                .collect();
+            (entries, reader.remaining().len())
+        }
+        IndexKind::GzipAccessPoints => {
+            let mut reader = IndexReader::<AccessPoint>::new(header, records);
+            let entries = reader
+                .by_ref()
+                .map(|(point, filter)| {
+                    let (offset, size) = point.compressed_range();
+                    Entry {
+                        offset,
+                        size,
+                        checksum: None,
+                        len: Some(point.end - point.start),
+                        line_count: None,
+                        filter,
+                        point: Some(point),
+                    }
+                })
+                .collect();
+            (entries, reader.remaining().len())
+        }
+    }
+}
+
+/// Frames must lie inside the data and follow each other without overlap.
+/// Gaps may only hold skippable frames, which `mg index` leaves out.
+fn check_frame_layout(entries: &[Entry], data: &[u8]) -> HashMap<usize, Vec<String>> {
+    let mut problems: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut prev_end = 0u64;
+    for (i, entry) in entries.iter().enumerate() {
+        let end = entry.offset.saturating_add(entry.size);
+        if end > data.len() as u64 {
+            problems.entry(i).or_default().push(format!(
+                "extends past the end of the data file ({} bytes)",
+                data.len()
+            ));
+        } else if entry.offset < prev_end {
+            problems.entry(i).or_default().push(format!("overlaps the previous frame, which ends at {}", prev_end));
+        } else if !skippable_only(&data[prev_end as usize..entry.offset as usize]) {
+            problems.entry(i).or_default().push(format!(
+                "{} unindexed bytes before it, at offset {}",
+                entry.offset - prev_end,
+                prev_end
+            ));
+        }
+        prev_end = prev_end.max(end);
+    }
+    // Leftovers of an interrupted append are dropped by the next one
+    if prev_end < data.len() as u64 && !skippable_only(&data[prev_end as usize..]) {
+        println!(
+            "note: {} bytes after the last indexed frame are not indexed (an interrupted append?); `mg salvage` can index them",
+            data.len() as u64 - prev_end
+        );
+    }
+    problems
+}
+
+/// Chunks must lie inside the data and cover the decompressed stream back to
+/// back; their compressed ranges overlap by design
+fn check_chunk_layout(entries: &[Entry], data: &[u8]) -> HashMap<usize, Vec<String>> {
+    let mut problems: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut prev_end = 0u64;
+    for (i, entry) in entries.iter().enumerate() {
+        let point = entry.point.as_ref().expect("gzip entries have access points");
+        if entry.offset.saturating_add(entry.size) > data.len() as u64 {
+            problems.entry(i).or_default().push(format!(
+                "extends past the end of the data file ({} bytes)",
+                data.len()
+            ));
+        }
+        if point.start != prev_end || point.end < point.start {
+            problems.entry(i).or_default().push(format!(
+                "covers decompressed bytes {}..{}, expected to start at {}",
+                point.start, point.end, prev_end
+            ));
+        }
+        prev_end = point.end;
+    }
+    problems
+}
+
+/// Whether `data` is made of whole skippable frames only
+fn skippable_only(mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        let is_skippable =
+            data.len() >= 8 && u32::from_le_bytes(data[..4].try_into().unwrap()) & 0xFFFF_FFF0 == 0x184D_2A50;
+        if !is_skippable {
+            return false;
+        }
+        let size = 8 + u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if size > data.len() {
+            return false;
+        }
+        data = &data[size..];
+    }
+    true
+}
+
+/// Decompress and check every entry inside the data on all cores, passing
+/// each report to `report` in entry order. With a `filter_spec` each filter
+/// is also rebuilt that way and compared.
+fn check_entries(
+    data: &[u8],
+    entries: &[Entry],
+    filter_spec: Option<FilterSpec>,
+    mut report: impl FnMut(usize, EntryReport),
+) -> io::Result<()> {
+    let next_entry = AtomicUsize::new(0);
+    let num_workers = num_cpus::get().min(entries.len()).max(1);
+    let (result_sender, result_receiver) = mpsc::sync_channel::<(usize, EntryReport)>(num_workers);
+
+    thread::scope(|scope| {
+        for _ in 0..num_workers {
+            let result_sender = result_sender.clone();
+            let next_entry = &next_entry;
+            scope.spawn(move || {
+                let mut set_capacity = HASH_CAPACITY;
+                let mut set = FastSet::new(if filter_spec.is_some() { set_capacity } else { 0 });
+                let mut decompressed = Vec::new();
+                loop {
+                    let i = next_entry.fetch_add(1, Ordering::Relaxed);
+                    let Some(entry) = entries.get(i) else {
+                        break;
+                    };
+                    let end = entry.offset.saturating_add(entry.size);
+                    if end > data.len() as u64 {
+                        // Already reported by the layout check
+                        if result_sender.send((i, EntryReport::default())).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let compressed = &data[entry.offset as usize..end as usize];
+
+                    decompressed.clear();
+                    let mut entry_report = check_data(entry, compressed, &mut decompressed);
+                    if let Some(filter_spec) = filter_spec
+                        && entry_report.problems.is_empty()
+                    {
+                        // Keep the set at most half full, as when indexing
+                        if decompressed.len() * 2 > set_capacity {
+                            set_capacity = (decompressed.len() * 2).next_power_of_two();
+                            set = FastSet::new(set_capacity);
+                        }
+                        set.clear();
+                        entry_report.problems.extend(check_filter(&entry.filter, filter_spec, &decompressed, &mut set));
+                    }
+                    if result_sender.send((i, entry_report)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        // Report in entry order as results become ready
+        let mut pending: HashMap<usize, EntryReport> = HashMap::new();
+        let mut next_to_report = 0usize;
+        for (i, entry_report) in result_receiver {
+            pending.insert(i, entry_report);
+            while let Some(entry_report) = pending.remove(&next_to_report) {
+                report(next_to_report, entry_report);
+                next_to_report += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Decompress one entry into `out`, checking its framing, checksum, size
+/// and line count
+fn check_data(entry: &Entry, compressed: &[u8], out: &mut Vec<u8>) -> EntryReport {
+    let mut report = EntryReport::default();
+    if entry.checksum.is_some_and(|checksum| frame_checksum(compressed) != checksum) {
+        report.problems.push("compressed bytes don't match the checksum in the index".to_string());
+    }
+    let decoded = match &entry.point {
+        Some(point) => extract_chunk(point, compressed, out),
+        None => {
+            match zstd::zstd_safe::find_frame_compressed_size(compressed) {
+                Ok(size) if size as u64 == entry.size => {}
+                Ok(size) => report.problems.push(format!("the frame there is {} bytes long", size)),
+                Err(code) => report.problems.push(format!(
+                    "no zstd frame there: {}",
+                    zstd::zstd_safe::get_error_name(code)
+                )),
+            }
+            if !report.problems.is_empty() {
+                return report;
+            }
+            report.checksummed = entry.checksum.is_some() || frame_has_checksum(compressed);
+            // The decoder compares the content checksum when there is one
+            zstd::Decoder::with_buffer(compressed)
+                .and_then(|mut decoder| decoder.read_to_end(out))
+                .map(|_| ())
+        }
+    };
+    if let Err(e) = decoded {
+        report.problems.push(format!("decompression failed: {}", e));
+        return report;
+    }
+
+    if let Some(len) = entry.len
+        && out.len() as u64 != len
+    {
+        report.problems.push(format!("decompresses to {} bytes, the index records {}", out.len(), len));
+    }
+    if let Some(line_count) = entry.line_count {
+        let lines = count_lines(out);
+        if lines != line_count {
+            report.problems.push(format!("holds {} lines, the index records {}", lines, line_count));
+        }
+    }
+    report
+}
+
+/// Whether the header of the zstd frame at the start of `frame` announces a
+/// content checksum
+fn frame_has_checksum(frame: &[u8]) -> bool {
+    frame.get(4).is_some_and(|&descriptor| descriptor & CONTENT_CHECKSUM_FLAG != 0)
+}
+
+/// Rebuild the filter of `data` and compare it with the stored one.
+/// xorf seeds construction the same way every time but fills the slots no
+/// key uses with random fingerprints, so two filters of the same keys differ
+/// bit for bit. They match when the stored one has the rebuilt one's size and
+/// holds every n-gram of the data; the report says how many a damaged filter
+/// misses, each one a match search would skip.
+fn check_filter(stored: &FrameFilter, filter_spec: FilterSpec, data: &[u8], set: &mut FastSet) -> Option<String> {
+    for key in filter_spec.keys(data) {
+        set.insert(key);
+    }
+    let keys = set.extract();
+    let rebuilt = filter_spec.filter_from_keys(&keys);
+    let missing = keys.iter().filter(|key| !stored.contains(key)).count();
+    if missing > 0 {
+        return Some(format!(
+            "filter misses {} of {} n-grams; rewrite the index with `mg reindex`",
+            missing,
+            keys.len()
+        ));
+    }
+    if rebuilt.len() != stored.len() {
+        return Some(format!(
+            "filter has {} fingerprints, a rebuilt one {}; rewrite the index with `mg reindex`",
+            stored.len(),
+            rebuilt.len()
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{run_build, BuildOptions};
+    use crate::testutil::{log_lines, temp_dir};
+
+    /// Build a few frames of log into `app.zst` in `dir`, returning the data path
+    fn build(dir: &Path) -> PathBuf {
+        let log_path = dir.join("app.log");
+        let zst_path = dir.join("app.zst");
+        fs::write(&log_path, log_lines(0, 40_000)).unwrap();
+        let zst = zst_path.to_str().unwrap();
+        run_build(&[log_path.display().to_string()], Some(zst), None, &BuildOptions::default()).unwrap();
+        zst_path
+    }
+
+    /// Numbers of the frames that fail verification, after `tamper` has had
+    /// its way with the entries read from the index
+    fn damaged_frames(zst_path: &Path, deep: bool, tamper: impl FnOnce(&mut Vec<Entry>)) -> Vec<usize> {
+        let index_data = fs::read(zst_path.with_extension("mg")).unwrap();
+        let data = fs::read(zst_path).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        let (mut entries, trailing) = read_entries(&header, records);
+        assert_eq!(trailing, 0);
+        tamper(&mut entries);
+        let mut problems = check_frame_layout(&entries, &data);
+        check_entries(&data, &entries, deep.then_some(header.filter), |i, report| {
+            problems.entry(i).or_default().extend(report.problems);
+        })
+        .unwrap();
+        let mut damaged: Vec<usize> = problems.into_iter().filter(|(_, p)| !p.is_empty()).map(|(i, _)| i).collect();
+        damaged.sort();
+        damaged
+    }
+
+    #[test]
+    fn intact_archive_verifies() {
+        let dir = temp_dir();
+        let zst_path = build(dir.path());
+        assert!(damaged_frames(&zst_path, true, |_| {}).is_empty());
+        run_verify(zst_path.to_str().unwrap(), None, true).unwrap();
+    }
+
+    #[test]
+    fn damaged_frames_are_reported_by_number() {
+        let dir = temp_dir();
+        let zst_path = build(dir.path());
+        let index_data = fs::read(zst_path.with_extension("mg")).unwrap();
+        let (header, records) = split_header(&index_data).unwrap();
+        let (entries, _) = read_entries(&header, records);
+        assert!(entries.len() >= 3);
+
+        let mut data = fs::read(&zst_path).unwrap();
+        data[(entries[1].offset + entries[1].size / 2) as usize] ^= 0x01;
+        fs::write(&zst_path, &data).unwrap();
+        assert_eq!(damaged_frames(&zst_path, false, |_| {}), [1]);
+        assert!(run_verify(zst_path.to_str().unwrap(), None, false).is_err());
+
+        // A data file cut short loses its last frame
+        data.truncate(data.len() - 10);
+        fs::write(&zst_path, &data).unwrap();
+        assert_eq!(damaged_frames(&zst_path, false, |_| {}), [1, entries.len() - 1]);
+    }
+
+    #[test]
+    fn deep_check_compares_filters() {
+        let dir = temp_dir();
+        let zst_path = build(dir.path());
+        let swap_filters = |entries: &mut Vec<Entry>| {
+            let (head, tail) = entries.split_at_mut(2);
+            std::mem::swap(&mut head[0].filter, &mut tail[0].filter);
+        };
+        assert!(damaged_frames(&zst_path, false, swap_filters).is_empty());
+        assert_eq!(damaged_frames(&zst_path, true, swap_filters), [0, 2]);
+    }
+}
@@ -2,6 +2,7 @@
 # Rebuild test_log.zst and test_log.mg from test_log.txt, then upload to GCS.
 # Usage: ./upload-to-gcs.sh [bucket]
 # Default bucket: makigami-bucket-1
+# Requires a build with `cargo build --features gcs`.
 
 set -e
 BUCKET="${1:-makigami-bucket-1}"
@@ -9,8 +10,9 @@ BUCKET="${1:-makigami-bucket-1}"
 echo "Building from test_log.txt..."
 ./target/debug/mg build test_log.txt
 
+# mg push uploads the data first and the index last, keeping the .zst -> .mg
+# naming that `mg search gs://...` expects
 echo "Uploading to gs://$BUCKET/..."
-gsutil cp test_log.zst "gs://$BUCKET/test.zst"
-gsutil cp test_log.mg  "gs://$BUCKET/test.idx"
+./target/debug/mg push --force test_log.zst "gs://$BUCKET/test.zst"
 
-echo "Done. Objects: gs://$BUCKET/test.zst, gs://$BUCKET/test.idx"
+echo "Done. Objects: gs://$BUCKET/test.zst, gs://$BUCKET/test.mg"